@@ -1,24 +1,31 @@
 use serde::Serialize;
 use crate::blockchain::Blockchain;
 use crate::miner::Handle as MinerHandle;
+use crate::miner::pool::Handle as PoolHandle;
+use crate::types::transaction_generator::{self, Handle as TxGeneratorHandle};
+use crate::miner::template::{self, BlockTemplate};
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::ban_list::{BAN_DURATION, MAX_BAN_DURATION};
 use crate::types::hash::{Hashable, H256};
 use crate::types::mempool::UTXO;
 use log::info;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
-use crate::types::block::{Block, Body};
+use crate::types::block::{self, Block};
 use crate::types::mempool::{Mempool, self};
 use crate::types::transaction::SignedTransaction;
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
+    pool: Option<PoolHandle>,
+    tx_generator: Option<TxGeneratorHandle>,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
@@ -30,6 +37,36 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct VersionSignal {
+    bit: u32,
+    count: u32,
+    locked_in: bool,
+}
+
+#[derive(Serialize)]
+struct VersionSignals {
+    window: u32,
+    /// number of blocks in the window, less than window when the chain is short
+    counted: u32,
+    threshold: u32,
+    signals: Vec<VersionSignal>,
+}
+
+#[derive(Serialize)]
+struct Supply {
+    finalized_height: u32,
+    /// circulating supply, the total value of the utxo set
+    utxo_supply: u64,
+    /// the most coins which can be created up to the finalized height
+    scheduled_supply: u64,
+    /// subsidy and fees not claimed by the miners
+    unclaimed: u64,
+    /// the utxo set never holds more than the schedule
+    consistent: bool,
+    next_subsidy: u64,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -55,6 +92,8 @@ impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
+        pool: &Option<PoolHandle>,
+        tx_generator: &Option<TxGeneratorHandle>,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
@@ -63,6 +102,8 @@ impl Server {
         let server = Self {
             handle,
             miner: miner.clone(),
+            pool: pool.clone(),
+            tx_generator: tx_generator.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
@@ -70,6 +111,8 @@ impl Server {
         thread::spawn(move || {
             for mut req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
+                let pool = server.pool.clone();
+                let tx_generator = server.tx_generator.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let mempool = Arc::clone(&server.mempool);
@@ -109,14 +152,278 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/stop" => {
+                            info!("Received request to stop mining");
+                            miner.stop();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        "/miner/payout" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // the mined blocks have no coinbase with pkh=none
+                            let payout = match params.get("pkh").map(|pkh| pkh.as_str()) {
+                                Some("none") => None,
+                                Some(pkh) => match pkh.parse::<H256>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing pkh: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => {
+                                    respond_result!(req, false, "missing pkh");
+                                    return;
+                                }
+                            };
+                            miner.set_payout(payout);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/signal" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let bits = match params.get("bits") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing bits");
+                                    return;
+                                }
+                            };
+                            let bits = match bits.parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing bits: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.signal(bits);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/hash-rate" => {
+                            respond_json!(req, miner.hash_rate());
+                        }
+                        "/miner/pool-workers" => {
+                            match pool {
+                                Some(pool) => {
+                                    respond_json!(req, pool.workers());
+                                }
+                                None => {
+                                    respond_result!(req, false, "mining pool not running");
+                                }
+                            }
+                        }
+                        "/miner/block-template" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // without a payout, the external miner adds its own coinbase
+                            let payout = match params.get("payout").map(|pkh| pkh.parse::<H256>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing payout: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let template = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let mempool = mempool.lock().unwrap();
+                                BlockTemplate::from_mempool(&blockchain, &mempool, payout, block::VERSION_BASE)
+                            };
+                            respond_json!(req, template);
+                        }
+                        "/miner/submit-block" => {
+                            if req.method().as_str() != "POST" {
+                                respond_result!(req, false, "not a post request");
+                                return;
+                            }
+                            let mut content = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut content) {
+                                respond_result!(req, false, format!("error reading block: {}", e));
+                                return;
+                            }
+                            let block = match serde_json::from_str::<Block>(&content) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
+                            };
+                            match template::submit(&block, &blockchain, &mempool) {
+                                Ok(tip) => {
+                                    info!("Submitted block {:?}", block.hash());
+                                    network.broadcast_block(&block);
+                                    respond_result!(req, true, if tip { "ok" } else { "ok, not on the tip" });
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("invalid block: {}", e));
+                                }
+                            }
+                        }
                         "/tx-generator/start" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let tx_generator = match tx_generator {
+                                Some(g) => g,
+                                None => {
+                                    respond_result!(req, false, "no transaction generator, its keys are missing");
+                                    return;
+                                }
+                            };
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let rate = match params.get("rate").map(|v| v.parse::<f64>()) {
+                                Some(Ok(v)) if v > 0.0 && v.is_finite() => v,
+                                Some(_) => {
+                                    respond_result!(req, false, "rate must be a positive number of tx/s");
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing rate");
+                                    return;
+                                }
+                            };
+                            let values = match params.get("value").map(|v| v.parse()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing value: {}", e));
+                                    return;
+                                }
+                                None => transaction_generator::ValueDistribution::Fixed(10),
+                            };
+                            let fee = match params.get("fee").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing fee: {}", e));
+                                    return;
+                                }
+                                None => 0,
+                            };
+                            info!("Received request to start the transaction generator");
+                            tx_generator.start(transaction_generator::Config { rate, values, fee });
+                            respond_result!(req, true, "ok");
                         }
+                        "/tx-generator/stop" => match tx_generator {
+                            Some(g) => {
+                                info!("Received request to stop the transaction generator");
+                                g.stop();
+                                respond_result!(req, true, "ok");
+                            }
+                            None => {
+                                respond_result!(req, false, "no transaction generator, its keys are missing");
+                            }
+                        },
+                        "/tx-generator/status" => match tx_generator {
+                            Some(g) => {
+                                respond_json!(req, g.status());
+                            }
+                            None => {
+                                respond_result!(req, false, "no transaction generator, its keys are missing");
+                            }
+                        },
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // ping one peer if addr is set, all peers otherwise
+                            match params.get("addr").map(|a| a.parse::<std::net::SocketAddr>()) {
+                                Some(Ok(addr)) => {
+                                    if network.send(addr, Message::Ping(String::from("Test ping"))) {
+                                        respond_result!(req, true, "ok");
+                                    } else {
+                                        respond_result!(req, false, "no such peer");
+                                    }
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                }
+                                None => {
+                                    network.broadcast(Message::Ping(String::from("Test ping")));
+                                    respond_result!(req, true, "ok");
+                                }
+                            }
+                        }
+                        "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr").map(|a| a.parse::<std::net::SocketAddr>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            if network.disconnect_peer(addr) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "no such peer");
+                            }
+                        }
+                        "/network/dropped-requests" => {
+                            respond_json!(req, network.dropped_requests());
+                        }
+                        "/network/peers" => {
+                            let peers: Vec<_> = network.list_peers().iter().map(|p| p.info()).collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/bans" => {
+                            respond_json!(req, network.bans());
+                        }
+                        "/network/ban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            let ip = match ip.parse::<std::net::IpAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing ip: {}", e));
+                                    return;
+                                }
+                            };
+                            // in seconds, a day by default, a year at most
+                            let duration = match params.get("duration").map(|d| d.parse::<u64>()) {
+                                Some(Ok(v)) => Duration::from_secs(v).min(MAX_BAN_DURATION),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing duration: {}", e));
+                                    return;
+                                }
+                                None => BAN_DURATION,
+                            };
+                            network.ban(ip, duration);
                             respond_result!(req, true, "ok");
                         }
+                        "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip").map(|ip| ip.parse::<std::net::IpAddr>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing ip: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            if network.unban(&ip) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "not banned");
+                            }
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -127,15 +434,37 @@ impl Server {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
                            
-                            // remove the last 6 block hash if len > 6 
+                            // remove the last k block hash if len > k 
+                            let k = blockchain.spec.k as usize;
                             let mut v_string: Vec<String> = v.into_iter().map(|h|h.to_string()).collect();
-                            if v_string.len() > 6{
-                               v_string = v_string[0..v_string.len()-6].to_vec();
+                            if v_string.len() > k{
+                               v_string = v_string[0..v_string.len()-k].to_vec();
                             }
                             
 
                             respond_json!(req, v_string);
                         }
+                        "/blockchain/chain-spec" => {
+                            let spec = blockchain.lock().unwrap().spec.clone();
+                            respond_json!(req, spec);
+                        }
+                        "/blockchain/supply" => {
+                            // lock the blockchain first, the same order as the block insertion
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            // the utxo set is synced with the finalized blocks
+                            let finalized_height = mempool.synced_block_height;
+                            let utxo_supply = mempool.utxo_supply();
+                            let scheduled_supply = blockchain.spec.scheduled_supply(finalized_height);
+                            respond_json!(req, Supply {
+                                finalized_height,
+                                utxo_supply,
+                                scheduled_supply,
+                                unclaimed: scheduled_supply.saturating_sub(utxo_supply),
+                                consistent: utxo_supply <= scheduled_supply,
+                                next_subsidy: blockchain.spec.reward.subsidy(blockchain.height + 1),
+                            });
+                        }
                         "/blockchain/height" => {
                             let height = blockchain.lock().unwrap().height; 
                             respond_json!(req, height);
@@ -170,6 +499,73 @@ impl Server {
                             respond_json!(req, tx_count); 
                               
                         }
+                        "/blockchain/version-signals" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let window = match params.get("window").map(|v| v.parse::<u32>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing window: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => block::SIGNAL_WINDOW,
+                            };
+                            let (counts, counted) = blockchain.lock().unwrap().version_signals(window);
+                            // scale the lock in threshold to the window
+                            let threshold = (window as u64 * block::SIGNAL_THRESHOLD as u64 / block::SIGNAL_WINDOW as u64) as u32;
+                            let signals: Vec<VersionSignal> = counts
+                                .into_iter()
+                                .enumerate()
+                                .filter(|(_, count)| *count > 0)
+                                .map(|(bit, count)| VersionSignal {
+                                    bit: bit as u32,
+                                    count,
+                                    locked_in: counted == window && count >= threshold,
+                                })
+                                .collect();
+                            respond_json!(req, VersionSignals { window, counted, threshold, signals });
+                        }
+                        "/blockchain/headers" => {
+                            // headers of the longest chain, used by light clients
+                            let blockchain = blockchain.lock().unwrap();
+                            let headers = blockchain.headers_in_longest_chain();
+                            respond_json!(req, headers);
+                        }
+                        "/blockchain/tx-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx_hash = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let tx_hash = match tx_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing tx: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.tx_proof(&tx_hash) {
+                                Some(tx_proof) => {
+                                    respond_json!(req, tx_proof);
+                                }
+                                None => {
+                                    respond_result!(req, false, "tx not found in the longest chain");
+                                }
+                            }
+                        }
                         "/utxo" => {
                             let mempool = mempool.lock().unwrap();
                             let mut utxo_list = Vec::new(); 
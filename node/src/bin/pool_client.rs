@@ -0,0 +1,187 @@
+use bitcoin::miner::clock::SystemClock;
+use bitcoin::miner::pool::{ClientLine, Request, ServerLine};
+use bitcoin::miner::search::{self, HashRate};
+use bitcoin::types::block::Header;
+use bitcoin::types::hash::H256;
+use bitcoin::types::pow::PowAlgorithm;
+use clap::clap_app;
+use crossbeam::channel::{unbounded, Receiver};
+use log::{error, info, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::RangeInclusive;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to wait for the answers to the last shares
+const RESULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Smallest move of the timestamp giving a fresh nonce space
+const TIMESTAMP_STEP: Duration = Duration::from_micros(1);
+
+/// What the pool tells the miner
+enum Event {
+    /// nonces of the client and PoW algorithm of the chain
+    Subscribed(RangeInclusive<u32>, PowAlgorithm),
+    Target(H256),
+    Job(u64, Header),
+}
+
+#[derive(Default)]
+struct Results {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Results {
+    fn total(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed) + self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+fn send(mut stream: &TcpStream, id: u64, request: Request) {
+    let mut bytes = serde_json::to_vec(&ClientLine { id, request }).unwrap();
+    bytes.push(b'\n');
+    if let Err(e) = stream.write_all(&bytes) {
+        error!("Error writing to the pool: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Read the lines of the pool, the results of the shares are counted, the rest goes to the miner
+fn read_loop(stream: TcpStream, events: crossbeam::channel::Sender<Event>, results: Arc<Results>) {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Error reading from the pool: {}", e);
+                break;
+            }
+        };
+        let event = match serde_json::from_str::<ServerLine>(&line) {
+            Ok(ServerLine::Subscribed { nonces, pow, .. }) => Event::Subscribed(nonces, pow),
+            Ok(ServerLine::SetTarget { target }) => Event::Target(target),
+            Ok(ServerLine::Notify { job_id, header, .. }) => Event::Job(job_id, header),
+            // the first ids are for subscribe and authorize
+            Ok(ServerLine::Result { id, error: None }) => {
+                if id > 2 {
+                    results.accepted.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            Ok(ServerLine::Result { id, error: Some(e) }) => {
+                warn!("Request {} rejected: {}", id, e);
+                if id > 2 {
+                    results.rejected.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            Err(e) => {
+                error!("Invalid line from the pool: {}", e);
+                break;
+            }
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+    process::exit(1);
+}
+
+fn main() {
+    let matches = clap_app!(PoolClient =>
+     (version: "0.1")
+     (about: "test client of the mining pool of a node")
+     (@arg verbose: -v ... "Increases the verbosity of logging")
+     (@arg pool: --pool [ADDR] default_value("127.0.0.1:3333") "Sets the address of the mining pool")
+     (@arg worker: --worker [NAME] default_value("worker") "Sets the worker name the shares are accounted to")
+     (@arg threads: --threads [INT] default_value("1") "Sets the number of threads searching the nonce")
+     (@arg shares: --shares [INT] default_value("0") "Exits after submitting this number of shares, never if 0")
+    )
+    .get_matches();
+
+    let verbosity = matches.occurrences_of("verbose") as usize;
+    stderrlog::new().verbosity(verbosity).init().unwrap();
+    let pool = matches.value_of("pool").unwrap().parse::<SocketAddr>().unwrap_or_else(|e| {
+        error!("Error parsing pool address: {}", e);
+        process::exit(1);
+    });
+    let worker = matches.value_of("worker").unwrap().to_string();
+    let threads = matches.value_of("threads").unwrap().parse::<usize>().unwrap_or_else(|e| {
+        error!("Error parsing threads: {}", e);
+        process::exit(1);
+    });
+    let shares = matches.value_of("shares").unwrap().parse::<u64>().unwrap_or_else(|e| {
+        error!("Error parsing shares: {}", e);
+        process::exit(1);
+    });
+
+    let stream = TcpStream::connect(pool).unwrap_or_else(|e| {
+        error!("Error connecting to the pool {}: {}", pool, e);
+        process::exit(1);
+    });
+    let (event_sender, events) = unbounded();
+    let results = Arc::new(Results::default());
+    {
+        let stream = stream.try_clone().unwrap();
+        let results = Arc::clone(&results);
+        thread::spawn(move || read_loop(stream, event_sender, results));
+    }
+    send(&stream, 1, Request::Subscribe { agent: format!("pool-client/{}", env!("CARGO_PKG_VERSION")) });
+    send(&stream, 2, Request::Authorize { worker: worker.clone() });
+
+    let hash_rate = HashRate::default();
+    let (mut nonces, mut target, mut job) = (None, None, None);
+    let mut next_id = 3;
+    let started = Instant::now();
+    while shares == 0 || next_id - 3 < shares {
+        // take the news of the pool, waiting for the first job
+        let ready = nonces.is_some() && target.is_some() && job.is_some();
+        let received: Vec<Event> = if ready { events.try_iter().collect() } else { vec![recv(&events)] };
+        for event in received {
+            match event {
+                Event::Subscribed(n, pow) => {
+                    info!("Subscribed, PoW {}", pow);
+                    nonces = Some((n, pow));
+                }
+                Event::Target(t) => target = Some(t),
+                Event::Job(job_id, header) => {
+                    info!("New job {} on {:?}", job_id, header.parent);
+                    job = Some((job_id, header));
+                }
+            }
+        }
+        let (job_id, header, (nonces, pow), target) = match (&mut job, &nonces, target) {
+            (Some((job_id, header)), Some(nonces), Some(target)) => (*job_id, header, nonces, target),
+            _ => continue,
+        };
+        // a share is checked against the target of the pool, not the block difficulty
+        if let Some(solved) = search::search_nonces(header, target, nonces.clone(), pow, &SystemClock, threads, &hash_rate, || !events.is_empty()) {
+            send(&stream, next_id, Request::Submit { job_id, nonce: solved.nonce, timestamp: solved.timestamp });
+            next_id += 1;
+            // the next share is searched on a new timestamp, which keeps up with the clock
+            header.timestamp = SystemTime::now().max(solved.timestamp + TIMESTAMP_STEP);
+        }
+    }
+    let deadline = Instant::now() + RESULT_TIMEOUT;
+    while results.total() < shares && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    println!(
+        "worker {}: {} shares accepted, {} rejected, {} hashes in {:.1}s",
+        worker,
+        results.accepted.load(Ordering::Relaxed),
+        results.rejected.load(Ordering::Relaxed),
+        hash_rate.hashes(),
+        started.elapsed().as_secs_f64()
+    );
+}
+
+fn recv(events: &Receiver<Event>) -> Event {
+    events.recv().unwrap_or_else(|_| {
+        error!("Pool connection closed");
+        process::exit(1);
+    })
+}
@@ -0,0 +1,84 @@
+use bitcoin::miner::clock::SystemClock;
+use bitcoin::miner::search::{self, HashRate};
+use bitcoin::types::block::generate_random_header;
+use bitcoin::types::chain_spec::ChainSpec;
+use bitcoin::types::hash::H256;
+use bitcoin::types::pow::PowAlgorithm;
+use clap::clap_app;
+use log::error;
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Number of hashes to expect before a hash is not larger than the target
+fn expected_hashes(target: &H256) -> f64 {
+    let target = target.0.iter().fold(0.0, |acc, byte| acc * 256.0 + *byte as f64);
+    2f64.powi(256) / (target + 1.0)
+}
+
+/// Hashes per second of the miner search with the algorithm, measured for the duration
+fn measure(pow: &PowAlgorithm, threads: usize, duration: Duration) -> f64 {
+    let mut header = generate_random_header(&H256::rand());
+    // no hash is below zero, the search runs until stopped
+    header.difficulty = H256([0; 32]);
+    let hash_rate = HashRate::default();
+    let started = Instant::now();
+    let solved = search::search(&header, pow, &SystemClock, threads, &hash_rate, || started.elapsed() >= duration);
+    assert!(solved.is_none());
+    hash_rate.hashes() as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let matches = clap_app!(PowBench =>
+     (version: "0.1")
+     (about: "compares the hash rate of the PoW algorithms with the search of the miner")
+     (@arg verbose: -v ... "Increases the verbosity of logging")
+     (@arg seconds: --seconds [SECS] default_value("3") "Sets the duration of the measure of each algorithm")
+     (@arg threads: --threads [INT] default_value("1") "Sets the number of threads searching the nonce")
+     (@arg memory_kib: --("memory-kib") [KIB] default_value("64") "Sets the table size of the memory hard algorithm")
+     (@arg chain_spec: --("chain-spec") [FILE] "Also measures the algorithm of the chain spec and the expected block time at its difficulty")
+    )
+    .get_matches();
+
+    let verbosity = matches.occurrences_of("verbose") as usize;
+    stderrlog::new().verbosity(verbosity).init().unwrap();
+    let seconds = matches.value_of("seconds").unwrap().parse::<f64>().unwrap_or_else(|e| {
+        error!("Error parsing seconds: {}", e);
+        process::exit(1);
+    });
+    let threads = matches.value_of("threads").unwrap().parse::<usize>().unwrap_or_else(|e| {
+        error!("Error parsing threads: {}", e);
+        process::exit(1);
+    });
+    let memory_kib = matches.value_of("memory_kib").unwrap().parse::<u32>().unwrap_or_else(|e| {
+        error!("Error parsing memory kib: {}", e);
+        process::exit(1);
+    });
+    let spec = matches.value_of("chain_spec").map(|path| {
+        ChainSpec::load(path).unwrap_or_else(|e| {
+            error!("Error loading chain spec: {}", e);
+            process::exit(1);
+        })
+    });
+
+    let mut algorithms = vec![
+        PowAlgorithm::Sha256,
+        PowAlgorithm::DoubleSha256,
+        PowAlgorithm::MemoryHard { memory_kib: memory_kib.max(1) },
+    ];
+    if let Some(spec) = &spec {
+        if !algorithms.contains(&spec.pow) {
+            algorithms.push(spec.pow);
+        }
+    }
+    let duration = Duration::from_secs_f64(seconds);
+    let mut baseline = None;
+    for pow in &algorithms {
+        let rate = measure(pow, threads, duration);
+        let baseline = *baseline.get_or_insert(rate);
+        print!("{:<24} {:>12.0} H/s {:>10.4}x sha256", pow.to_string(), rate, rate / baseline);
+        match &spec {
+            Some(spec) => println!("  {:>10.1}s per block on {}", expected_hashes(&spec.difficulty) / rate, spec.network),
+            None => println!(),
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use crate::types::block::{self, Block};
+use crate::types::block::{self, Block, Header};
 use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::{Output, SignedTransaction};
+use crate::types::chain_spec::ChainSpec;
+use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 /// Confirmation block number, a block with height i is confirmed(finalized) when its child block with height i + K is inserted into the blockchain
+/// This is the default of the dev network, see `ChainSpec::k`
 pub const K: u32= 6;
+/// Block reward of the dev network, see `ChainSpec::reward`
 pub const REWARD: u32 = 50; 
 pub struct BlockWithHeight {
     pub block: Block,
@@ -10,6 +16,18 @@ pub struct BlockWithHeight {
     pub height: u32,
 }
 
+/// Merkle inclusion proof of a tx in a block of the longest chain, served to light clients
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    pub block_hash: H256,
+    /// index of the tx in the block body
+    pub index: usize,
+    /// number of txs in the block body
+    pub leaf_size: usize,
+    pub proof: Vec<H256>,
+    pub tx: SignedTransaction,
+}
+
 pub struct Blockchain {
     /// we save all blocks in a hashmap, key is the hash of the block, value is (block, height)
     pub blocks: HashMap<H256, BlockWithHeight>,
@@ -17,14 +35,20 @@ pub struct Blockchain {
     /// height of the longest chain, genesis block is 0, not the finalized chain 
     pub height: u32,
     pub finalized_block: H256, 
+    genesis: H256,
+    /// parameters of the network
+    pub spec: ChainSpec,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the dev network, only containing the genesis block
     pub fn new() -> Self {
-        // unimplemented!()
+        Blockchain::new_with_spec(ChainSpec::dev())
+    }
+    /// Create a new blockchain of the network described by the chain spec, only containing the genesis block
+    pub fn new_with_spec(spec: ChainSpec) -> Self {
         let mut blocks = HashMap::new();
-        let genesis_block = Block::genesis();
+        let genesis_block = Block::genesis_from_spec(&spec);
         let genesis_hash = genesis_block.hash();
         _ = blocks.insert(
             genesis_hash,
@@ -38,8 +62,14 @@ impl Blockchain {
             tail_block: genesis_hash,
             height: 0,
             finalized_block: genesis_hash,
+            genesis: genesis_hash,
+            spec,
         }
     }
+    /// hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis
+    }
     /// get difficulty from the tail block 
     pub fn get_difficulty(&self) -> H256 {
         let block_hash = self.tail_block;
@@ -68,7 +98,7 @@ impl Blockchain {
             self.tail_block = block_hash;
             self.height += 1;
             //update the finalized block
-            if self.height > K {
+            if self.height > self.spec.k {
                 self.finalized_block = self.get_K_last_block_hash();
             }
         } else {
@@ -90,7 +120,7 @@ impl Blockchain {
                 //update the tail block and height
                 self.tail_block = block_hash;
                 self.height = block_height;
-                if self.height > K {
+                if self.height > self.spec.k {
                     let new_finalized_block = self.get_K_last_block_hash();
                     if new_finalized_block == self.finalized_block {
                         okk = false;
@@ -117,12 +147,32 @@ impl Blockchain {
     }
     pub fn get_K_last_block_hash(&self) -> H256 {
         let mut block_hash = self.tail_block;
-        for _ in 0..K {
+        for _ in 0..self.spec.k {
             block_hash = self.blocks.get(&block_hash).unwrap().block.get_parent();
         }
         block_hash
     }
 
+    /// Blocks which left and joined the longest chain when its tip moved from `old_tip` to the current tip,
+    /// both ordered from the fork point to the tip
+    pub fn branch_change(&self, old_tip: &H256) -> (Vec<H256>, Vec<H256>) {
+        let (mut left, mut joined) = (vec![], vec![]);
+        let (mut old, mut new) = (*old_tip, self.tail_block);
+        while old != new {
+            let old_block = self.blocks.get(&old).unwrap();
+            let new_block = self.blocks.get(&new).unwrap();
+            if new_block.height >= old_block.height {
+                joined.push(new);
+                new = new_block.block.get_parent();
+            } else {
+                left.push(old);
+                old = old_block.block.get_parent();
+            }
+        }
+        left.reverse();
+        joined.reverse();
+        (left, joined)
+    }
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut blocks = vec![];
@@ -135,16 +185,133 @@ impl Blockchain {
         blocks.reverse();
         blocks
     }
+    /// Get all headers of the longest chain, ordered from genesis to the tip
+    pub fn headers_in_longest_chain(&self) -> Vec<Header> {
+        self.all_blocks_in_longest_chain()
+            .iter()
+            .map(|h| self.blocks.get(h).unwrap().block.header.clone())
+            .collect()
+    }
+    /// Block locator of the longest chain: the hashes from the tip back to genesis, dense near the tip
+    /// and exponentially sparser after the first 10, always ending with genesis
+    pub fn block_locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut index = chain.len() as i64 - 1;
+        while index > 0 {
+            locator.push(chain[index as usize]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index -= step;
+        }
+        locator.push(self.genesis);
+        locator
+    }
+    /// Headers of the longest chain following the first locator hash found in it, at most `max` headers.
+    /// If no locator hash is in the longest chain, start right after genesis
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let chain = self.all_blocks_in_longest_chain();
+        let fork = locator
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash))
+            .find(|b| chain.get(b.height as usize) == Some(&b.block.hash()))
+            .map(|b| b.height as usize)
+            .unwrap_or(0);
+        chain[fork + 1..]
+            .iter()
+            .take(max)
+            .map(|h| self.blocks.get(h).unwrap().block.header.clone())
+            .collect()
+    }
+    /// Count the blocks signalling each feature bit in the last `window` blocks of the longest chain,
+    /// return the counts indexed by bit and the number of blocks actually counted
+    pub fn version_signals(&self, window: u32) -> (Vec<u32>, u32) {
+        self.version_signals_at(&self.tail_block, window)
+    }
+    /// Count the blocks signalling each feature bit in the last `window` blocks of the chain ending at the block,
+    /// the block must be in the blockchain
+    pub fn version_signals_at(&self, hash: &H256, window: u32) -> (Vec<u32>, u32) {
+        let mut counts = vec![0; block::VERSION_FEATURE_BITS as usize];
+        let mut counted = 0;
+        let mut block_hash = *hash;
+        // genesis never signals
+        while counted < window && block_hash != self.genesis_hash() {
+            let header = &self.blocks.get(&block_hash).unwrap().block.header;
+            for (bit, count) in counts.iter_mut().enumerate() {
+                if header.signals(bit as u32) {
+                    *count += 1;
+                }
+            }
+            counted += 1;
+            block_hash = header.parent;
+        }
+        (counts, counted)
+    }
+    /// Feature bits active for the children of the block: the ones signalled by `SIGNAL_THRESHOLD` of the
+    /// `SIGNAL_WINDOW` blocks of the chain ending at the block
+    pub fn active_features(&self, hash: &H256) -> u32 {
+        let (counts, counted) = self.version_signals_at(hash, block::SIGNAL_WINDOW);
+        if counted < block::SIGNAL_WINDOW {
+            return 0;
+        }
+        counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count >= block::SIGNAL_THRESHOLD)
+            .fold(0, |features, (bit, _)| features | 1 << bit)
+    }
+    /// Find the tx with the given tx hash in the longest chain and build its merkle proof
+    pub fn tx_proof(&self, tx_hash: &H256) -> Option<TxProof> {
+        for block_hash in self.all_blocks_in_longest_chain().into_iter().rev() {
+            let block = &self.blocks.get(&block_hash).unwrap().block;
+            if let Some(index) = block.body.txs.iter().position(|tx| tx.get_tx_hash() == *tx_hash) {
+                let merkle_tree = MerkleTree::new(&block.body.txs);
+                return Some(TxProof {
+                    block_hash,
+                    index,
+                    leaf_size: block.body.txs.len(),
+                    proof: merkle_tree.proof(index),
+                    tx: block.body.txs[index].clone(),
+                });
+            }
+        }
+        None
+    }
+    /// Unspent outputs after the block, replaying its chain from genesis, the block must be in the blockchain
+    pub fn utxo_after(&self, hash: &H256) -> HashMap<(H256, usize), Output> {
+        let mut chain = vec![];
+        let mut block_hash = *hash;
+        while block_hash != H256::default() {
+            let block = &self.blocks.get(&block_hash).unwrap().block;
+            chain.push(block);
+            block_hash = block.get_parent();
+        }
+        let mut utxo = HashMap::new();
+        for block in chain.into_iter().rev() {
+            for tx in &block.body.txs {
+                for input in &tx.transaction.inputs {
+                    utxo.remove(&(input.source_tx_hash, input.index));
+                }
+                let tx_hash = tx.get_tx_hash();
+                for (index, output) in tx.transaction.outputs.iter().enumerate() {
+                    utxo.insert((tx_hash, index), output.clone());
+                }
+            }
+        }
+        utxo
+    }
     /// get all blocks (with data) from genesis to finialized
     pub fn get_all_blocks_from_genesis_to_finialized(&self) -> Vec<Block> {
-        if self.height <=K {
+        if self.height <= self.spec.k {
             //just return the genesis block
             return vec![self.blocks.get(&self.finalized_block).unwrap().block.clone()];
         }
         let mut blocks = vec![];
         let mut block_hash = self.finalized_block;
         let mut block_height: i64 = self.height as i64;
-        block_height -= K as i64;
+        block_height -= self.spec.k as i64;
         while block_height >= 0 {
             let  bblock = self.blocks.get(&block_hash).unwrap().block.clone();
             block_hash = bblock.get_parent();
@@ -222,6 +389,104 @@ mod tests {
         assert_eq!(b1.hash(), b3.hash());
     }
     #[test]
+    fn version_signalling() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        for i in 0..10 {
+            let mut block = generate_random_block(&parent);
+            if i % 2 == 0 {
+                block.header.version = block::VERSION_BASE | (1 << 3);
+            }
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        let (counts, counted) = blockchain.version_signals(block::SIGNAL_WINDOW);
+        assert_eq!(counted, 10);
+        assert_eq!(counts[3], 5);
+        assert_eq!(counts.iter().sum::<u32>(), 5);
+        let (counts, counted) = blockchain.version_signals(3);
+        assert_eq!(counted, 3);
+        assert_eq!(counts[3], 1);
+    }
+    #[test]
+    fn feature_activation() {
+        let mut blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        for i in 0..block::SIGNAL_WINDOW {
+            let mut block = generate_random_block(&parent);
+            if i >= block::SIGNAL_WINDOW - block::SIGNAL_THRESHOLD {
+                block.header.version = block::VERSION_BASE | (1 << block::FEATURE_TIMESTAMP_AFTER_PARENT);
+            }
+            blockchain.insert(&block);
+            // not active before a full window signals it
+            assert_eq!(blockchain.active_features(&parent), 0);
+            parent = block.hash();
+        }
+        assert_eq!(blockchain.active_features(&parent), 1 << block::FEATURE_TIMESTAMP_AFTER_PARENT);
+        // a child of the last block must be timestamped after it
+        let parent_header = blockchain.blocks[&parent].block.header.clone();
+        let mut block = generate_random_block(&parent);
+        block.header.timestamp = parent_header.timestamp;
+        let features = blockchain.active_features(&parent);
+        assert!(block.validate_soft_forks(&parent_header, features).is_err());
+        assert!(block.validate_soft_forks(&parent_header, 0).is_ok());
+        block.header.timestamp = parent_header.timestamp + std::time::Duration::from_secs(1);
+        assert!(block.validate_soft_forks(&parent_header, features).is_ok());
+    }
+    #[test]
+    fn header_version_rules() {
+        let mut block = generate_random_block(&H256::default());
+        assert!(block.validate_rules().is_ok());
+        block.header.version = 0;
+        assert!(block.header.validate_version().is_err());
+        block.header.version = 0x4000_0000;
+        assert!(block.header.validate_version().is_err());
+        assert!(!block.header.signals(0));
+        // legacy headers commit the txs too
+        block.header.version = block::VERSION_MIN;
+        assert!(block.validate_rules().is_ok());
+        block.header.merkle_root = H256::rand();
+        assert!(block.validate_rules().is_err());
+        block.header.version = block::VERSION_BASE | 1;
+        assert!(block.header.signals(0));
+        assert!(block.validate_rules().is_err());
+    }
+    #[test]
+    fn genesis_tx_proof(){
+        let blockchain = Blockchain::new();
+        let genesis = Block::genesis();
+        let ico_tx = &genesis.body.txs[0];
+        let tx_proof = blockchain.tx_proof(&ico_tx.get_tx_hash()).unwrap();
+        assert_eq!(tx_proof.block_hash, genesis.hash());
+        assert!(crate::types::merkle::verify(&genesis.header.merkle_root, &tx_proof.tx.hash(), &tx_proof.proof, tx_proof.index, tx_proof.leaf_size));
+        assert!(blockchain.tx_proof(&H256::rand()).is_none());
+    }
+    #[test]
+    fn block_locator() {
+        let mut blockchain = Blockchain::new();
+        let mut chain = vec![blockchain.tip()];
+        for _ in 0..30 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block);
+            chain.push(block.hash());
+        }
+        let locator = blockchain.block_locator();
+        // 10 dense hashes from the tip, then heights 19, 15, 7 and genesis
+        assert_eq!(locator[..10], chain[21..].iter().rev().cloned().collect::<Vec<H256>>()[..]);
+        assert_eq!(locator[10..], [chain[19], chain[15], chain[7], chain[0]]);
+        // a peer at height 12 gets the headers from height 13
+        let mut peer_locator = vec![H256::rand(), chain[12], chain[5]];
+        let headers = blockchain.headers_after(&peer_locator, 5);
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers[0].parent, chain[12]);
+        // a block off the longest chain is skipped
+        let fork = generate_random_block(&chain[20]);
+        blockchain.insert(&fork);
+        peer_locator.insert(0, fork.hash());
+        assert_eq!(blockchain.headers_after(&peer_locator, 2000).len(), 30 - 12);
+        assert_eq!(blockchain.headers_after(&[H256::rand()], 2000).len(), 30);
+    }
+    #[test]
     fn test_genesis_block(){
         let blockchain = Blockchain::new();
         let genesis = blockchain.get_all_blocks_from_genesis_to_finialized().clone(); 
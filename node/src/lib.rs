@@ -0,0 +1,12 @@
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod blockchain;
+pub mod types;
+pub mod miner;
+pub mod network;
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod simulation;
+pub use blockchain::Blockchain;
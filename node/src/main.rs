@@ -1,29 +1,28 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod blockchain;
-pub mod types;
-pub mod miner;
-pub mod network;
-use blockchain::Blockchain;
+use bitcoin::{api, miner, network};
+use bitcoin::miner::pool;
+use bitcoin::blockchain::Blockchain;
 use clap::clap_app;
 use smol::channel;
 use log::{error, info};
 use api::Server as ApiServer;
-use types::mempool;
+use bitcoin::types::mempool;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
-use crate::types::block_buffer::BlockBuffer; 
-use crate::types::transaction::Output;
-use crate::types::block::Block;
-use crate::types::hash::{H256, Hashable};
-use crate::types::mempool::{UTXO, Mempool};
-use crate::types::key_pair; 
+use bitcoin::types::block_buffer::BlockBuffer; 
+use bitcoin::types::transaction::Output;
+use bitcoin::types::block::Block;
+use bitcoin::types::hash::{H256, Hashable};
+use bitcoin::types::mempool::{UTXO, Mempool};
+use bitcoin::types::key_pair; 
+use bitcoin::types::chain_spec::ChainSpec;
+use bitcoin::types::ico::{self, IcoGenerator};
+use bitcoin::types::transaction_generator;
+use bitcoin::network::address_book::AddressBook;
+use bitcoin::network::connection_manager::ConnectionManager;
+use bitcoin::network::keepalive::Keepalive;
+use bitcoin::network::transport::{self, TransportConfig};
+use ring::signature::KeyPair;
 fn main() {
     // parse command line arguments
     let matches = clap_app!(RBTC =>
@@ -33,30 +32,44 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outbound peers to keep")
+     (@arg address_book: --("address-book") [FILE] "Sets the file persisting the known peer addresses, peers-<P2P PORT>.json if not set")
+     (@arg encrypt: --encrypt "Encrypts and authenticates the P2P connections, the peers must enable it too")
+     (@arg node_key: --("node-key") [FILE] "Sets the identity key file of the node, created if missing, implies --encrypt")
+     (@arg allow_peer: --("allow-peer") ... [PUBKEY] "Only accepts the peers proving one of these hex identity keys, implies --encrypt")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg chain_spec: --("chain-spec") [FILE] "Sets the chain spec file of the network, the dev network is used if not set")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching the nonce")
+     (@arg payout: --payout [PKH] "Sets the public key hash receiving the block subsidy and fees of the mined blocks")
+     (@arg pool: --pool [ADDR] "Serves mining jobs to external miners at this address")
+     (@arg pool_share_bits: --("pool-share-bits") [INT] default_value("12") "Sets the leading zero bits of the hash of a pool share")
+     (@arg tx_keys: --("tx-keys") [DIR] "Sets the directory of the alice, bob and caro keys spent by the transaction generator, ../keys if not set")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    // load the chain spec
+    let spec = match matches.value_of("chain_spec") {
+        Some(path) => ChainSpec::load(path).unwrap_or_else(|e| {
+            error!("Error loading chain spec: {}", e);
+            process::exit(1);
+        }),
+        None => ChainSpec::dev(),
+    };
+    info!("Running network {}", spec.network);
+    let k = spec.k;
+    let blockchain = Blockchain::new_with_spec(spec);
     // init the genesis process 
     let blockchain = Arc::new(Mutex::new(blockchain));
     let block_buffer = Arc::new(Mutex::new(BlockBuffer::new()));
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let mempool = Arc::new(Mutex::new(Mempool::new_with_k(k)));
 
     // get genesis outputs from the genesis block
     let genesis_block = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
-    // extract utxos from genesis_block
-    let genesis_utxo = &genesis_block.body.txs[0].transaction.outputs;
-    // add utxos to mempool
-    let genesis_hash = genesis_block.body.txs[0].get_tx_hash(); 
-    let mut i = 0; 
-    for utxo in genesis_utxo {
-        mempool.lock().unwrap().add_utxo((genesis_hash, i), UTXO {output: utxo.clone(), used_in_mempool: false, used_height: 0});
-        i= i + 1; 
-    }
+    // add genesis utxos to mempool
+    mempool.lock().unwrap().add_genesis(&genesis_block);
     // init a new keypair for this block 
     //let mykey = key_pair::random(); 
     // parse p2p server address
@@ -79,11 +92,46 @@ fn main() {
             process::exit(1);
         });
 
+    // load the known peer addresses, by default one file per p2p port so that local nodes do not share it
+    let address_book_path = matches
+        .value_of("address_book")
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| format!("peers-{}.json", p2p_addr.port()));
+    let address_book = AddressBook::load(&address_book_path).unwrap_or_else(|e| {
+        error!("Error loading address book: {}", e);
+        process::exit(1);
+    });
+    info!("Loaded {} peer addresses from {}", address_book.len(), address_book_path);
+    let address_book = Arc::new(Mutex::new(address_book));
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
+    // set up the encrypted transport, with the identity of the node and the allowed peers if any
+    let identity = matches.value_of("node_key").map(|path| {
+        let identity = transport::load_identity(path).unwrap_or_else(|e| {
+            error!("Error loading node key: {}", e);
+            process::exit(1);
+        });
+        info!("Node identity {}", hex::encode(identity.public_key().as_ref()));
+        Arc::new(identity)
+    });
+    let allow_list = matches.values_of("allow_peer").map(|keys| {
+        transport::parse_allow_list(keys).unwrap_or_else(|e| {
+            error!("Error parsing allowed peer: {}", e);
+            process::exit(1);
+        })
+    });
+    let transport = if matches.is_present("encrypt") || identity.is_some() || allow_list.is_some() {
+        info!("P2P transport encrypted{}", if allow_list.is_some() { ", allow-list mode" } else { "" });
+        Some(TransportConfig { identity, allow_list })
+    } else {
+        None
+    };
+
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let magic = blockchain.lock().unwrap().spec.magic;
+    let (server_ctx, server) = network::server::new(p2p_addr, magic, transport, msg_tx).unwrap();
     server_ctx.start().unwrap();
 
     // start the network msg handle worker
@@ -102,57 +150,103 @@ fn main() {
         &blockchain,
         &block_buffer,
         &mempool,
+        &address_book,
     );
     // p2p network worker, handle all the network messages and update the blockchain
     worker_ctx.start();
 
     // start the miner
     // The miner thread will manage the block update and mining process
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
+    let payout = matches.value_of("payout").map(|pkh| {
+        pkh.parse::<H256>().unwrap_or_else(|e| {
+            error!("Error parsing payout public key hash: {}", e);
+            process::exit(1);
+        })
+    });
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, payout, miner_threads);
     let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool);
     // miner main process, just mine a new block and propose to mine worker
     miner_ctx.start();
     miner_worker_ctx.start();
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    // start the mining pool, the blocks found by its miners pay the payout too
+    let pool = matches.value_of("pool").map(|addr| {
+        let addr = addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing pool address: {}", e);
+            process::exit(1);
         });
-    }
+        let share_bits = matches
+            .value_of("pool_share_bits")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing pool share bits: {}", e);
+                process::exit(1);
+            });
+        let (pool_ctx, pool) = pool::new(addr, pool::share_target(share_bits), &blockchain, &mempool, &server, payout)
+            .unwrap_or_else(|e| {
+                error!("Error starting mining pool: {}", e);
+                process::exit(1);
+            });
+        pool_ctx.start();
+        pool
+    });
+
+    // the transaction generator spends the outputs of its keys, it is paused until started by the API
+    let tx_keys = matches.value_of("tx_keys").unwrap_or(ico::CONFIG_PATH);
+    let tx_generator = if std::path::Path::new(tx_keys).join("alice.key").exists() {
+        let (tx_generator_ctx, tx_generator) =
+            transaction_generator::new(IcoGenerator::load_key(tx_keys), &mempool, &server);
+        tx_generator_ctx.start();
+        Some(tx_generator)
+    } else {
+        info!("No transaction generator, no keys in {}", tx_keys);
+        None
+    };
+
+    // keep the outbound connections, starting from the known peers
+    let seeds: Vec<net::SocketAddr> = matches
+        .values_of("known_peer")
+        .map(|peers| {
+            peers
+                .map(|peer| {
+                    peer.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                        error!("Error parsing peer address {}: {}", peer, e);
+                        process::exit(1);
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peer number: {}", e);
+            process::exit(1);
+        });
+    let connection_manager = ConnectionManager::new(&server, &blockchain, &address_book, seeds, outbound);
+    connection_manager.start();
 
+    // ping the peers and drop the dead ones
+    Keepalive::new(&server).start();
 
     // start the API server
 
     ApiServer::start(
         api_addr,
         &miner,
+        &pool,
+        &tx_generator,
         &server,
         &blockchain,
         &mempool, 
@@ -1,10 +1,17 @@
+pub mod clock;
+pub mod pool;
+pub mod search;
+pub mod template;
 pub mod worker;
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
 use log::debug;
 use log::info;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
-use std::time;
+use std::time::{self, Instant};
 
 use crate::types::block;
 use crate::types::block::generate_random_block;
@@ -13,19 +20,56 @@ use crate::types::block_buffer;
 use crate::types::hash::{Hashable, H256};
 use crate::types::mempool::Mempool;
 use crate::Blockchain;
+use clock::{Clock, SystemClock};
+use search::HashRate;
+use template::BlockTemplate;
 use std::thread;
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Stop,       // pause the mining until the next start
     Update,     // update the block in mining, it may due to new blockchain tip or new transaction
+    Signal(u32), // the feature bits signalled in the version of the mined headers
+    Payout(Option<H256>), // the receiver of the coinbase of the next blocks
+    Mine(Sender<Block>), // mine a block now and send it back, for the simulated mining of the tests
     Exit,
 }
 
-enum OperatingState {
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+/// The block in mining
+#[derive(Serialize, Debug, Clone)]
+pub struct TemplateStatus {
+    pub height: u32,
+    pub parent: H256,
+    pub tx_count: usize,
+}
+
+/// Mining state shared by the miner thread with the handles
+struct Stats {
+    state: OperatingState,
+    payout: Option<H256>,
+    template: Option<TemplateStatus>,
+    /// hashes of the blocks mined since the start
+    found: Vec<H256>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: OperatingState,
+    pub hash_rate: u64,
+    pub payout: Option<H256>,
+    pub blocks_found: usize,
+    /// blocks found which are not in the longest chain anymore
+    pub stale_blocks: usize,
+    pub template: Option<TemplateStatus>,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -37,22 +81,49 @@ pub struct Context {
     last_block_hash: H256,
     //mempool
     mempool: Arc<Mutex<Mempool>>,
+    /// header version of the mined blocks
+    version: u32,
+    /// receiver of the coinbase, no coinbase in the mined blocks if not set
+    payout: Option<H256>,
+    /// number of threads searching the nonce
+    threads: usize,
+    hash_rate: Arc<HashRate>,
+    /// signal received while searching, handled before building the next block
+    pending_signal: Option<ControlSignal>,
+    stats: Arc<Mutex<Stats>>,
+    /// timestamps of the mined blocks
+    clock: Arc<dyn Clock>,
 }
 
+/// Age of a block in mining after which new transactions in the mempool rebuild it
+const TEMPLATE_REFRESH: time::Duration = time::Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    hash_rate: Arc<HashRate>,
+    stats: Arc<Mutex<Stats>>,
+    blockchain: Arc<Mutex<Blockchain>>,
 }
 
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    payout: Option<H256>,
+    threads: usize,
 ) -> (Context, Handle, Receiver<Block>) {
     // api_server => miner_thread
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     // miner_thread => miner_worker_thread
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let hash_rate = Arc::new(HashRate::default());
+    let stats = Arc::new(Mutex::new(Stats {
+        state: OperatingState::Paused,
+        payout,
+        template: None,
+        found: Vec::new(),
+    }));
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
@@ -60,21 +131,45 @@ pub fn new(
         blockchain: Arc::clone(blockchain),
         last_block_hash: blockchain.lock().unwrap().tip(),
         mempool: Arc::clone(mempool),
+        version: block::VERSION_BASE,
+        payout,
+        threads: threads.max(1),
+        hash_rate: Arc::clone(&hash_rate),
+        pending_signal: None,
+        stats: Arc::clone(&stats),
+        clock: Arc::new(SystemClock),
     };
     //a sender abstraction for control signal from api server
     let handle = Handle {
         control_chan: signal_chan_sender,
+        hash_rate,
+        stats,
+        blockchain: Arc::clone(blockchain),
     };
 
     (ctx, handle, finished_block_receiver)
 }
 
-#[cfg(any(test, test_utilities))]
+/// Create a miner mining on command with `Handle::mine_block`, on a single thread with the timestamps
+/// of the clock. On a chain with a trivial target, e.g. `ChainSpec::simulated`, the blocks are mined at
+/// once and are the same on every run, which keeps the tests of forks and finalization deterministic.
+pub fn new_simulated(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    payout: Option<H256>,
+    clock: Arc<dyn Clock>,
+) -> (Context, Handle, Receiver<Block>) {
+    let (mut ctx, handle, finished_block_chan) = new(blockchain, mempool, payout, 1);
+    ctx.clock = clock;
+    (ctx, handle, finished_block_chan)
+}
+
+#[cfg(any(test, feature = "test-utilities"))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Arc::new(Mutex::new(Blockchain::new()));
     let mempool = Arc::new(Mutex::new(Mempool::new()));
     println!("The genesis hash is {:?}", blockchain.lock().unwrap().tip());
-    new(&blockchain, &mempool)
+    new(&blockchain, &mempool, None, 1)
 }
 
 impl Handle {
@@ -88,21 +183,170 @@ impl Handle {
             .unwrap();
     }
 
+    /// Mine a block on the tip now and return it, the miner needs not be started
+    pub fn mine_block(&self) -> Block {
+        let (reply, block) = bounded(1);
+        self.control_chan.send(ControlSignal::Mine(reply)).unwrap();
+        block.recv().expect("Miner shut down")
+    }
+
+    /// Pause the mining, the block in mining is dropped
+    pub fn stop(&self) {
+        self.control_chan.send(ControlSignal::Stop).unwrap();
+    }
+
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Set the feature bits signalled by the mined blocks
+    pub fn signal(&self, bits: u32) {
+        self.control_chan.send(ControlSignal::Signal(bits)).unwrap();
+    }
+
+    /// Set the receiver of the coinbase of the next blocks, no coinbase if None
+    pub fn set_payout(&self, payout: Option<H256>) {
+        self.control_chan.send(ControlSignal::Payout(payout)).unwrap();
+    }
+
+    /// Hashes per second of all the mining threads, measured every second while mining
+    pub fn hash_rate(&self) -> u64 {
+        self.hash_rate.rate()
+    }
+
+    /// State of the miner, the blocks it found and the block in mining
+    pub fn status(&self) -> Status {
+        let (state, payout, template, found) = {
+            let stats = self.stats.lock().unwrap();
+            (stats.state.clone(), stats.payout, stats.template.clone(), stats.found.clone())
+        };
+        // the miner locks the stats while holding the blockchain, not the other way around
+        let stale_blocks = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let longest_chain: HashSet<H256> = blockchain.all_blocks_in_longest_chain().into_iter().collect();
+            found.iter().filter(|hash| !longest_chain.contains(hash)).count()
+        };
+        Status {
+            // the last measurement stays while not mining
+            hash_rate: if let OperatingState::Run(_) = state { self.hash_rate.rate() } else { 0 },
+            state,
+            payout,
+            blocks_found: found.len(),
+            stale_blocks,
+            template,
+        }
+    }
 }
 
 impl Context {
     /// Start a miner thread which running the mining loop
     pub fn start(mut self) {
+        let threads = self.threads;
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+        info!("Miner initialized into paused mode, {} mining threads", threads);
+    }
+
+    fn set_signal_bits(&mut self, bits: u32) {
+        let bits = bits & ((1 << block::VERSION_FEATURE_BITS) - 1);
+        info!("Miner signalling feature bits {:#x}", bits);
+        self.version = block::VERSION_BASE | bits;
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.operating_state = OperatingState::Run(i);
+            }
+            ControlSignal::Stop => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Update => {
+                // in paused state, don't need to update
+                if let OperatingState::Run(_) = self.operating_state {
+                    self.last_block_hash = self.blockchain.lock().unwrap().tip();
+                    info!("Updated: The lastest block hash is {:?}", self.last_block_hash);
+                }
+            }
+            ControlSignal::Signal(bits) => {
+                self.set_signal_bits(bits);
+            }
+            ControlSignal::Payout(payout) => {
+                info!("Miner paying the coinbase to {:?}", payout);
+                self.payout = payout;
+            }
+            ControlSignal::Mine(reply) => {
+                let block = self.mine_block();
+                let _ = reply.send(block);
+            }
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.state = self.operating_state.clone();
+        stats.payout = self.payout;
+        if !matches!(self.operating_state, OperatingState::Run(_)) {
+            stats.template = None;
+        }
+    }
+
+    /// Build a block on the tip, the txs valid on the tip are taken from the mempool
+    fn build_block(&mut self) -> Block {
+        let blockchain = self.blockchain.lock().unwrap();
+        self.last_block_hash = blockchain.tip();
+        // get the new block body
+        let mut mempool = self.mempool.lock().unwrap();
+        let utxo = block_buffer::utxo_after(&self.last_block_hash, &blockchain, &mempool);
+        let (body, _, fee) = mempool.propose_block_body(&utxo);
+        drop(mempool);
+        // build the block on the tip, paying the subsidy and fees to ourselves
+        let mut template = BlockTemplate::new(&blockchain, body, fee, self.payout, self.version);
+        template.header.timestamp = self.clock.now();
+        self.stats.lock().unwrap().template = Some(TemplateStatus {
+            height: template.coinbase.height,
+            parent: self.last_block_hash,
+            tx_count: template.transactions.len(),
+        });
+        template.block()
+    }
+
+    /// Validate a mined block, then insert it and pass it to the miner worker
+    fn push_block(&mut self, block: &Block) {
+        {
+            // released before sleeping, the status and the p2p worker need the blockchain
+            let mut blockchain_unlocked = self.blockchain.lock().unwrap();
+            let mut unlocked_mempool = self.mempool.lock().unwrap();
+            if let Err(e) = block_buffer::validate_block(block, &blockchain_unlocked, &unlocked_mempool) {
+                warn!("Drop invalid mined block {:?}: {}", block.hash(), e);
+                return;
+            }
+            block_buffer::blockchain_insert_with_mempool_atomic(block.clone(), &mut blockchain_unlocked, &mut unlocked_mempool);
+        }
+        self.stats.lock().unwrap().found.push(block.hash());
+        self.finished_block_chan
+            .send(block.clone())
+            .expect("Send finished block error");
+    }
+
+    /// Mine a block on the tip now, trying the nonces in order on a single thread so that the same
+    /// chain, mempool and clock always give the same block. Meant for chains with a trivial target.
+    fn mine_block(&mut self) -> Block {
+        let mut block = self.build_block();
+        let target = block.header.difficulty;
+        let pow = self.blockchain.lock().unwrap().spec.pow;
+        block.header = search::search_nonces(&block.header, target, 0..=u32::MAX, &pow, &*self.clock, 1, &self.hash_rate, || false)
+            .expect("the search is never stopped");
+        debug!("Mined block {:?} on command", block.hash());
+        self.push_block(&block);
+        block
     }
 
     fn miner_loop(&mut self) {
@@ -113,97 +357,68 @@ impl Context {
                 // if the miner in paused state, it will wait for the signal from api server, then go to the next loop
                 OperatingState::Paused => {
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
+                    self.handle_signal(signal);
                     continue;
                 }
                 OperatingState::ShutDown => {
                     return;
                 }
-                // working state
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                //unimplemented!()
-                                self.last_block_hash = self.blockchain.lock().unwrap().tip();
-                                info!(
-                                    "Updated: The lastest block hash is {:?}",
-                                    self.last_block_hash
-                                );
-                            }
-                        };
-                    }
+                // working state, a signal received while searching is handled first
+                _ => match self.pending_signal.take().map(Ok).unwrap_or_else(|| self.control_chan.try_recv()) {
+                    Ok(signal) => self.handle_signal(signal),
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
 
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            } 
-            let mut new_block: Block;
-            // get the new block body 
-            let (new_body, mr, fee) = self.mempool.lock().unwrap().propose_block_body();
-
-            //get header 
-            let mut new_header = block::generate_random_header(& H256::default());
-            let difficulty: H256;
-            {
-                //get the last hash and difficulty of the blockchain
-               
-                difficulty = self.blockchain.lock().unwrap().get_difficulty();
-                self.last_block_hash = self.blockchain.lock().unwrap().tip();
+            match self.operating_state {
+                OperatingState::ShutDown => return,
+                OperatingState::Paused => continue,
+                OperatingState::Run(_) => {}
             }
-            new_header.difficulty = difficulty;
-            new_header.merkle_root = mr;
-            new_header.parent = self.last_block_hash;
-            // build block 
-            new_block = Block {
-                header: new_header,
-                body: new_body,
-            };
+            let mut new_block = self.build_block();
             debug!("Start mining a block from {:?}", self.last_block_hash);
             info!("The new block will wrap {} tx", new_block.body.tx_count);
 
-            // range nounce 
-            loop {
-                if new_block.hash() < new_block.header.difficulty {
-                    break;
+            // search the nonce on all the threads, the timestamp rolls when a thread exhausts its nonce range.
+            // The search stops on a control signal, a new tip, or new transactions once the block gets old,
+            // then the block is rebuilt on the current tip.
+            let started = Instant::now();
+            let mempool_size = self.mempool.lock().unwrap().txs.len();
+            let (blockchain, mempool, control_chan) = (&self.blockchain, &self.mempool, &self.control_chan);
+            let (parent, mut pending_signal) = (self.last_block_hash, None);
+            let pow = blockchain.lock().unwrap().spec.pow;
+            let solved = search::search(&new_block.header, &pow, &*self.clock, self.threads, &self.hash_rate, || {
+                match control_chan.try_recv() {
+                    Ok(signal) => {
+                        pending_signal = Some(signal);
+                        return true;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 }
-                new_block.header.nonce += 1;
-            }
+                if blockchain.lock().unwrap().tip() != parent {
+                    debug!("New tip, stop mining on {:?}", parent);
+                    return true;
+                }
+                started.elapsed() >= TEMPLATE_REFRESH && mempool.lock().unwrap().txs.len() != mempool_size
+            });
+            self.pending_signal = pending_signal;
+            new_block.header = match solved {
+                Some(header) => header,
+                None => {
+                    // the txs were taken from the mempool, the ones still valid on the new tip go into the rebuilt block
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let mut mempool = self.mempool.lock().unwrap();
+                    let utxo = block_buffer::utxo_after(&blockchain.tip(), &blockchain, &mempool);
+                    mempool.restore_txs(new_block.body.txs, &utxo);
+                    continue;
+                }
+            };
 
             // push to the chain 
-            info!("mined a new block, hash is {:?}", new_block.hash());
-            let mut blockchain_unlocked = self.blockchain.lock().unwrap();
-            let mut unlocked_mempool = self.mempool.lock().unwrap();
-            block_buffer::blockchain_insert_with_mempool_atomic(new_block.clone(),&mut blockchain_unlocked, &mut unlocked_mempool);
-
-
-
-            self.finished_block_chan
-                .send(new_block.clone())
-                .expect("Send finished block error");
+            info!("mined a new block, hash is {:?}, {} H/s", new_block.hash(), self.hash_rate.rate());
+            self.push_block(&new_block);
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -245,3 +460,131 @@ mod test {
 // node1: http://127.0.0.1:7000/miner/start?lambda=1500000 1.5 sec per block
 // node2: http://127.0.0.1:7001/miner/start?lambda=2000000 2 sec per block
 // node3: http://127.0.0.1:7002/miner/start?lambda=2000000 2 sec per block
+
+#[cfg(test)]
+mod control_tests {
+    use super::*;
+    use crate::miner::clock::MockClock;
+    use crate::types::chain_spec::ChainSpec;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Wait until the status of the miner satisfies the condition
+    fn wait_status<F: Fn(&Status) -> bool>(handle: &Handle, condition: F) -> Status {
+        for _ in 0..100 {
+            let status = handle.status();
+            if condition(&status) {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("unexpected miner status {:?}", handle.status());
+    }
+
+    #[test]
+    fn start_stop_and_payout() {
+        let (miner_ctx, miner_handle, _finished_block_chan) = test_new();
+        miner_ctx.start();
+        let status = miner_handle.status();
+        assert_eq!(status.state, OperatingState::Paused);
+        assert!(status.template.is_none());
+
+        let payout = H256::rand();
+        miner_handle.set_payout(Some(payout));
+        miner_handle.start(0);
+        let status = wait_status(&miner_handle, |s| s.template.is_some());
+        assert_eq!(status.state, OperatingState::Run(0));
+        assert_eq!(status.payout, Some(payout));
+        assert!(status.template.unwrap().tx_count >= 1);
+
+        // the search is interrupted, the block in mining is dropped
+        miner_handle.stop();
+        let status = wait_status(&miner_handle, |s| s.state == OperatingState::Paused);
+        assert!(status.template.is_none());
+        assert_eq!(status.hash_rate, 0);
+        assert_eq!(status.stale_blocks, 0);
+        miner_handle.exit();
+    }
+
+    /// Mine blocks on command on a new simulated chain, the clock moving 10 seconds before each block
+    fn simulate(spec: &ChainSpec, blocks: u32) -> (Vec<Block>, Arc<Mutex<Blockchain>>, Arc<Mutex<Mempool>>) {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new_with_spec(spec.clone())));
+        let mempool = Arc::new(Mutex::new(Mempool::new_with_k(spec.k)));
+        let genesis = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
+        mempool.lock().unwrap().add_genesis(&genesis);
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(spec.genesis_timestamp)));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            new_simulated(&blockchain, &mempool, Some(H256([7; 32])), clock.clone());
+        miner_ctx.start();
+        let mined = (0..blocks)
+            .map(|_| {
+                clock.advance(Duration::from_secs(10));
+                let block = miner_handle.mine_block();
+                assert_eq!(finished_block_chan.recv().unwrap().hash(), block.hash());
+                block
+            })
+            .collect();
+        miner_handle.exit();
+        (mined, blockchain, mempool)
+    }
+
+    #[test]
+    fn deterministic_simulated_mining() {
+        let spec = ChainSpec::simulated();
+        let (first, blockchain, mempool) = simulate(&spec, spec.k + 2);
+        let (second, _, _) = simulate(&spec, spec.k + 2);
+        let hashes = |blocks: &Vec<Block>| blocks.iter().map(|b| b.hash()).collect::<Vec<H256>>();
+        assert_eq!(hashes(&first), hashes(&second));
+
+        let genesis_time = UNIX_EPOCH + Duration::from_secs(spec.genesis_timestamp);
+        for (i, block) in first.iter().enumerate() {
+            assert_eq!(block.header.timestamp, genesis_time + Duration::from_secs(10 * (i as u64 + 1)));
+            if i > 0 {
+                assert_eq!(block.get_parent(), first[i - 1].hash());
+            }
+        }
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.height, spec.k + 2);
+        assert_eq!(blockchain.tip(), first.last().unwrap().hash());
+        // the first two blocks are final, the mempool holds their coinbase outputs
+        let mempool = mempool.lock().unwrap();
+        assert_eq!(mempool.synced_block_height, 2);
+        assert!(mempool.utxo.contains_key(&(first[1].body.txs[0].get_tx_hash(), 0)));
+        assert!(!mempool.utxo.contains_key(&(first[2].body.txs[0].get_tx_hash(), 0)));
+    }
+
+    #[test]
+    fn mined_txs_are_not_mined_again() {
+        use crate::types::ico::{self, IcoGenerator};
+        use crate::types::transaction::{self, Input, Output, SignedTransaction, Transaction, Witness};
+        use ring::signature::KeyPair;
+
+        let spec = ChainSpec::simulated();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new_with_spec(spec.clone())));
+        let mempool = Arc::new(Mutex::new(Mempool::new_with_k(spec.k)));
+        let genesis = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
+        mempool.lock().unwrap().add_genesis(&genesis);
+        // alice spends her genesis output
+        let alice = &IcoGenerator::load_key(ico::CONFIG_PATH)[0];
+        let transaction = Transaction {
+            inputs: vec![Input::new(&genesis.body.txs[0].get_tx_hash(), 0)],
+            outputs: vec![Output { pk_hash: H256::rand(), value: genesis.body.txs[0].transaction.outputs[0].value }],
+        };
+        let sig = transaction::sign(&transaction, alice).as_ref().to_vec();
+        let tx = SignedTransaction { transaction, fee: 0, witnesses: vec![Witness { pubkey: alice.public_key().as_ref().to_vec(), sig }] };
+        mempool.lock().unwrap().add_tx(&tx).unwrap();
+
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(spec.genesis_timestamp)));
+        let (miner_ctx, miner_handle, finished_block_chan) = new_simulated(&blockchain, &mempool, None, clock);
+        miner_ctx.start();
+        let first = miner_handle.mine_block();
+        assert_eq!(finished_block_chan.recv().unwrap().hash(), first.hash());
+        assert_eq!(first.body.tx_count, 1);
+        assert_eq!(first.body.txs[0].get_tx_hash(), tx.get_tx_hash());
+        // the tx left the mempool with the first block, the second one does not spend it again
+        let second = miner_handle.mine_block();
+        assert_eq!(finished_block_chan.recv().unwrap().hash(), second.hash());
+        assert!(second.body.txs.is_empty());
+        assert_eq!(blockchain.lock().unwrap().tip(), second.hash());
+        miner_handle.exit();
+    }
+}
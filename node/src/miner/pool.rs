@@ -0,0 +1,505 @@
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::template::{self, BlockTemplate};
+use super::TEMPLATE_REFRESH;
+use crate::network::server::Handle as ServerHandle;
+use crate::types::block::{self, Block, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::mempool::Mempool;
+use crate::types::pow::{Pow, PowAlgorithm};
+use crate::Blockchain;
+
+/// Period of checking the tip and the mempool for a new job
+const JOB_POLL: Duration = Duration::from_millis(500);
+/// Jobs on the current tip which still accept shares
+const MAX_JOBS: usize = 8;
+/// How far in the future the timestamp of a share can be
+const MAX_TIME_DRIFT: Duration = Duration::from_secs(60);
+/// Bits of the nonce given to each subscription, the top bits tell the subscriptions apart
+const SUBSCRIPTION_NONCE_BITS: u32 = 24;
+/// Longest line accepted from a client
+const MAX_LINE: u64 = 4096;
+/// A client not taking a line within it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Line sent by a client, answered by a `ServerLine::Result` with the same id
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientLine {
+    pub id: u64,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    /// ask for jobs, answered by `Subscribed`, then `SetTarget` and `Notify`
+    Subscribe { agent: String },
+    /// name the worker the shares of the connection are accounted to
+    Authorize { worker: String },
+    /// a solution of the header of the job with the nonce and timestamp
+    Submit { job_id: u64, nonce: u32, timestamp: SystemTime },
+}
+
+/// Line sent by the pool
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerLine {
+    /// answer to subscribe, the nonces of the jobs the client searches and the PoW algorithm of the chain
+    Subscribed {
+        id: u64,
+        nonces: RangeInclusive<u32>,
+        #[serde(default)]
+        pow: PowAlgorithm,
+    },
+    /// answer to a request, the reason of the rejection if any
+    Result { id: u64, error: Option<String> },
+    /// the hash of a share is below the target
+    SetTarget { target: H256 },
+    /// a header to solve, the previous jobs are stale when clean is set
+    Notify { job_id: u64, header: Header, clean: bool },
+}
+
+/// Shares of a worker
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct WorkerStats {
+    pub worker: String,
+    pub accepted: u64,
+    /// shares of a job on an old tip
+    pub stale: u64,
+    pub rejected: u64,
+    /// shares solving the block
+    pub blocks: u64,
+}
+
+/// Target of a share having at least `zero_bits` leading zero bits
+pub fn share_target(zero_bits: u32) -> H256 {
+    let mut target = [255u8; 32];
+    for (i, byte) in target.iter_mut().enumerate() {
+        let bits = zero_bits.saturating_sub(i as u32 * 8).min(8);
+        *byte = if bits == 8 { 0 } else { 255 >> bits };
+    }
+    target.into()
+}
+
+struct Job {
+    block: Block,
+    created: Instant,
+    /// pending txs of the mempool when the job was built
+    mempool_size: usize,
+    shares: HashSet<(u32, SystemTime)>,
+}
+
+struct Client {
+    stream: TcpStream,
+    /// nonces searched by the client, no two subscriptions share them
+    nonces: RangeInclusive<u32>,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: HashMap<u64, Job>,
+    next_job_id: u64,
+    /// the jobs before it are on an old tip
+    first_job_id: u64,
+    target: H256,
+    clients: HashMap<u64, Client>,
+    next_subscription: u64,
+    workers: HashMap<String, WorkerStats>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// held while a job is sent to the clients, so they get the jobs in order
+    notify: Mutex<()>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    server: ServerHandle,
+    /// receiver of the coinbase of the blocks found by the pool
+    payout: Option<H256>,
+    share_target: H256,
+    pow: PowAlgorithm,
+}
+
+pub struct Context {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+pub fn new(
+    addr: SocketAddr,
+    share_target: H256,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    server: &ServerHandle,
+    payout: Option<H256>,
+) -> io::Result<(Context, Handle)> {
+    let listener = TcpListener::bind(addr)?;
+    let pow = blockchain.lock().unwrap().spec.pow;
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::default()),
+        notify: Mutex::new(()),
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        server: server.clone(),
+        payout,
+        share_target,
+        pow,
+    });
+    let handle = Handle { shared: Arc::clone(&shared) };
+    Ok((Context { listener, shared }, handle))
+}
+
+impl Handle {
+    /// Shares of the workers which submitted any
+    pub fn workers(&self) -> Vec<WorkerStats> {
+        let state = self.shared.state.lock().unwrap();
+        let mut workers: Vec<WorkerStats> = state.workers.values().cloned().collect();
+        workers.sort_by(|a, b| a.worker.cmp(&b.worker));
+        workers
+    }
+}
+
+impl Context {
+    /// The address the pool listens at
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start the threads accepting the clients and renewing the jobs
+    pub fn start(self) {
+        let addr = self.local_addr().unwrap();
+        self.shared.new_job(true);
+        let shared = Arc::clone(&self.shared);
+        thread::Builder::new()
+            .name("pool-jobs".to_string())
+            .spawn(move || loop {
+                thread::sleep(JOB_POLL);
+                shared.renew_job();
+            })
+            .unwrap();
+        let Context { listener, shared } = self;
+        thread::Builder::new()
+            .name("pool-listener".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("Error accepting pool client: {}", e);
+                            continue;
+                        }
+                    };
+                    let shared = Arc::clone(&shared);
+                    thread::spawn(move || {
+                        let addr = stream.peer_addr().ok();
+                        if let Err(e) = shared.serve(stream) {
+                            debug!("Pool client {:?} disconnected: {}", addr, e);
+                        }
+                    });
+                }
+            })
+            .unwrap();
+        info!("Mining pool listening at {}", addr);
+    }
+}
+
+fn write_line(mut stream: &TcpStream, line: &ServerLine) -> io::Result<()> {
+    let mut bytes = serde_json::to_vec(line).unwrap();
+    bytes.push(b'\n');
+    stream.write_all(&bytes)
+}
+
+impl Shared {
+    /// Build a job on the tip and send it to all the clients
+    fn new_job(&self, clean: bool) {
+        let (template, mempool_size) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let mempool = self.mempool.lock().unwrap();
+            let template = BlockTemplate::from_mempool(&blockchain, &mempool, self.payout, block::VERSION_BASE);
+            (template, mempool.txs.len())
+        };
+        // a share is never harder than the block
+        let target = std::cmp::max(self.share_target, template.target);
+        let header = template.header.clone();
+        let _notify = self.notify.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let job_id = state.next_job_id;
+        state.next_job_id += 1;
+        if clean {
+            state.jobs.clear();
+            state.first_job_id = job_id;
+        }
+        if state.jobs.len() >= MAX_JOBS {
+            let oldest = *state.jobs.keys().min().unwrap();
+            state.jobs.remove(&oldest);
+        }
+        let job = Job { block: template.block(), created: Instant::now(), mempool_size, shares: HashSet::new() };
+        state.jobs.insert(job_id, job);
+        let new_target = state.target != target;
+        state.target = target;
+        debug!("New pool job {} on {:?}", job_id, header.parent);
+        // a slow client must not hold the state of the pool, write outside of the lock
+        let streams: Vec<TcpStream> = state.clients.values().filter_map(|c| c.stream.try_clone().ok()).collect();
+        drop(state);
+        for stream in streams {
+            let sent = if new_target { write_line(&stream, &ServerLine::SetTarget { target }) } else { Ok(()) };
+            if let Err(e) = sent.and_then(|_| write_line(&stream, &ServerLine::Notify { job_id, header: header.clone(), clean })) {
+                // the serving thread of the client stops and forgets it
+                debug!("Disconnect pool client {:?}: {}", stream.peer_addr().ok(), e);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+
+    /// Start a new job when the tip moved, or when the mempool changed and the job got old
+    fn renew_job(&self) {
+        let (parent, created, mempool_size) = {
+            let state = self.state.lock().unwrap();
+            match state.jobs.get(&(state.next_job_id - 1)) {
+                Some(job) => (job.block.header.parent, job.created, job.mempool_size),
+                None => (H256::default(), Instant::now(), 0),
+            }
+        };
+        if self.blockchain.lock().unwrap().tip() != parent {
+            self.new_job(true);
+        } else if created.elapsed() >= TEMPLATE_REFRESH && self.mempool.lock().unwrap().txs.len() != mempool_size {
+            self.new_job(false);
+        }
+    }
+
+    /// Answer the requests of a client until it disconnects
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut subscription = None;
+        let mut worker = None;
+        let mut reader = BufReader::new(stream.try_clone()?).take(MAX_LINE);
+        let result = loop {
+            let mut line = String::new();
+            reader.set_limit(MAX_LINE);
+            if reader.read_line(&mut line)? == 0 {
+                break Ok(());
+            }
+            let line: ClientLine = match serde_json::from_str(&line) {
+                Ok(l) => l,
+                Err(e) => {
+                    let error = format!("invalid request: {}", e);
+                    write_line(&stream, &ServerLine::Result { id: 0, error: Some(error.clone()) })?;
+                    break Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+            };
+            let id = line.id;
+            match line.request {
+                Request::Subscribe { agent } => {
+                    let mut state = self.state.lock().unwrap();
+                    let number = state.next_subscription;
+                    state.next_subscription += 1;
+                    let prefix = (number % (1 << (32 - SUBSCRIPTION_NONCE_BITS))) as u32;
+                    let nonces = prefix << SUBSCRIPTION_NONCE_BITS..=(prefix << SUBSCRIPTION_NONCE_BITS | ((1 << SUBSCRIPTION_NONCE_BITS) - 1));
+                    info!("Pool client {} subscribed, agent {}", number, agent);
+                    write_line(&stream, &ServerLine::Subscribed { id, nonces: nonces.clone(), pow: self.pow })?;
+                    write_line(&stream, &ServerLine::SetTarget { target: state.target })?;
+                    let job_id = state.next_job_id - 1;
+                    if let Some(job) = state.jobs.get(&job_id) {
+                        write_line(&stream, &ServerLine::Notify { job_id, header: job.block.header.clone(), clean: true })?;
+                    }
+                    if let Some(old) = subscription.replace(number) {
+                        state.clients.remove(&old);
+                    }
+                    state.clients.insert(number, Client { stream: stream.try_clone()?, nonces });
+                }
+                Request::Authorize { worker: name } => {
+                    info!("Pool worker {} authorized", name);
+                    worker = Some(name);
+                    write_line(&stream, &ServerLine::Result { id, error: None })?;
+                }
+                Request::Submit { job_id, nonce, timestamp } => {
+                    let error = match (subscription, &worker) {
+                        (None, _) => Some("not subscribed".to_string()),
+                        (_, None) => Some("unauthorized".to_string()),
+                        (Some(number), Some(name)) => self.submit(number, name, job_id, nonce, timestamp).err(),
+                    };
+                    write_line(&stream, &ServerLine::Result { id, error })?;
+                }
+            }
+        };
+        if let Some(number) = subscription {
+            self.state.lock().unwrap().clients.remove(&number);
+        }
+        result
+    }
+
+    /// Check and account a share, the block is submitted to the blockchain if the share solves it
+    fn submit(&self, subscription: u64, worker: &str, job_id: u64, nonce: u32, timestamp: SystemTime) -> Result<(), String> {
+        let solved = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let stats = state.workers.entry(worker.to_string()).or_insert_with(|| WorkerStats {
+                worker: worker.to_string(),
+                ..Default::default()
+            });
+            let checked = match state.jobs.get_mut(&job_id) {
+                None if job_id < state.first_job_id => {
+                    stats.stale += 1;
+                    return Err("stale job".to_string());
+                }
+                None => Err("unknown job".to_string()),
+                Some(job) => check_share(job, &state.clients[&subscription].nonces, state.target, &self.pow, nonce, timestamp),
+            };
+            match checked {
+                Ok(solved) => {
+                    stats.accepted += 1;
+                    solved
+                }
+                Err(e) => {
+                    stats.rejected += 1;
+                    return Err(e);
+                }
+            }
+        };
+        if let Some(block) = solved {
+            match template::submit(&block, &self.blockchain, &self.mempool) {
+                Ok(_) => {
+                    info!("Pool worker {} found block {:?}", worker, block.hash());
+                    self.server.broadcast_block(&block);
+                    if let Some(stats) = self.state.lock().unwrap().workers.get_mut(worker) {
+                        stats.blocks += 1;
+                    }
+                    self.new_job(true);
+                }
+                Err(e) => warn!("Block {:?} of pool worker {} rejected: {}", block.hash(), worker, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check a share of a job, return the solved block if the share solves it
+fn check_share(
+    job: &mut Job,
+    nonces: &RangeInclusive<u32>,
+    target: H256,
+    pow: &dyn Pow,
+    nonce: u32,
+    timestamp: SystemTime,
+) -> Result<Option<Block>, String> {
+    if !nonces.contains(&nonce) {
+        return Err("nonce out of range".to_string());
+    }
+    if timestamp < job.block.header.timestamp || timestamp > SystemTime::now() + MAX_TIME_DRIFT {
+        return Err("timestamp out of range".to_string());
+    }
+    let mut header = job.block.header.clone();
+    header.nonce = nonce;
+    header.timestamp = timestamp;
+    let hash = pow.pow_hash(&header);
+    if hash > target {
+        return Err("low difficulty share".to_string());
+    }
+    if !job.shares.insert((nonce, timestamp)) {
+        return Err("duplicate share".to_string());
+    }
+    if hash > header.difficulty {
+        return Ok(None);
+    }
+    let mut block = job.block.clone();
+    block.header = header;
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::clock::SystemClock;
+    use crate::miner::search::{self, HashRate};
+
+    fn send(stream: &TcpStream, id: u64, request: Request) {
+        let mut bytes = serde_json::to_vec(&ClientLine { id, request }).unwrap();
+        bytes.push(b'\n');
+        (&*stream).write_all(&bytes).unwrap();
+    }
+
+    fn recv(reader: &mut BufReader<TcpStream>) -> ServerLine {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn share_targets() {
+        assert_eq!(share_target(0), H256([255; 32]));
+        let target = share_target(12);
+        assert_eq!(target.0[0], 0);
+        assert_eq!(target.0[1], 0x0f);
+        assert_eq!(target.0[2], 255);
+    }
+
+    #[test]
+    fn submit_shares() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _receiver) = ServerHandle::new_for_test();
+        let (ctx, pool) = new("127.0.0.1:0".parse().unwrap(), share_target(4), &blockchain, &mempool, &server, Some(H256::rand())).unwrap();
+        let addr = ctx.local_addr().unwrap();
+        ctx.start();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        // a share before subscribing is rejected
+        send(&stream, 1, Request::Submit { job_id: 0, nonce: 0, timestamp: SystemTime::now() });
+        assert!(matches!(recv(&mut reader), ServerLine::Result { id: 1, error: Some(_) }));
+        send(&stream, 2, Request::Subscribe { agent: "test".to_string() });
+        let (nonces, pow) = match recv(&mut reader) {
+            ServerLine::Subscribed { id: 2, nonces, pow } => (nonces, pow),
+            line => panic!("unexpected {:?}", line),
+        };
+        assert_eq!(pow, PowAlgorithm::Sha256);
+        let target = match recv(&mut reader) {
+            ServerLine::SetTarget { target } => target,
+            line => panic!("unexpected {:?}", line),
+        };
+        assert_eq!(target, share_target(4));
+        let (job_id, header) = match recv(&mut reader) {
+            ServerLine::Notify { job_id, header, clean: true } => (job_id, header),
+            line => panic!("unexpected {:?}", line),
+        };
+        send(&stream, 3, Request::Authorize { worker: "alice".to_string() });
+        assert!(matches!(recv(&mut reader), ServerLine::Result { id: 3, error: None }));
+
+        // solve a share
+        let solved = search::search_nonces(&header, target, nonces.clone(), &pow, &SystemClock, 1, &HashRate::default(), || false).unwrap();
+        assert!(nonces.contains(&solved.nonce));
+        let share = Request::Submit { job_id, nonce: solved.nonce, timestamp: solved.timestamp };
+        send(&stream, 4, share.clone());
+        assert!(matches!(recv(&mut reader), ServerLine::Result { id: 4, error: None }));
+        send(&stream, 5, share);
+        match recv(&mut reader) {
+            ServerLine::Result { id: 5, error: Some(e) } => assert_eq!(e, "duplicate share"),
+            line => panic!("unexpected {:?}", line),
+        }
+        send(&stream, 6, Request::Submit { job_id, nonce: nonces.end() + 1, timestamp: solved.timestamp });
+        match recv(&mut reader) {
+            ServerLine::Result { id: 6, error: Some(e) } => assert_eq!(e, "nonce out of range"),
+            line => panic!("unexpected {:?}", line),
+        }
+
+        let workers = pool.workers();
+        assert_eq!(workers.len(), 1);
+        assert_eq!(workers[0].worker, "alice");
+        assert_eq!(workers[0].accepted, 1);
+        assert_eq!(workers[0].rejected, 2);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::types::block::Header;
+use crate::types::hash::H256;
+use crate::types::pow::Pow;
+use super::clock::Clock;
+use crossbeam::channel::{bounded, RecvTimeoutError};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of SHA-256 hashes a mining thread computes between checks of the stop flag
+const BATCH: u32 = 4096;
+/// Period of measuring the hash rate
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+/// Period of asking whether the search should stop
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hashes tried by the mining threads, and the hash rate measured from them
+#[derive(Debug, Default)]
+pub struct HashRate {
+    hashes: AtomicU64,
+    /// hashes per second over the last measurement
+    rate: AtomicU64,
+}
+
+impl HashRate {
+    fn add(&self, hashes: u64) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    /// Total number of hashes tried
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Hashes per second
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+}
+
+/// Split the nonces into disjoint ranges, one per thread
+pub fn nonce_ranges(nonces: &RangeInclusive<u32>, threads: usize) -> Vec<RangeInclusive<u32>> {
+    let (first, last) = (*nonces.start() as u64, *nonces.end() as u64);
+    let threads = threads.max(1) as u64;
+    let size = ((last + 1).saturating_sub(first) / threads).max(1);
+    (0..threads)
+        .map(|i| first + i * size)
+        .take_while(|start| *start <= last)
+        .enumerate()
+        .map(|(i, start)| {
+            let end = if i as u64 == threads - 1 { last } else { (start + size - 1).min(last) };
+            start as u32..=end as u32
+        })
+        .collect()
+}
+
+/// Move the timestamp forward, to the time of the clock or by a second, giving a fresh nonce space
+pub fn roll_timestamp(header: &mut Header, clock: &dyn Clock) {
+    let next = header.timestamp + Duration::from_secs(1);
+    header.timestamp = clock.now().max(next);
+}
+
+/// Try the nonces of the range on the header until its PoW hash is below the target, the timestamp is
+/// rolled when the range is exhausted. Return the solved header, or None once `stop` is set.
+fn search_range(
+    mut header: Header,
+    target: H256,
+    range: RangeInclusive<u32>,
+    pow: &dyn Pow,
+    clock: &dyn Clock,
+    stop: &AtomicBool,
+    hash_rate: &HashRate,
+) -> Option<Header> {
+    let batch = (BATCH / pow.cost()).max(1);
+    loop {
+        let mut tried = 0;
+        for nonce in range.clone() {
+            header.nonce = nonce;
+            if pow.pow_hash(&header) < target {
+                hash_rate.add(tried as u64 + 1);
+                return Some(header);
+            }
+            tried += 1;
+            if tried == batch {
+                hash_rate.add(tried as u64);
+                tried = 0;
+                if stop.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+        }
+        hash_rate.add(tried as u64);
+        roll_timestamp(&mut header, clock);
+    }
+}
+
+/// Search a nonce solving the header with the PoW algorithm on `threads` threads, each one searching its own
+/// nonce range, the timestamps are rolled with the clock. `should_stop` is polled every `POLL_INTERVAL`,
+/// the search gives up and returns None once it is true.
+pub fn search<F: FnMut() -> bool>(
+    header: &Header,
+    pow: &dyn Pow,
+    clock: &dyn Clock,
+    threads: usize,
+    hash_rate: &HashRate,
+    should_stop: F,
+) -> Option<Header> {
+    search_nonces(header, header.difficulty, 0..=u32::MAX, pow, clock, threads, hash_rate, should_stop)
+}
+
+/// Like `search`, only trying the given nonces until the PoW hash is below the target,
+/// which can be easier than the difficulty of the header
+#[allow(clippy::too_many_arguments)]
+pub fn search_nonces<F: FnMut() -> bool>(
+    header: &Header,
+    target: H256,
+    nonces: RangeInclusive<u32>,
+    pow: &dyn Pow,
+    clock: &dyn Clock,
+    threads: usize,
+    hash_rate: &HashRate,
+    mut should_stop: F,
+) -> Option<Header> {
+    let stop = AtomicBool::new(false);
+    let (found_sender, found_receiver) = bounded(threads.max(1));
+    thread::scope(|scope| {
+        for range in nonce_ranges(&nonces, threads) {
+            let header = header.clone();
+            let found_sender = found_sender.clone();
+            let stop = &stop;
+            scope.spawn(move || {
+                if let Some(header) = search_range(header, target, range, pow, clock, stop, hash_rate) {
+                    let _ = found_sender.send(header);
+                }
+            });
+        }
+        // measure the hash rate until a thread finds the nonce or the search is stopped
+        let mut last = (Instant::now(), hash_rate.hashes());
+        let solved = loop {
+            match found_receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(header) => break Some(header),
+                Err(RecvTimeoutError::Timeout) => {
+                    if should_stop() {
+                        break None;
+                    }
+                    let now = (Instant::now(), hash_rate.hashes());
+                    let elapsed = now.0.duration_since(last.0);
+                    if elapsed >= RATE_INTERVAL {
+                        hash_rate.set_rate(((now.1 - last.1) as f64 / elapsed.as_secs_f64()) as u64);
+                        last = now;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => unreachable!("mining threads never give up"),
+            }
+        };
+        stop.store(true, Ordering::Relaxed);
+        solved
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_header;
+    use crate::types::hash::Hashable;
+    use crate::miner::clock::{MockClock, SystemClock};
+    use crate::types::pow::PowAlgorithm;
+
+    #[test]
+    fn disjoint_nonce_ranges() {
+        let ranges = nonce_ranges(&(0..=u32::MAX), 3);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(*ranges[0].start(), 0);
+        assert_eq!(*ranges[2].end(), u32::MAX);
+        for pair in ranges.windows(2) {
+            assert_eq!(*pair[0].end() as u64 + 1, *pair[1].start() as u64);
+        }
+        assert_eq!(nonce_ranges(&(0..=u32::MAX), 1), vec![0..=u32::MAX]);
+        // a part of the nonces, smaller than the number of threads
+        assert_eq!(nonce_ranges(&(256..=511), 2), vec![256..=383, 384..=511]);
+        assert_eq!(nonce_ranges(&(7..=8), 4), vec![7..=7, 8..=8]);
+    }
+
+    #[test]
+    fn search_on_threads() {
+        let mut header = generate_random_header(&H256::rand());
+        // one hash out of 256
+        header.difficulty = H256([0; 32]);
+        header.difficulty.0[0] = 1;
+        let hash_rate = HashRate::default();
+        let solved = search(&header, &PowAlgorithm::Sha256, &SystemClock, 4, &hash_rate, || false).unwrap();
+        assert!(solved.hash() < header.difficulty);
+        assert_eq!(solved.parent, header.parent);
+        assert!(hash_rate.hashes() > 0);
+    }
+
+    #[test]
+    fn search_with_other_pow() {
+        let mut header = generate_random_header(&H256::rand());
+        // one hash out of 16
+        header.difficulty = H256([0; 32]);
+        header.difficulty.0[0] = 16;
+        for pow in [PowAlgorithm::DoubleSha256, PowAlgorithm::MemoryHard { memory_kib: 4 }] {
+            let solved = search(&header, &pow, &SystemClock, 2, &HashRate::default(), || false).unwrap();
+            assert!(pow.pow_hash(&solved) < header.difficulty);
+        }
+    }
+
+    #[test]
+    fn stopped_search() {
+        let mut header = generate_random_header(&H256::rand());
+        // no hash is below zero
+        header.difficulty = H256([0; 32]);
+        let hash_rate = HashRate::default();
+        let mut polls = 0;
+        let solved = search(&header, &PowAlgorithm::Sha256, &SystemClock, 2, &hash_rate, || {
+            polls += 1;
+            polls == 3
+        });
+        assert!(solved.is_none());
+        assert_eq!(polls, 3);
+    }
+
+    #[test]
+    fn rolled_timestamp_after_exhausted_range() {
+        let mut header = generate_random_header(&H256::rand());
+        let start = header.timestamp;
+        // a clock behind the header moves it by a second, a clock ahead moves it to its time
+        let clock = MockClock::new(start);
+        roll_timestamp(&mut header, &clock);
+        assert_eq!(header.timestamp, start + Duration::from_secs(1));
+        clock.advance(Duration::from_secs(60));
+        roll_timestamp(&mut header, &clock);
+        assert_eq!(header.timestamp, start + Duration::from_secs(60));
+        // a range of a single nonce which does not solve the header is retried with new timestamps
+        header.difficulty = H256([0; 32]);
+        header.difficulty.0[0] = 16;
+        let stop = AtomicBool::new(false);
+        let solved = search_range(header.clone(), header.difficulty, 7..=7, &PowAlgorithm::Sha256, &clock, &stop, &HashRate::default()).unwrap();
+        assert_eq!(solved.nonce, 7);
+        assert!(solved.hash() < header.difficulty);
+    }
+}
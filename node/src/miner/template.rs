@@ -0,0 +1,178 @@
+use serde::Serialize;
+use std::sync::Mutex;
+
+use crate::types::block::{self, Block, Body, Header};
+use crate::types::block_buffer;
+use crate::types::hash::{Hashable, H256};
+use crate::types::mempool::Mempool;
+use crate::types::merkle::MerkleTree;
+use crate::types::pow::PowAlgorithm;
+use crate::types::transaction::SignedTransaction;
+use crate::Blockchain;
+
+/// How the block pays its miner, the coinbase can claim at most `value`
+#[derive(Serialize, Debug, Clone)]
+pub struct CoinbaseParams {
+    pub height: u32,
+    pub subsidy: u64,
+    pub fees: u64,
+    /// subsidy plus fees
+    pub value: u64,
+    /// receiver of the coinbase included in the transactions, if any
+    pub payout: Option<H256>,
+}
+
+/// A block to solve on the tip of the blockchain. The header is complete, a miner only searches
+/// the nonce and the timestamp until the PoW hash of the header is below the target.
+#[derive(Serialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub header: Header,
+    pub target: H256,
+    /// PoW algorithm of the chain
+    pub pow: PowAlgorithm,
+    /// the first one is the coinbase when a payout is set, the merkle root of the header covers them all
+    pub transactions: Vec<SignedTransaction>,
+    pub coinbase: CoinbaseParams,
+}
+
+impl BlockTemplate {
+    /// Build a template on the tip of the blockchain wrapping the txs of the body, which pay `fees`,
+    /// with a coinbase paying the subsidy and the fees to `payout` if set
+    pub fn new(blockchain: &Blockchain, mut body: Body, fees: u64, payout: Option<H256>, version: u32) -> Self {
+        let height = blockchain.height + 1;
+        let subsidy = blockchain.spec.reward.subsidy(height);
+        if let Some(pk_hash) = payout {
+            body.txs.insert(0, SignedTransaction::coinbase(height, pk_hash, subsidy + fees));
+        }
+        let mut header = block::generate_random_header(&blockchain.tip());
+        header.version = version;
+        header.difficulty = blockchain.spec.difficulty;
+        header.merkle_root = MerkleTree::new(&body.txs).root();
+        BlockTemplate {
+            target: header.difficulty,
+            pow: blockchain.spec.pow,
+            header,
+            transactions: body.txs,
+            coinbase: CoinbaseParams { height, subsidy, fees, value: subsidy + fees, payout },
+        }
+    }
+
+    /// Build a template from the pending txs valid on the tip, which stay in the mempool
+    pub fn from_mempool(blockchain: &Blockchain, mempool: &Mempool, payout: Option<H256>, version: u32) -> Self {
+        let (body, _, fees) = mempool.valid_block_body(&block_buffer::utxo_after(&blockchain.tip(), blockchain, mempool));
+        BlockTemplate::new(blockchain, body, fees, payout, version)
+    }
+
+    /// The block of the template, to be solved
+    pub fn block(self) -> Block {
+        Block {
+            header: self.header,
+            body: Body {
+                tx_count: self.transactions.len(),
+                txs: self.transactions,
+            },
+        }
+    }
+}
+
+/// Validate a solved block whose parent is in the blockchain and insert it, its txs leave the pending txs
+/// of the mempool if it joins the longest chain. Return true if the block is the new tip.
+pub fn submit(block: &Block, blockchain: &Mutex<Blockchain>, mempool: &Mutex<Mempool>) -> Result<bool, String> {
+    let hash = block.hash();
+    let mut blockchain = blockchain.lock().unwrap();
+    if blockchain.blocks.contains_key(&hash) {
+        return Err("duplicate block".to_string());
+    }
+    let mut mempool = mempool.lock().unwrap();
+    block_buffer::validate_block(block, &blockchain, &mempool)?;
+    block_buffer::blockchain_insert_with_mempool_atomic(block.clone(), &mut blockchain, &mut mempool);
+    Ok(blockchain.tip() == hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::clock::SystemClock;
+    use crate::miner::search::{self, HashRate};
+    use crate::types::chain_spec::ChainSpec;
+    use crate::types::pow::Pow;
+
+    #[test]
+    fn solve_and_submit_template() {
+        let blockchain = Mutex::new(Blockchain::new());
+        let mempool = Mutex::new(Mempool::new());
+        let payout = H256::rand();
+        let template = {
+            let blockchain = blockchain.lock().unwrap();
+            BlockTemplate::from_mempool(&blockchain, &mempool.lock().unwrap(), Some(payout), block::VERSION_BASE)
+        };
+        assert_eq!(template.coinbase.height, 1);
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.transactions[0].transaction.outputs[0].pk_hash, payout);
+        let pow = template.pow;
+        let mut block = template.block();
+        assert!(block.validate_rules().is_ok());
+        // an unsolved block is rejected
+        if block.hash() > block.header.difficulty {
+            assert!(submit(&block, &blockchain, &mempool).is_err());
+        }
+        block.header = search::search(&block.header, &pow, &SystemClock, 2, &HashRate::default(), || false).unwrap();
+        assert_eq!(submit(&block, &blockchain, &mempool), Ok(true));
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+        assert!(submit(&block, &blockchain, &mempool).is_err());
+    }
+
+    #[test]
+    fn submit_with_the_pow_of_the_chain() {
+        let mut spec = ChainSpec::dev();
+        spec.pow = PowAlgorithm::DoubleSha256;
+        // one hash out of 16
+        spec.difficulty = H256([255; 32]);
+        spec.difficulty.0[0] = 15;
+        let blockchain = Mutex::new(Blockchain::new_with_spec(spec));
+        let mempool = Mutex::new(Mempool::new());
+        let template = BlockTemplate::from_mempool(&blockchain.lock().unwrap(), &mempool.lock().unwrap(), None, block::VERSION_BASE);
+        assert_eq!(template.pow, PowAlgorithm::DoubleSha256);
+        let mut block = template.block();
+        // the block id meets the target, not the PoW hash
+        let target = block.header.difficulty;
+        while block.hash() > target || PowAlgorithm::DoubleSha256.pow_hash(&block.header) <= target {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(submit(&block, &blockchain, &mempool), Err("invalid PoW".to_string()));
+        block.header = search::search(&block.header, &PowAlgorithm::DoubleSha256, &SystemClock, 1, &HashRate::default(), || false).unwrap();
+        assert_eq!(submit(&block, &blockchain, &mempool), Ok(true));
+    }
+
+    #[test]
+    fn template_of_the_txs_valid_on_the_tip() {
+        use crate::types::ico::{self, IcoGenerator};
+        use crate::types::transaction::{self, Input, Output, Transaction, Witness};
+        use ring::signature::KeyPair;
+
+        let spec = ChainSpec::simulated();
+        let blockchain = Mutex::new(Blockchain::new_with_spec(spec.clone()));
+        let mempool = Mutex::new(Mempool::new_with_k(spec.k));
+        let genesis = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
+        mempool.lock().unwrap().add_genesis(&genesis);
+        // alice spends her genesis output
+        let alice = &IcoGenerator::load_key(ico::CONFIG_PATH)[0];
+        let transaction = Transaction {
+            inputs: vec![Input::new(&genesis.body.txs[0].get_tx_hash(), 0)],
+            outputs: vec![Output { pk_hash: H256::rand(), value: genesis.body.txs[0].transaction.outputs[0].value }],
+        };
+        let sig = transaction::sign(&transaction, alice).as_ref().to_vec();
+        let tx = SignedTransaction { transaction, fee: 0, witnesses: vec![Witness { pubkey: alice.public_key().as_ref().to_vec(), sig }] };
+        mempool.lock().unwrap().add_tx(&tx).unwrap();
+        let template = BlockTemplate::from_mempool(&blockchain.lock().unwrap(), &mempool.lock().unwrap(), None, block::VERSION_BASE);
+        assert_eq!(template.transactions.len(), 1);
+        // the tx is in a block of the chain which is not final, it is still pending
+        let block = Block::new_block_from_txs(&genesis.hash(), &vec![tx]);
+        blockchain.lock().unwrap().insert(&block);
+        let template = BlockTemplate::from_mempool(&blockchain.lock().unwrap(), &mempool.lock().unwrap(), None, block::VERSION_BASE);
+        assert!(template.transactions.is_empty());
+        let mut block = template.block();
+        block.header = search::search(&block.header, &spec.pow, &SystemClock, 1, &HashRate::default(), || false).unwrap();
+        assert_eq!(submit(&block, &blockchain, &mempool), Ok(true));
+    }
+}
@@ -1,6 +1,5 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
-use crate::network::message;
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
@@ -54,10 +53,8 @@ impl Worker {
             info!("Insert a mined block {:?} to blockchain", _block.hash());
             */
             //broadcast 
-            let mut new_blocks = Vec::new();
-            new_blocks.push(_block.hash());
-            self.server.broadcast(message::Message::NewBlockHashes(new_blocks));
-            debug!("Broadcast a new block hash {:?} to peers", _block.hash());
+            self.server.broadcast_block(&_block);
+            debug!("Broadcast a new block {:?} to peers", _block.hash());
 
         }
     }
@@ -0,0 +1,250 @@
+use serde::{Serialize, Deserialize};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of addresses in an `Addr` message
+pub const MAX_ADDR: usize = 1000;
+/// Maximum number of addresses kept in the book
+pub const MAX_ENTRIES: usize = 5000;
+/// An address failing this many times in a row is forgotten
+pub const MAX_FAILURES: u32 = 10;
+/// Upper bound of the retry backoff in seconds
+pub const MAX_BACKOFF: u64 = 3600;
+
+/// What we know about a peer address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddrEntry {
+    /// listening address of the peer
+    pub addr: SocketAddr,
+    /// last time a handshake with it succeeded, in seconds since unix epoch, 0 if never
+    pub last_seen: u64,
+    /// last time we tried to connect to it, in seconds since unix epoch, 0 if never
+    pub last_attempt: u64,
+    /// number of failed connection attempts since it was last seen
+    pub failures: u32,
+}
+
+/// Known peer addresses, learned from the `-c` flags, the handshakes and the `Addr` messages,
+/// persisted as a JSON file so that a restarted node finds its peers again
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    path: Option<String>,
+    entries: HashMap<SocketAddr, AddrEntry>,
+    /// changed since the last save
+    dirty: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl AddrEntry {
+    fn new(addr: SocketAddr) -> Self {
+        AddrEntry { addr, last_seen: 0, last_attempt: 0, failures: 0 }
+    }
+
+    /// Seconds to wait after the last attempt before trying again, doubled by every failure
+    fn backoff(&self) -> u64 {
+        if self.failures == 0 {
+            return 0;
+        }
+        (1u64 << self.failures.min(12)).min(MAX_BACKOFF)
+    }
+}
+
+impl AddressBook {
+    /// An address book only kept in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the address book persisted at the path, an empty book is created if the file does not exist
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut book = AddressBook { path: Some(path.to_string()), ..Default::default() };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(book),
+            Err(e) => return Err(format!("error reading {}: {}", path, e)),
+        };
+        let entries: Vec<AddrEntry> = serde_json::from_str(&content).map_err(|e| format!("error parsing {}: {}", path, e))?;
+        book.entries = entries.into_iter().map(|e| (e.addr, e)).collect();
+        Ok(book)
+    }
+
+    /// Write the book to its file if it has changed
+    pub fn save(&mut self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        let mut entries: Vec<&AddrEntry> = self.entries.values().collect();
+        entries.sort_by_key(|e| e.addr);
+        let content = serde_json::to_string_pretty(&entries).unwrap();
+        fs::write(path, content).map_err(|e| format!("error writing {}: {}", path, e))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrEntry> {
+        self.entries.get(addr)
+    }
+
+    /// Learn a new address, nothing changes if it is already known. When the book is full, the new
+    /// address replaces the failing or never seen entry least worth a try, and is dropped if there is none
+    pub fn add(&mut self, addr: SocketAddr) {
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            return;
+        }
+        if self.entries.contains_key(&addr) {
+            return;
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            let evicted = self
+                .entries
+                .values()
+                .filter(|e| e.failures > 0 || e.last_seen == 0)
+                .max_by(|a, b| a.failures.cmp(&b.failures).then(b.last_seen.cmp(&a.last_seen)))
+                .map(|e| e.addr);
+            match evicted {
+                Some(evicted) => {
+                    self.entries.remove(&evicted);
+                }
+                None => return,
+            }
+        }
+        self.entries.insert(addr, AddrEntry::new(addr));
+        self.dirty = true;
+    }
+
+    /// A handshake with the peer succeeded
+    pub fn mark_seen(&mut self, addr: SocketAddr) {
+        self.add(addr);
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.last_seen = now();
+            entry.failures = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// A connection attempt to the peer failed, the address is forgotten after `MAX_FAILURES` failures
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.last_attempt = now();
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES {
+                warn!("Forget peer address {} after {} failures", addr, entry.failures);
+                self.entries.remove(&addr);
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// Up to n addresses to share in an `Addr` message, the most recently seen first
+    pub fn sample(&self, n: usize) -> Vec<SocketAddr> {
+        let mut entries: Vec<&AddrEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.failures.cmp(&b.failures)));
+        entries.iter().take(n).map(|e| e.addr).collect()
+    }
+
+    /// Up to n addresses worth a connection attempt now, skipping the excluded ones and those in backoff
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>, n: usize) -> Vec<SocketAddr> {
+        let now = now();
+        let mut entries: Vec<&AddrEntry> = self
+            .entries
+            .values()
+            .filter(|e| !exclude.contains(&e.addr))
+            .filter(|e| e.last_attempt + e.backoff() <= now)
+            .collect();
+        // never failing and recently seen addresses are tried first
+        entries.sort_by(|a, b| a.failures.cmp(&b.failures).then(b.last_seen.cmp(&a.last_seen)));
+        entries.iter().take(n).map(|e| e.addr).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn seen_and_failures() {
+        let mut book = AddressBook::new();
+        book.add(addr(6000));
+        book.add(addr(6000));
+        book.add(SocketAddr::from(([0, 0, 0, 0], 6001)));
+        assert_eq!(book.len(), 1);
+        book.mark_seen(addr(6002));
+        assert_eq!(book.sample(10), vec![addr(6002), addr(6000)]);
+        // a failing address is in backoff
+        book.mark_failed(addr(6000));
+        assert_eq!(book.get(&addr(6000)).unwrap().failures, 1);
+        assert_eq!(book.candidates(&HashSet::new(), 10), vec![addr(6002)]);
+        let exclude: HashSet<SocketAddr> = vec![addr(6002)].into_iter().collect();
+        assert!(book.candidates(&exclude, 10).is_empty());
+        // seen again resets the failures
+        book.mark_seen(addr(6000));
+        assert_eq!(book.get(&addr(6000)).unwrap().failures, 0);
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed(addr(6000));
+        }
+        assert!(book.get(&addr(6000)).is_none());
+    }
+
+    #[test]
+    fn full_book() {
+        let mut book = AddressBook::new();
+        for i in 0..MAX_ENTRIES {
+            book.mark_seen(SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 6000)));
+        }
+        // only good addresses, the new one is dropped
+        book.add(addr(6000));
+        assert_eq!(book.len(), MAX_ENTRIES);
+        assert!(book.get(&addr(6000)).is_none());
+        // the most failing address makes room
+        let failing = SocketAddr::from(([10, 0, 0, 1], 6000));
+        book.mark_failed(failing);
+        book.mark_failed(failing);
+        book.mark_failed(SocketAddr::from(([10, 0, 0, 2], 6000)));
+        book.add(addr(6000));
+        assert_eq!(book.len(), MAX_ENTRIES);
+        assert!(book.get(&failing).is_none());
+        // then the never seen ones
+        book.add(addr(6001));
+        assert!(book.get(&SocketAddr::from(([10, 0, 0, 2], 6000))).is_none());
+        book.add(addr(6002));
+        assert!(book.get(&addr(6000)).is_none() ^ book.get(&addr(6001)).is_none());
+        assert!(book.get(&addr(6002)).is_some());
+    }
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("address_book_test_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let mut book = AddressBook::load(path).unwrap();
+        assert!(book.is_empty());
+        book.mark_seen(addr(6000));
+        book.add(addr(6001));
+        book.mark_failed(addr(6001));
+        book.save().unwrap();
+        let loaded = AddressBook::load(path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&addr(6000)), book.get(&addr(6000)));
+        assert_eq!(loaded.get(&addr(6001)).unwrap().failures, 1);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A peer whose misbehavior score reaches it is banned
+pub const BAN_SCORE: u32 = 100;
+/// How long a misbehaving peer is banned
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 3600);
+/// Longest ban set through the API
+pub const MAX_BAN_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// A banned ip and the end of its ban
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// in seconds since unix epoch
+    pub until: u64,
+}
+
+/// Time-limited bans by ip, the peers with a banned ip are neither accepted nor connected to.
+/// The ip is banned rather than the address, the port of an incoming peer changes every connection.
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban the ip for the duration from now, a longer existing ban is kept
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        let until = now().saturating_add(duration.as_secs());
+        let entry = self.bans.entry(ip).or_insert(0);
+        *entry = (*entry).max(until);
+    }
+
+    /// Lift the ban of the ip, return false if it is not banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.bans.get(ip) {
+            Some(until) => *until > now(),
+            None => false,
+        }
+    }
+
+    /// The bans in effect, the expired ones are removed
+    pub fn list(&mut self) -> Vec<Ban> {
+        let now = now();
+        self.bans.retain(|_, until| *until > now);
+        let mut bans: Vec<Ban> = self.bans.iter().map(|(ip, until)| Ban { ip: *ip, until: *until }).collect();
+        bans.sort_by_key(|b| b.ip);
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_and_expire() {
+        let mut bans = BanList::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        bans.ban(ip, Duration::from_secs(100));
+        assert!(bans.is_banned(&ip));
+        assert!(!bans.is_banned(&other));
+        // a shorter ban does not shorten the existing one
+        bans.ban(ip, Duration::from_secs(1));
+        assert!(bans.list()[0].until >= now() + 99);
+        // expired
+        bans.ban(other, Duration::from_secs(0));
+        assert!(!bans.is_banned(&other));
+        assert_eq!(bans.list().len(), 1);
+        assert!(bans.unban(&ip));
+        assert!(!bans.unban(&ip));
+        assert!(!bans.is_banned(&ip));
+        // a ban without end
+        bans.ban(ip, Duration::from_secs(u64::MAX));
+        assert_eq!(bans.list()[0].until, u64::MAX);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::types::block::{Block, Body, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::SignedTransaction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Maximum number of txs of a compact block, and of tx indexes in `GetBlockTxs` and `BlockTxs`
+pub const MAX_BLOCK_TXS: usize = 100_000;
+
+/// Short id of a tx in a compact block: the first 8 bytes of sha256(block hash || wtxid).
+/// Salting with the block hash keeps a collision from being reused in every block.
+pub fn short_id(block_hash: &H256, wtxid: &H256) -> u64 {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(&block_hash.0);
+    ctx.update(&wtxid.0);
+    let mut id = [0; 8];
+    id.copy_from_slice(&ctx.finish().as_ref()[0..8]);
+    u64::from_le_bytes(id)
+}
+
+/// A tx sent in full in a compact block, with its index in the block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrefilledTx {
+    pub index: u32,
+    pub tx: SignedTransaction,
+}
+
+/// A block announced with its header and the short ids of its txs, the receiver rebuilds it
+/// from its mempool. The coinbase is never in a mempool, it is prefilled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// short ids of the txs not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    /// prefilled txs, by increasing index
+    pub prefilled: Vec<PrefilledTx>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        let mut short_ids = vec![];
+        let mut prefilled = vec![];
+        for (index, tx) in block.body.txs.iter().enumerate() {
+            if tx.is_coinbase() {
+                prefilled.push(PrefilledTx { index: index as u32, tx: tx.clone() });
+            } else {
+                short_ids.push(short_id(&hash, &tx.get_wtxid()));
+            }
+        }
+        CompactBlock { header: block.header.clone(), short_ids, prefilled }
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Fill the txs of the block from the candidate txs, usually the mempool. A short id matching
+    /// several candidates is left missing, the tx is requested rather than guessed.
+    pub fn reconstruct<'a, I>(&self, candidates: I) -> Result<PartialBlock, String>
+    where
+        I: IntoIterator<Item = &'a SignedTransaction>,
+    {
+        let tx_count = self.tx_count();
+        let mut txs: Vec<Option<SignedTransaction>> = vec![None; tx_count];
+        let mut last = None;
+        for prefilled in &self.prefilled {
+            let index = prefilled.index as usize;
+            if index >= tx_count || matches!(last, Some(last) if index <= last) {
+                return Err(format!("invalid prefilled tx index {}", index));
+            }
+            last = Some(index);
+            txs[index] = Some(prefilled.tx.clone());
+        }
+        // the positions of the short ids are the ones left by the prefilled txs
+        let positions: Vec<usize> = (0..tx_count).filter(|i| txs[*i].is_none()).collect();
+        let mut wanted: HashMap<u64, Option<usize>> = HashMap::new();
+        for (id, position) in self.short_ids.iter().zip(positions) {
+            wanted
+                .entry(*id)
+                .and_modify(|p| *p = None)
+                .or_insert(Some(position));
+        }
+        let hash = self.header.hash();
+        let mut found: HashMap<usize, Option<SignedTransaction>> = HashMap::new();
+        for tx in candidates {
+            if let Some(Some(position)) = wanted.get(&short_id(&hash, &tx.get_wtxid())) {
+                found
+                    .entry(*position)
+                    .and_modify(|t| *t = None)
+                    .or_insert_with(|| Some(tx.clone()));
+            }
+        }
+        for (position, tx) in found {
+            txs[position] = tx;
+        }
+        Ok(PartialBlock { header: self.header.clone(), txs })
+    }
+}
+
+/// A compact block being rebuilt, waiting for its missing txs
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub header: Header,
+    txs: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Indexes of the txs still missing
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.txs.len()).filter(|i| self.txs[*i].is_none()).map(|i| i as u32).collect()
+    }
+
+    /// Fill the missing txs with the reply to `GetBlockTxs`, in the order they were requested
+    pub fn fill(&mut self, txs: Vec<SignedTransaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return Err(format!("{} txs received, {} missing", txs.len(), missing.len()));
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            self.txs[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// The complete block, its txs must match the merkle root of the header, otherwise a short
+    /// id matched a wrong tx and the full block has to be requested
+    pub fn into_block(self) -> Result<Block, String> {
+        let txs: Option<Vec<SignedTransaction>> = self.txs.into_iter().collect();
+        let txs = txs.ok_or_else(|| "missing txs".to_string())?;
+        let block = Block { header: self.header, body: Body { tx_count: txs.len(), txs } };
+        block.validate_rules()?;
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::generate_random_transaction;
+
+    fn random_tx() -> SignedTransaction {
+        SignedTransaction { transaction: generate_random_transaction(), fee: 0, witnesses: vec![] }
+    }
+
+    fn block_with(txs: usize) -> Block {
+        let mut all = vec![SignedTransaction::coinbase(1, H256::rand(), 50)];
+        all.extend((0..txs).map(|_| random_tx()));
+        Block::new_block_from_txs(&H256::rand(), &all)
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let block = block_with(4);
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.short_ids.len(), 4);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.tx_count(), 5);
+        // the mempool has every tx, plus an unrelated one
+        let mut mempool: Vec<SignedTransaction> = block.body.txs[1..].to_vec();
+        mempool.push(random_tx());
+        let partial = compact.reconstruct(&mempool).unwrap();
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.into_block().unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn fill_missing_txs() {
+        let block = block_with(4);
+        let compact = CompactBlock::new(&block);
+        let mempool = vec![block.body.txs[1].clone(), block.body.txs[3].clone()];
+        let mut partial = compact.reconstruct(&mempool).unwrap();
+        assert_eq!(partial.missing(), vec![2, 4]);
+        assert!(partial.clone().into_block().is_err());
+        assert!(partial.fill(vec![block.body.txs[2].clone()]).is_err());
+        partial.fill(vec![block.body.txs[2].clone(), block.body.txs[4].clone()]).unwrap();
+        assert_eq!(partial.into_block().unwrap().body.txs.len(), 5);
+        // the wrong txs do not match the merkle root
+        let mut partial = compact.reconstruct(&mempool).unwrap();
+        partial.fill(vec![random_tx(), random_tx()]).unwrap();
+        assert!(partial.into_block().is_err());
+    }
+
+    #[test]
+    fn invalid_prefilled_index() {
+        let block = block_with(1);
+        let mut compact = CompactBlock::new(&block);
+        compact.prefilled[0].index = 2;
+        assert!(compact.reconstruct(&[]).is_err());
+    }
+}
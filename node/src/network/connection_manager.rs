@@ -0,0 +1,98 @@
+use super::address_book::AddressBook;
+use super::message::{Message, Version};
+use super::peer;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Period of checking the outbound connections
+pub const CHECK_INTERVAL: time::Duration = time::Duration::from_millis(1000);
+
+/// Keep the outbound connections: the seed peers are always reconnected, and addresses from the
+/// address book are tried until there are `target` outbound peers
+pub struct ConnectionManager {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    address_book: Arc<Mutex<AddressBook>>,
+    seeds: Vec<SocketAddr>,
+    target: usize,
+    outbound: HashMap<SocketAddr, peer::Handle>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        address_book: &Arc<Mutex<AddressBook>>,
+        seeds: Vec<SocketAddr>,
+        target: usize,
+    ) -> Self {
+        Self {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            address_book: Arc::clone(address_book),
+            seeds,
+            target,
+            outbound: HashMap::new(),
+        }
+    }
+
+    pub fn start(mut self) {
+        info!("Connection manager started, target {} outbound peers", self.target);
+        thread::Builder::new()
+            .name("connection-manager".to_string())
+            .spawn(move || loop {
+                self.check();
+                thread::sleep(CHECK_INTERVAL);
+            })
+            .unwrap();
+    }
+
+    fn check(&mut self) {
+        // forget the dropped peers
+        let dropped: Vec<SocketAddr> = self.outbound.iter().filter(|(_, p)| p.is_closed()).map(|(a, _)| *a).collect();
+        for addr in dropped {
+            info!("Outbound peer {} dropped", addr);
+            self.outbound.remove(&addr);
+        }
+        // the seeds are retried every time, like the `-c` flags always did
+        let seeds: Vec<SocketAddr> = self.seeds.iter().filter(|a| !self.outbound.contains_key(a)).cloned().collect();
+        for addr in seeds {
+            self.connect(addr);
+        }
+        if self.outbound.len() < self.target {
+            let mut exclude: HashSet<SocketAddr> = self.outbound.keys().cloned().collect();
+            exclude.insert(self.server.local_addr());
+            let candidates = self.address_book.lock().unwrap().candidates(&exclude, self.target - self.outbound.len());
+            for addr in candidates {
+                self.connect(addr);
+            }
+        }
+        if let Err(e) = self.address_book.lock().unwrap().save() {
+            warn!("Error saving the address book: {}", e);
+        }
+    }
+
+    /// Connect to a peer and start the handshake, the address book records a failure
+    fn connect(&mut self, addr: SocketAddr) {
+        debug!("Connecting to peer {}", addr);
+        match self.server.connect(addr) {
+            Ok(mut peer) => {
+                info!("Connected to outgoing peer {}", addr);
+                // the connecting side starts the handshake
+                let version = Version::local(&self.blockchain.lock().unwrap(), self.server.local_addr().port());
+                peer.write(Message::Version(version));
+                self.outbound.insert(addr, peer);
+            }
+            Err(e) => {
+                debug!("Error connecting to peer {}: {}", addr, e);
+                self.address_book.lock().unwrap().mark_failed(addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use log::{debug, info};
+use std::thread;
+use std::time::Duration;
+
+/// Period of checking the peers
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Every peer done the handshake is pinged this often, measuring its latency
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A peer not answering a ping in time is disconnected
+pub const PING_TIMEOUT: Duration = Duration::from_secs(60);
+/// A peer sending nothing for this long is disconnected, the pongs keep the live peers active
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Ping the peers periodically and disconnect the dead ones
+pub struct Keepalive {
+    server: ServerHandle,
+}
+
+impl Keepalive {
+    pub fn new(server: &ServerHandle) -> Self {
+        Self { server: server.clone() }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("keepalive".to_string())
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                self.check();
+            })
+            .unwrap();
+        info!("Keepalive started, ping every {} seconds", PING_INTERVAL.as_secs());
+    }
+
+    fn check(&self) {
+        for mut peer in self.server.list_peers() {
+            if peer.idle_time() > IDLE_TIMEOUT {
+                info!("Peer {} idle for {} seconds, disconnecting", peer.addr(), peer.idle_time().as_secs());
+                peer.disconnect();
+                continue;
+            }
+            if matches!(peer.ping_wait(), Some(wait) if wait > PING_TIMEOUT) {
+                info!("Peer {} did not answer the ping, disconnecting", peer.addr());
+                peer.disconnect();
+                continue;
+            }
+            if !peer.handshake_done() {
+                continue;
+            }
+            if let Some(nonce) = peer.start_ping(PING_INTERVAL) {
+                debug!("Keepalive ping {} to {}", nonce, peer.addr());
+                peer.write(Message::Ping(nonce));
+            }
+        }
+    }
+}
@@ -1,6 +1,119 @@
+use bincode::Options;
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::address_book::MAX_ADDR;
+use super::compact_block::{CompactBlock, MAX_BLOCK_TXS};
+use super::sync::MAX_HEADERS;
+use crate::blockchain::Blockchain;
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+
+/// Version of the p2p protocol
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Peers from this protocol version get the new blocks as compact blocks
+pub const COMPACT_BLOCKS_VERSION: u32 = 2;
+/// Peers below this protocol version are disconnected
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const USER_AGENT: &str = concat!("/RBTC:", env!("CARGO_PKG_VERSION"), "/");
+/// Service bit of a full node which serves blocks
+pub const SERVICE_FULL_NODE: u64 = 1;
+
+/// Size of the frame header: magic bytes, payload length and payload checksum
+pub const FRAME_HEADER_SIZE: usize = 12;
+/// Maximum payload size of a frame, a peer announcing a larger frame is disconnected
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Maximum number of hashes in `NewBlockHashes`, `GetBlocks`, `NewTransactionHashes` and `GetTransactions`,
+/// and of blocks and txs in the replies
+pub const MAX_INV: usize = 1000;
+/// Maximum number of hashes in a block locator, enough for 2^90 blocks
+pub const MAX_LOCATOR: usize = 101;
+/// Maximum length of the strings of `Version`, `Ping` and `Pong`
+pub const MAX_STRING: usize = 256;
+
+/// Header of a frame on the wire, followed by the bincode payload of a `Message`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// magic bytes of the network, see `ChainSpec::magic`
+    pub magic: [u8; 4],
+    pub length: u32,
+    /// first 4 bytes of the sha256 of the payload
+    pub checksum: [u8; 4],
+}
+
+impl FrameHeader {
+    pub fn new(magic: [u8; 4], payload: &[u8]) -> Self {
+        FrameHeader { magic, length: payload.len() as u32, checksum: checksum(payload) }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut bytes = [0; FRAME_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic);
+        bytes[4..8].copy_from_slice(&self.length.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.checksum);
+        bytes
+    }
+
+    /// Parse a frame header of the network with the magic bytes
+    pub fn parse(bytes: &[u8; FRAME_HEADER_SIZE], magic: &[u8; 4]) -> Result<Self, String> {
+        let mut header = FrameHeader { magic: [0; 4], length: 0, checksum: [0; 4] };
+        header.magic.copy_from_slice(&bytes[0..4]);
+        let mut length = [0; 4];
+        length.copy_from_slice(&bytes[4..8]);
+        header.length = u32::from_be_bytes(length);
+        header.checksum.copy_from_slice(&bytes[8..12]);
+        if header.magic != *magic {
+            return Err(format!("unexpected magic bytes {}", hex::encode(header.magic)));
+        }
+        if header.length as usize > MAX_FRAME_SIZE {
+            return Err(format!("frame of {} bytes is too large", header.length));
+        }
+        Ok(header)
+    }
+
+    pub fn check_payload(&self, payload: &[u8]) -> Result<(), String> {
+        if checksum(payload) != self.checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&digest.as_ref()[0..4]);
+    checksum
+}
+
+/// The first message of a connection, sent by the connecting side. The accepting side checks it,
+/// replies with its own `Version` and a `Verack`, then the connecting side replies a `Verack`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol_version: u32,
+    /// network name of the chain spec
+    pub network: String,
+    pub genesis: H256,
+    pub best_height: u32,
+    pub user_agent: String,
+    pub services: u64,
+    /// port of the p2p server of the sender, with the ip of the connection it is the address to share
+    pub listen_port: u16,
+}
+
+impl Version {
+    /// The version message of this node
+    pub fn local(blockchain: &Blockchain, listen_port: u16) -> Version {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            network: blockchain.spec.network.clone(),
+            genesis: blockchain.genesis_hash(),
+            best_height: blockchain.height,
+            user_agent: USER_AGENT.to_string(),
+            services: SERVICE_FULL_NODE,
+            listen_port,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +125,100 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(Version),
+    Verack,
+    GetAddr,
+    /// listening addresses of known peers, at most `address_book::MAX_ADDR`
+    Addr(Vec<SocketAddr>),
+    /// block locator of the longest chain of the sender
+    GetHeaders(Vec<H256>),
+    /// headers following the locator, at most `sync::MAX_HEADERS`
+    Headers(Vec<Header>),
+    /// a new block with the short ids of its txs, only sent to peers of `COMPACT_BLOCKS_VERSION`
+    CompactBlock(CompactBlock),
+    /// indexes of the txs of a compact block the sender could not find
+    GetBlockTxs(H256, Vec<u32>),
+    /// the txs of a block requested by `GetBlockTxs`, in the requested order
+    BlockTxs(H256, Vec<SignedTransaction>),
+}
+
+impl Message {
+    /// Deserialize a message payload, the collections announcing more items than the frame could
+    /// hold are rejected before allocation, so are the trailing bytes
+    pub fn decode(bytes: &[u8]) -> Result<Message, String> {
+        let msg: Message = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(MAX_FRAME_SIZE as u64)
+            .deserialize(bytes)
+            .map_err(|e| e.to_string())?;
+        msg.check_limits()?;
+        Ok(msg)
+    }
+
+    /// Check the number of items of each message type
+    pub fn check_limits(&self) -> Result<(), String> {
+        let (count, max) = match self {
+            Message::Ping(nonce) | Message::Pong(nonce) => (nonce.len(), MAX_STRING),
+            Message::NewBlockHashes(hashes)
+            | Message::GetBlocks(hashes)
+            | Message::NewTransactionHashes(hashes)
+            | Message::GetTransactions(hashes) => (hashes.len(), MAX_INV),
+            Message::Blocks(blocks) => (blocks.len(), MAX_INV),
+            Message::Transactions(txs) => (txs.len(), MAX_INV),
+            Message::Version(version) => (version.user_agent.len().max(version.network.len()), MAX_STRING),
+            Message::Verack | Message::GetAddr => (0, 0),
+            Message::Addr(addrs) => (addrs.len(), MAX_ADDR),
+            Message::GetHeaders(locator) => (locator.len(), MAX_LOCATOR),
+            Message::Headers(headers) => (headers.len(), MAX_HEADERS),
+            Message::CompactBlock(compact) => (compact.tx_count(), MAX_BLOCK_TXS),
+            Message::GetBlockTxs(_, indexes) => (indexes.len(), MAX_BLOCK_TXS),
+            Message::BlockTxs(_, txs) => (txs.len(), MAX_BLOCK_TXS),
+        };
+        if count > max {
+            return Err(format!("{} items, more than {}", count, max));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_header() {
+        let payload = bincode::serialize(&Message::GetAddr).unwrap();
+        let header = FrameHeader::new(*b"RBTC", &payload);
+        let parsed = FrameHeader::parse(&header.to_bytes(), b"RBTC").unwrap();
+        assert_eq!(parsed, header);
+        assert!(parsed.check_payload(&payload).is_ok());
+        assert!(parsed.check_payload(&[1, 2, 3]).is_err());
+        // another network
+        assert!(FrameHeader::parse(&header.to_bytes(), b"TEST").is_err());
+        // too large
+        let mut bytes = header.to_bytes();
+        bytes[4..8].copy_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(FrameHeader::parse(&bytes, b"RBTC").is_err());
+    }
+
+    #[test]
+    fn decode_limits() {
+        let hashes: Vec<H256> = (0..MAX_INV).map(|_| H256::rand()).collect();
+        let bytes = bincode::serialize(&Message::GetBlocks(hashes.clone())).unwrap();
+        assert!(Message::decode(&bytes).is_ok());
+        // too many items
+        let mut too_many = hashes;
+        too_many.push(H256::rand());
+        let bytes = bincode::serialize(&Message::GetBlocks(too_many)).unwrap();
+        assert!(Message::decode(&bytes).is_err());
+        // a length prefix larger than the frame is rejected without allocating it
+        let mut bytes = bincode::serialize(&Message::GetBlocks(vec![])).unwrap();
+        let len = bytes.len();
+        bytes[len - 8..].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Message::decode(&bytes).is_err());
+        // trailing bytes
+        let mut bytes = bincode::serialize(&Message::Verack).unwrap();
+        bytes.push(0);
+        assert!(Message::decode(&bytes).is_err());
+    }
 }
@@ -1,4 +1,12 @@
+pub mod address_book;
+pub mod ban_list;
+pub mod compact_block;
+pub mod connection_manager;
+pub mod keepalive;
 pub mod message;
 pub mod peer;
+pub mod rate_limit;
 pub mod server;
+pub mod sync;
+pub mod transport;
 pub mod worker;
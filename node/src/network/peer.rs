@@ -1,69 +1,402 @@
-use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use super::message::{Message, Version};
+use super::rate_limit::{RateLimiter, RequestKind};
+use futures::channel::mpsc;
+use log::{trace, warn};
+use serde::Serialize;
 use smol::Async;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Maximum number of messages waiting to be written to a peer, a peer too slow to read them is disconnected
+pub const WRITE_QUEUE_SIZE: usize = 1024;
+/// Maximum number of messages of a peer kept until its handshake is done
+pub const MAX_EARLY_MESSAGES: usize = 16;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
+    direction: Direction,
+) -> std::io::Result<(mpsc::Receiver<Vec<u8>>, Handle)> {
+    let (write_sender, write_receiver) = mpsc::channel(WRITE_QUEUE_SIZE);
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        state: Arc::new((Mutex::new(State::new()), Condvar::new())),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// Per-peer state shared by all the clones of a peer handle
+#[derive(Debug)]
+pub struct State {
+    /// the version message received from this peer
+    version: Option<Version>,
+    /// the peer has acknowledged our version
+    verack_received: bool,
+    /// misbehavior score, the peer is banned when it reaches `ban_list::BAN_SCORE`
+    score: u32,
+    /// rate limits of the requests of this peer
+    limiter: RateLimiter,
+    connected_at: Instant,
+    /// when the last frame was received from the peer
+    last_recv: Instant,
+    /// when the last keepalive ping was sent
+    last_ping: Option<Instant>,
+    /// nonce of the ping waiting for its pong, and when it was sent
+    ping_pending: Option<(String, Instant)>,
+    /// round-trip time of the last answered ping
+    latency: Option<Duration>,
+    /// static identity key proven by the peer in the encrypted transport handshake
+    identity: Option<Vec<u8>>,
+    /// messages received before the handshake was done, handled once it is
+    early_messages: Vec<Message>,
+}
+
+impl State {
+    fn new() -> Self {
+        let now = Instant::now();
+        State {
+            version: None,
+            verack_received: false,
+            score: 0,
+            limiter: RateLimiter::default(),
+            connected_at: now,
+            last_recv: now,
+            last_ping: None,
+            ping_pending: None,
+            latency: None,
+            identity: None,
+            early_messages: Vec::new(),
+        }
+    }
+}
+
+/// What becomes of a message received before the handshake is known to be done
+pub enum EarlyMessage {
+    /// the handshake is done, the message is handled now
+    Ready(Message),
+    /// kept until the handshake is done
+    Kept,
+    /// too many messages are kept, the message is dropped
+    Dropped,
+}
+
+/// Connection stats of a peer, see `/network/peers`
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: Direction,
+    pub handshake_done: bool,
+    pub user_agent: Option<String>,
+    pub protocol_version: Option<u32>,
+    pub best_height: Option<u32>,
+    pub connected_secs: u64,
+    /// seconds since the last message of the peer
+    pub idle_secs: u64,
+    /// round-trip time of the last answered ping, in milliseconds
+    pub latency_ms: Option<u64>,
+    /// how long the pending ping has waited for its pong, in milliseconds
+    pub ping_wait_ms: Option<u64>,
+    pub score: u32,
+    pub dropped_requests: u64,
+    /// hex of the identity key proven by the peer, if any
+    pub identity: Option<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    write_queue: mpsc::Sender<Vec<u8>>,
+    direction: Direction,
+    /// the condvar is notified when the handshake is done
+    state: Arc<(Mutex<State>, Condvar)>,
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: mpsc::Receiver<Vec<u8>>
 }
 
 impl Handle {
+    /// Queue a message to the peer, never blocks
     pub fn write(&mut self, msg: Message) {
         let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
+        if let Err(e) = self.write_queue.try_send(buffer) {
+            if e.is_full() {
+                warn!("Write queue of peer {} is full, disconnecting", self.addr);
+                self.disconnect();
+            } else {
                 trace!("Trying to send to disconnected peer");
             }
-        });
+        }
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
-    #[cfg(any(test,test_utilities))]
-    pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Close the connection, the writer task shuts down the socket once the queue is drained
+    pub fn disconnect(&self) {
+        // closing a clone closes the channel shared by all the clones
+        self.write_queue.clone().close_channel();
+    }
+
+    /// Record the version message of the peer, return true if it completes the handshake
+    pub fn set_version(&self, version: Version) -> bool {
+        let mut state = self.state.0.lock().unwrap();
+        state.version = Some(version);
+        self.check_handshake(&state)
+    }
+
+    pub fn version(&self) -> Option<Version> {
+        self.state.0.lock().unwrap().version.clone()
+    }
+
+    /// The address the peer accepts connections at, known once it has sent its version
+    pub fn listen_addr(&self) -> Option<std::net::SocketAddr> {
+        let state = self.state.0.lock().unwrap();
+        state.version.as_ref().map(|v| std::net::SocketAddr::new(self.addr.ip(), v.listen_port))
+    }
+
+    /// The connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
+    /// Record the verack of the peer, return true if it completes the handshake
+    pub fn set_verack_received(&self) -> bool {
+        let mut state = self.state.0.lock().unwrap();
+        if state.verack_received {
+            return false;
+        }
+        state.verack_received = true;
+        self.check_handshake(&state)
+    }
+
+    fn check_handshake(&self, state: &State) -> bool {
+        let done = state.version.is_some() && state.verack_received;
+        if done {
+            self.state.1.notify_all();
+        }
+        done
+    }
+
+    /// Add to the misbehavior score, return the new score
+    pub fn misbehave(&self, points: u32) -> u32 {
+        let mut state = self.state.0.lock().unwrap();
+        state.score = state.score.saturating_add(points);
+        state.score
+    }
+
+    pub fn score(&self) -> u32 {
+        self.state.0.lock().unwrap().score
+    }
+
+    /// Take the cost of a request out of the peer's bucket of the kind, return false if it is rate limited
+    pub fn allow_request(&self, kind: RequestKind, cost: u32) -> bool {
+        self.state.0.lock().unwrap().limiter.allow(kind, cost)
+    }
+
+    /// Number of requests of this peer dropped by the rate limits
+    pub fn dropped_requests(&self) -> u64 {
+        self.state.0.lock().unwrap().limiter.dropped()
+    }
+
+    pub fn set_identity(&self, identity: Option<Vec<u8>>) {
+        self.state.0.lock().unwrap().identity = identity;
+    }
+
+    /// The identity key proven by the peer in the encrypted transport handshake
+    pub fn identity(&self) -> Option<Vec<u8>> {
+        self.state.0.lock().unwrap().identity.clone()
+    }
+
+    /// A frame is received from the peer
+    pub fn record_activity(&self) {
+        self.state.0.lock().unwrap().last_recv = Instant::now();
+    }
+
+    /// Time since the last frame received from the peer
+    pub fn idle_time(&self) -> Duration {
+        self.state.0.lock().unwrap().last_recv.elapsed()
+    }
+
+    /// Start a keepalive ping if the last one is older than the interval and answered,
+    /// return the nonce to send
+    pub fn start_ping(&self, interval: Duration) -> Option<String> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.ping_pending.is_some() || matches!(state.last_ping, Some(t) if t.elapsed() < interval) {
+            return None;
+        }
+        let nonce = rand::random::<u64>().to_string();
+        let now = Instant::now();
+        state.last_ping = Some(now);
+        state.ping_pending = Some((nonce.clone(), now));
+        Some(nonce)
+    }
+
+    /// Record the pong of the pending ping, return the round-trip time, or None if the nonce is not
+    /// the pending one, e.g. the pong of a ping sent through the API
+    pub fn pong_received(&self, nonce: &str) -> Option<Duration> {
+        let mut state = self.state.0.lock().unwrap();
+        match &state.ping_pending {
+            Some((pending, sent)) if pending == nonce => {
+                let latency = sent.elapsed();
+                state.ping_pending = None;
+                state.latency = Some(latency);
+                Some(latency)
+            }
+            _ => None,
+        }
+    }
+
+    /// How long the pending ping has waited for its pong
+    pub fn ping_wait(&self) -> Option<Duration> {
+        self.state.0.lock().unwrap().ping_pending.as_ref().map(|(_, sent)| sent.elapsed())
+    }
+
+    /// Round-trip time of the last answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.state.0.lock().unwrap().latency
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        let state = self.state.0.lock().unwrap();
+        let version = state.version.as_ref();
+        PeerInfo {
+            addr: self.addr,
+            direction: self.direction,
+            handshake_done: state.version.is_some() && state.verack_received,
+            user_agent: version.map(|v| v.user_agent.clone()),
+            protocol_version: version.map(|v| v.protocol_version),
+            best_height: version.map(|v| v.best_height),
+            connected_secs: state.connected_at.elapsed().as_secs(),
+            idle_secs: state.last_recv.elapsed().as_secs(),
+            latency_ms: state.latency.map(|l| l.as_millis() as u64),
+            ping_wait_ms: state.ping_pending.as_ref().map(|(_, sent)| sent.elapsed().as_millis() as u64),
+            score: state.score,
+            dropped_requests: state.limiter.dropped(),
+            identity: state.identity.as_ref().map(hex::encode),
+        }
+    }
+
+    /// Both sides have exchanged and acknowledged the version messages
+    pub fn handshake_done(&self) -> bool {
+        let state = self.state.0.lock().unwrap();
+        state.version.is_some() && state.verack_received
+    }
+
+    /// Keep a message until the handshake is done. The messages of a peer are handled by several
+    /// worker threads, a message right after the verack may be handled before it.
+    pub fn defer_until_handshake(&self, msg: Message) -> EarlyMessage {
+        let mut state = self.state.0.lock().unwrap();
+        if state.version.is_some() && state.verack_received {
+            return EarlyMessage::Ready(msg);
+        }
+        if state.early_messages.len() >= MAX_EARLY_MESSAGES {
+            return EarlyMessage::Dropped;
+        }
+        state.early_messages.push(msg);
+        EarlyMessage::Kept
+    }
+
+    /// The messages kept until the handshake, empty while it is not done
+    pub fn take_early_messages(&self) -> Vec<Message> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.version.is_some() && state.verack_received {
+            std::mem::take(&mut state.early_messages)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Wait until the handshake is done, return false on timeout
+    pub fn wait_handshake(&self, timeout: Duration) -> bool {
+        let state = self.state.0.lock().unwrap();
+        let (state, _) = self
+            .state
+            .1
+            .wait_timeout_while(state, timeout, |s| s.version.is_none() || !s.verack_received)
+            .unwrap();
+        state.version.is_some() && state.verack_received
+    }
+
+    /// A handle of an incoming peer which has not done the handshake yet
+    #[cfg(any(test, feature = "test-utilities"))]
+    pub fn test_handle_without_handshake() -> (Handle, TestReceiver) {
+        let (s,r) = mpsc::channel(WRITE_QUEUE_SIZE);
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            direction: Direction::Incoming,
+            state: Arc::new((Mutex::new(State::new()), Condvar::new())),
         },
         TestReceiver {
             r
         })
     }
+
+    /// The same test handle with another address
+    #[cfg(any(test, feature = "test-utilities"))]
+    pub fn with_addr(mut self, addr: std::net::SocketAddr) -> Handle {
+        self.addr = addr;
+        self
+    }
+
+    /// The same test handle in the other direction
+    #[cfg(any(test, feature = "test-utilities"))]
+    pub fn with_direction(mut self, direction: Direction) -> Handle {
+        self.direction = direction;
+        self
+    }
+
+    #[cfg(any(test, feature = "test-utilities"))]
+    pub fn test_handle() -> (Handle, TestReceiver) {
+        let (handle, r) = Handle::test_handle_without_handshake();
+        {
+            let mut state = handle.state.0.lock().unwrap();
+            state.version = Some(Version {
+                protocol_version: super::message::PROTOCOL_VERSION,
+                network: String::new(),
+                genesis: Default::default(),
+                best_height: 0,
+                user_agent: String::new(),
+                services: 0,
+                listen_port: 0,
+            });
+            state.verack_received = true;
+        }
+        (handle, r)
+    }
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
         let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+
+    /// The next message written to the peer, None once the peer is disconnected
+    pub fn next_message(&mut self) -> Option<Message> {
+        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r))?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
+
+    /// Wait until the peer is disconnected, return false if a message arrives first
+    pub fn closed(&mut self) -> bool {
+        smol::block_on(futures::stream::StreamExt::next(&mut self.r)).is_none()
+    }
+}
@@ -0,0 +1,158 @@
+use super::message::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+/// The requests a peer can flood us with, each one limited by its own token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub enum RequestKind {
+    GetBlocks,
+    GetTransactions,
+    GetBlockTxs,
+    GetHeaders,
+    GetAddr,
+    Ping,
+}
+
+impl RequestKind {
+    /// The kind of a request and its cost in tokens, the number of requested items
+    pub fn of(msg: &Message) -> Option<(RequestKind, u32)> {
+        match msg {
+            Message::GetBlocks(hashes) => Some((RequestKind::GetBlocks, hashes.len() as u32)),
+            Message::GetTransactions(hashes) => Some((RequestKind::GetTransactions, hashes.len() as u32)),
+            Message::GetBlockTxs(_, indexes) => Some((RequestKind::GetBlockTxs, indexes.len() as u32)),
+            Message::GetHeaders(_) => Some((RequestKind::GetHeaders, 1)),
+            Message::GetAddr => Some((RequestKind::GetAddr, 1)),
+            Message::Ping(_) => Some((RequestKind::Ping, 1)),
+            _ => None,
+        }
+    }
+
+    /// Burst size and refill rate per second of the bucket
+    pub fn limit(&self) -> (f64, f64) {
+        match self {
+            // the sync asks at most 16 blocks at once, orphans a few more
+            RequestKind::GetBlocks => (2000.0, 200.0),
+            RequestKind::GetTransactions => (5000.0, 1000.0),
+            RequestKind::GetBlockTxs => (5000.0, 1000.0),
+            // every reply can hold 2000 headers
+            RequestKind::GetHeaders => (10.0, 2.0),
+            RequestKind::GetAddr => (2.0, 1.0 / 60.0),
+            RequestKind::Ping => (10.0, 1.0),
+        }
+    }
+}
+
+/// Token bucket, filled at a constant rate up to its capacity, a request takes its cost out of it
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    /// tokens per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        TokenBucket { capacity, rate, tokens: capacity, last: Instant::now() }
+    }
+
+    /// Take the tokens if there are enough, return false otherwise
+    pub fn try_take(&mut self, cost: u32) -> bool {
+        self.try_take_at(cost, Instant::now())
+    }
+
+    fn try_take_at(&mut self, cost: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens < cost as f64 {
+            return false;
+        }
+        self.tokens -= cost as f64;
+        true
+    }
+}
+
+/// The buckets of a peer, one per request kind
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<RequestKind, TokenBucket>,
+    /// requests dropped from this peer
+    dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn allow(&mut self, kind: RequestKind, cost: u32) -> bool {
+        let allowed = self
+            .buckets
+            .entry(kind)
+            .or_insert_with(|| {
+                let (capacity, rate) = kind.limit();
+                TokenBucket::new(capacity, rate)
+            })
+            .try_take(cost);
+        if !allowed {
+            self.dropped += 1;
+        }
+        allowed
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Number of requests dropped by the rate limits of all peers, by kind
+#[derive(Debug, Default)]
+pub struct DroppedRequests {
+    counts: BTreeMap<RequestKind, u64>,
+}
+
+impl DroppedRequests {
+    pub fn record(&mut self, kind: RequestKind) {
+        *self.counts.entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn counts(&self) -> BTreeMap<RequestKind, u64> {
+        self.counts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        bucket.last = start;
+        assert!(bucket.try_take_at(8, start));
+        assert!(!bucket.try_take_at(3, start));
+        assert!(bucket.try_take_at(2, start));
+        // refilled at 2 tokens per second
+        assert!(!bucket.try_take_at(1, start + Duration::from_millis(400)));
+        assert!(bucket.try_take_at(1, start + Duration::from_millis(500)));
+        // never more than the capacity
+        assert!(!bucket.try_take_at(11, start + Duration::from_secs(100)));
+        assert!(bucket.try_take_at(10, start + Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::default();
+        let (capacity, _) = RequestKind::GetBlocks.limit();
+        assert!(limiter.allow(RequestKind::GetBlocks, capacity as u32));
+        assert!(!limiter.allow(RequestKind::GetBlocks, 100));
+        // the kinds have their own buckets
+        assert!(limiter.allow(RequestKind::GetTransactions, 100));
+        assert_eq!(limiter.dropped(), 1);
+        let msg = Message::GetBlocks(vec![Default::default(); 3]);
+        assert_eq!(RequestKind::of(&msg), Some((RequestKind::GetBlocks, 3)));
+        assert_eq!(RequestKind::of(&Message::Verack), None);
+    }
+}
@@ -1,42 +1,65 @@
-use crate::types::address::Address;
+use super::ban_list::{Ban, BanList};
+use super::compact_block::CompactBlock;
 use super::peer;
+use super::rate_limit::{DroppedRequests, RequestKind};
+use super::transport::{self, Opener, Sealer, TransportConfig};
 use super::message;
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::HashMap;
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::thread;
 
+/// Time to wait for the TCP connection to a new peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub fn new(
     addr: std::net::SocketAddr,
+    magic: [u8; 4],
+    transport: Option<TransportConfig>,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let ban_list = Arc::new(Mutex::new(BanList::new()));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        addr,
+        ban_list: Arc::clone(&ban_list),
+        dropped_requests: Arc::new(Mutex::new(DroppedRequests::default())),
     };
     let ctx = Context {
-        peers: std::collections::HashMap::new(),
+        peers: HashMap::new(),
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        ban_list,
+        magic,
+        transport: transport.map(Arc::new),
     };
     Ok((ctx, handle))
 }
 
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    ban_list: Arc<Mutex<BanList>>,
+    /// magic bytes of the frames of the network
+    magic: [u8; 4],
+    /// the encrypted transport, plaintext if not set
+    transport: Option<Arc<TransportConfig>>,
 }
 
 impl Context {
@@ -82,14 +105,33 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    // dial outside of the control loop, an unreachable address must not stall it
+                    let ban_list = Arc::clone(&self.ban_list);
+                    let control_chan = self.control_sender.clone();
+                    ex.spawn(async move {
+                        match Self::dial(addr, ban_list).await {
+                            Ok(stream) => {
+                                let _ = control_chan.send(ControlSignal::ConnectedPeer(stream, result_chan)).await;
+                            }
+                            Err(e) => {
+                                let _ = result_chan.send(Err(e));
+                            }
+                        }
+                    })
+                        .detach();
+                }
+                ControlSignal::ConnectedPeer(stream, result_chan) => {
+                    trace!("Processing ConnectedPeer command");
+                    let handle = self.register(stream, peer::Direction::Outgoing, ex.clone()).await;
+                    let _ = result_chan.send(handle);
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
-                    }
+                    broadcast_message(&mut self.peers, &msg);
+                }
+                ControlSignal::BroadcastBlock(block) => {
+                    trace!("Processing BroadcastBlock command");
+                    broadcast_block(&mut self.peers, &block);
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
@@ -100,25 +142,57 @@ impl Context {
                     self.peers.remove(&addr);
                     info!("Peer {} disconnected", addr);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::DisconnectIp(ip) => {
+                    trace!("Processing DisconnectIp({})", ip);
+                    for (_, hd) in self.peers.iter().filter(|(addr, _)| addr.ip() == ip) {
+                        hd.disconnect();
+                    }
+                }
+                ControlSignal::SendToPeer(addr, msg, result_chan) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    let sent = match self.peers.get_mut(&addr) {
+                        Some(hd) => {
+                            hd.write(msg);
+                            true
+                        }
+                        None => false,
+                    };
+                    let _ = result_chan.send(sent);
+                }
+                ControlSignal::ListPeers(result_chan) => {
+                    trace!("Processing ListPeers");
+                    let _ = result_chan.send(self.peers.values().cloned().collect());
+                }
+                ControlSignal::DisconnectPeer(addr, result_chan) => {
+                    trace!("Processing DisconnectPeer({})", addr);
+                    let found = match self.peers.get(&addr) {
+                        Some(hd) => {
+                            hd.disconnect();
+                            true
+                        }
+                        None => false,
+                    };
+                    let _ = result_chan.send(found);
                 }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
+    /// Open a connection to a peer, the control loop registers it
+    async fn dial(
+        addr: std::net::SocketAddr,
+        ban_list: Arc<Mutex<BanList>>,
+    ) -> std::io::Result<Async<net::TcpStream>> {
+        if ban_list.lock().unwrap().is_banned(&addr.ip()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "the peer is banned"));
+        }
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
-
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+        let timeout = async {
+            smol::Timer::after(CONNECT_TIMEOUT).await;
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))
+        };
+        smol::future::or(Async::<std::net::TcpStream>::connect(addr), timeout).await
     }
 
     async fn accept(
@@ -126,6 +200,11 @@ impl Context {
         stream: Async<net::TcpStream>,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
+        let addr = stream.get_ref().peer_addr()?;
+        if self.ban_list.lock().unwrap().is_banned(&addr.ip()) {
+            info!("Refuse banned peer {}", addr);
+            return Ok(());
+        }
         self.register(stream, peer::Direction::Incoming, ex).await?;
         Ok(())
     }
@@ -133,88 +212,43 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (write_queue, handle) = peer::new(&stream, direction)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let magic = self.magic;
+        // with the encrypted transport, the reader does the handshake and passes the sending key to the writer
+        let (sealer_sender, sealer_receiver) = match self.transport {
+            Some(_) => {
+                let (s, r) = oneshot::channel();
+                (Some(s), Some(r))
+            }
+            None => (None, None),
+        };
+        let transport = self.transport.clone();
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
+        let reader_stream = stream.clone();
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
-            loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
-                        break;
-                    }
-                }
-            }
-            // the peer is disconnected
+            let transport = transport.zip(sealer_sender);
+            Self::read_loop(reader_stream, magic, transport, new_msg_chan, handle_copy.clone()).await;
+            // the peer is disconnected, stop the writer too
+            handle_copy.disconnect();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-            }
-            // the peer is disconnected
+            Self::write_loop(stream.clone(), magic, sealer_receiver, write_queue).await;
+            // the peer is disconnected, shut down the socket so that the reader stops too
+            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -226,26 +260,232 @@ impl Context {
         self.peers.insert(addr, handle.clone());
         Ok(handle)
     }
+
+    /// Read the frames of a peer until it is disconnected or sends an invalid frame
+    async fn read_loop(
+        mut stream: AsyncArc<Async<net::TcpStream>>,
+        magic: [u8; 4],
+        transport: Option<(Arc<TransportConfig>, oneshot::Sender<Sealer>)>,
+        new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+        handle: peer::Handle,
+    ) {
+        let addr = *handle.addr();
+        let mut opener = None;
+        if let Some((config, sealer_chan)) = transport {
+            let initiator = handle.direction() == peer::Direction::Outgoing;
+            let timeout = async {
+                smol::Timer::after(transport::HANDSHAKE_TIMEOUT).await;
+                Err(transport::invalid_data("timeout".to_string()))
+            };
+            let session = smol::future::or(transport::handshake(&mut stream, &config, magic, initiator), timeout).await;
+            match session {
+                Ok(session) => {
+                    match &session.remote_identity {
+                        Some(key) => info!("Encrypted transport with peer {}, identity {}", addr, hex::encode(key)),
+                        None => info!("Encrypted transport with anonymous peer {}", addr),
+                    }
+                    handle.set_identity(session.remote_identity);
+                    opener = Some(session.opener);
+                    if sealer_chan.send(session.sealer).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("Transport handshake with peer {} failed: {}", addr, e);
+                    return;
+                }
+            }
+        }
+        let mut reader = BufReader::new(stream);
+        // the buffer to store the frame header: magic bytes, length and checksum of the frame
+        let mut header_buffer = [0; message::FRAME_HEADER_SIZE];
+        // the buffer to store the message content
+        let mut msg_buffer: Vec<u8> = vec![];
+        loop {
+            let payload = match opener.as_mut() {
+                Some(opener) => Self::read_sealed_frame(&mut reader, opener, &magic).await,
+                None => Self::read_frame(&mut reader, &magic, &mut header_buffer, &mut msg_buffer).await,
+            };
+            let new_payload = match payload {
+                Ok(payload) => payload,
+                // a frame of another network, too large or corrupted closes the connection
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    warn!("Invalid frame from peer {}: {}", addr, e);
+                    break;
+                }
+                Err(_) => break,
+            };
+            handle.record_activity();
+            new_msg_chan
+                .send((new_payload, handle.clone()))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Read a plaintext frame, return its payload
+    async fn read_frame(
+        reader: &mut BufReader<AsyncArc<Async<net::TcpStream>>>,
+        magic: &[u8; 4],
+        header_buffer: &mut [u8; message::FRAME_HEADER_SIZE],
+        msg_buffer: &mut Vec<u8>,
+    ) -> std::io::Result<Vec<u8>> {
+        // first, read exactly the frame header
+        reader.read_exact(header_buffer).await?;
+        let header = message::FrameHeader::parse(header_buffer, magic).map_err(transport::invalid_data)?;
+        let msg_size = header.length as usize;
+        // then, read exactly msg_size bytes to get the whole message
+        if msg_buffer.len() < msg_size {
+            msg_buffer.resize(msg_size, 0);
+        }
+        reader.read_exact(&mut msg_buffer[0..msg_size]).await?;
+        header.check_payload(&msg_buffer[0..msg_size]).map_err(transport::invalid_data)?;
+        Ok(msg_buffer[0..msg_size].to_vec())
+    }
+
+    /// Read an encrypted record holding a whole frame, return the payload of the frame
+    async fn read_sealed_frame(
+        reader: &mut BufReader<AsyncArc<Async<net::TcpStream>>>,
+        opener: &mut Opener,
+        magic: &[u8; 4],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut frame = transport::read_record(reader, opener).await?;
+        if frame.len() < message::FRAME_HEADER_SIZE {
+            return Err(transport::invalid_data("record shorter than a frame header".to_string()));
+        }
+        let payload = frame.split_off(message::FRAME_HEADER_SIZE);
+        let mut header_bytes = [0; message::FRAME_HEADER_SIZE];
+        header_bytes.copy_from_slice(&frame);
+        let header = message::FrameHeader::parse(&header_bytes, magic).map_err(transport::invalid_data)?;
+        if header.length as usize != payload.len() {
+            return Err(transport::invalid_data("frame length mismatch".to_string()));
+        }
+        header.check_payload(&payload).map_err(transport::invalid_data)?;
+        Ok(payload)
+    }
+
+    /// Write the queued messages to a peer until the queue is closed or the connection fails
+    async fn write_loop(
+        stream: AsyncArc<Async<net::TcpStream>>,
+        magic: [u8; 4],
+        sealer_chan: Option<oneshot::Receiver<Sealer>>,
+        mut write_queue: futures::channel::mpsc::Receiver<Vec<u8>>,
+    ) {
+        // wait for the transport handshake, a failed one drops the sender
+        let mut sealer = match sealer_chan {
+            Some(chan) => match chan.await {
+                Ok(sealer) => Some(sealer),
+                Err(_) => return,
+            },
+            None => None,
+        };
+        let mut writer = BufWriter::new(stream);
+        // first, get a message to write from the queue, the queue is closed on disconnect
+        while let Some(new_msg) = write_queue.next().await {
+
+            // second, encode the frame header
+            let header = message::FrameHeader::new(magic, &new_msg).to_bytes();
+
+            // third, write the frame header and the payload, sealed in a record if encrypted
+            let written = match sealer.as_mut() {
+                Some(sealer) => {
+                    let mut frame = header.to_vec();
+                    frame.extend_from_slice(&new_msg);
+                    writer.write_all(&sealer.seal(&frame)).await
+                }
+                None => match writer.write_all(&header).await {
+                    Ok(_) => writer.write_all(&new_msg).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if written.is_err() {
+                break;
+            }
+            match writer.flush().await {
+                Ok(_) => {}
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Write the message to the peers, those in the middle of the handshake only get the handshake messages
+fn broadcast_message(peers: &mut HashMap<std::net::SocketAddr, peer::Handle>, msg: &message::Message) {
+    for (_, hd) in peers.iter_mut().filter(|(_, hd)| hd.handshake_done()) {
+        hd.write(msg.clone());
+    }
+}
+
+/// Announce a block to the peers done the handshake. The peers supporting compact blocks rebuild the
+/// block from their mempool, the others request the full block.
+fn broadcast_block(peers: &mut HashMap<std::net::SocketAddr, peer::Handle>, block: &Block) {
+    let compact = message::Message::CompactBlock(CompactBlock::new(block));
+    let hashes = message::Message::NewBlockHashes(vec![block.hash()]);
+    for (_, hd) in peers.iter_mut().filter(|(_, hd)| hd.handshake_done()) {
+        let compact_blocks = matches!(
+            hd.version(),
+            Some(v) if v.protocol_version >= message::COMPACT_BLOCKS_VERSION
+        );
+        hd.write(if compact_blocks { compact.clone() } else { hashes.clone() });
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    addr: std::net::SocketAddr,
+    ban_list: Arc<Mutex<BanList>>,
+    /// requests dropped by the rate limits of the peers
+    dropped_requests: Arc<Mutex<DroppedRequests>>,
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub struct TestReceiver{
     control_chan: smol::channel::Receiver<ControlSignal>,
+    ban_list: Arc<Mutex<BanList>>,
+    dropped_requests: Arc<Mutex<DroppedRequests>>,
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 impl TestReceiver {
+    pub fn is_banned(&self, ip: &std::net::IpAddr) -> bool {
+        self.ban_list.lock().unwrap().is_banned(ip)
+    }
+
+    pub fn dropped_requests(&self) -> std::collections::BTreeMap<RequestKind, u64> {
+        self.dropped_requests.lock().unwrap().counts()
+    }
+
     pub fn recv(&self) -> Option<message::Message> {
         let sig = smol::block_on(self.control_chan.recv()).unwrap();
         match sig {
             // in this test, only return broadcast msg
             ControlSignal::BroadcastMessage(msg) => Some(msg),
+            // what a peer without compact blocks gets
+            ControlSignal::BroadcastBlock(block) => Some(message::Message::NewBlockHashes(vec![block.hash()])),
             _ => None,
         }
     }
+
+    /// Wait for the next broadcast and write it to the peers like the server does, return false once
+    /// the server handles are all dropped
+    pub fn relay(&self, peers: &Mutex<HashMap<std::net::SocketAddr, peer::Handle>>) -> bool {
+        loop {
+            match smol::block_on(self.control_chan.recv()) {
+                Ok(ControlSignal::BroadcastMessage(msg)) => {
+                    broadcast_message(&mut peers.lock().unwrap(), &msg);
+                    return true;
+                }
+                Ok(ControlSignal::BroadcastBlock(block)) => {
+                    broadcast_block(&mut peers.lock().unwrap(), &block);
+                    return true;
+                }
+                // the bans and the connections are up to the test
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    }
 }
 
 impl Handle {
@@ -259,19 +499,78 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    /// The address the p2p server listens at
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Announce a new block, as a compact block to the peers supporting it
+    pub fn broadcast_block(&self, block: &Block) {
+        smol::block_on(self.control_chan.send(ControlSignal::BroadcastBlock(block.clone()))).unwrap();
+    }
+
+    /// Ban the ip for the duration and disconnect its peers
+    pub fn ban(&self, ip: std::net::IpAddr, duration: Duration) {
+        self.ban_list.lock().unwrap().ban(ip, duration);
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectIp(ip))).unwrap();
+    }
+
+    /// Lift the ban of the ip, return false if it is not banned
+    pub fn unban(&self, ip: &std::net::IpAddr) -> bool {
+        self.ban_list.lock().unwrap().unban(ip)
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.ban_list.lock().unwrap().list()
+    }
+
+    /// Count a request dropped by a rate limit
+    pub fn record_dropped(&self, kind: RequestKind) {
+        self.dropped_requests.lock().unwrap().record(kind);
     }
 
-    #[cfg(any(test,test_utilities))]
+    /// Number of requests dropped by the rate limits, by kind
+    pub fn dropped_requests(&self) -> std::collections::BTreeMap<RequestKind, u64> {
+        self.dropped_requests.lock().unwrap().counts()
+    }
+
+    /// Send a message to a connected peer, return false if there is no such peer
+    pub fn send(&self, addr: std::net::SocketAddr, msg: message::Message) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer(addr, msg, sender))).unwrap();
+        smol::block_on(receiver).unwrap_or(false)
+    }
+
+    /// The connected peers, including those in the middle of the handshake
+    pub fn list_peers(&self) -> Vec<peer::Handle> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ListPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap_or_default()
+    }
+
+    /// Disconnect a peer, return false if there is no such peer
+    pub fn disconnect_peer(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectPeer(addr, sender))).unwrap();
+        smol::block_on(receiver).unwrap_or(false)
+    }
+
+    #[cfg(any(test, feature = "test-utilities"))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
-        let t = TestReceiver {control_chan: r};
+        let ban_list = Arc::new(Mutex::new(BanList::new()));
+        let dropped_requests = Arc::new(Mutex::new(DroppedRequests::default()));
+        let h = Handle {
+            control_chan: s,
+            addr: std::net::SocketAddr::from(([127, 0, 0, 1], 6000)),
+            ban_list: Arc::clone(&ban_list),
+            dropped_requests: Arc::clone(&dropped_requests),
+        };
+        let t = TestReceiver {control_chan: r, ban_list, dropped_requests};
         (h,t)
     }
 }
@@ -282,7 +581,100 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
+    BroadcastBlock(Block),
+    /// an outgoing connection is open, register the peer
+    ConnectedPeer(
+        Async<net::TcpStream>,
+        oneshot::Sender<std::io::Result<peer::Handle>>,
+    ),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    DisconnectIp(std::net::IpAddr),
+    SendToPeer(std::net::SocketAddr, message::Message, oneshot::Sender<bool>),
+    ListPeers(oneshot::Sender<Vec<peer::Handle>>),
+    DisconnectPeer(std::net::SocketAddr, oneshot::Sender<bool>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntest::timeout;
+
+    /// a started server and the receiver of its messages
+    fn start_server(port: u16) -> (Handle, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        start_server_with(port, None)
+    }
+
+    fn start_server_with(
+        port: u16,
+        transport: Option<TransportConfig>,
+    ) -> (Handle, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        let (msg_tx, msg_rx) = smol::channel::unbounded();
+        let (ctx, handle) = new(std::net::SocketAddr::from(([127, 0, 0, 1], port)), *b"TEST", transport, msg_tx).unwrap();
+        ctx.start().unwrap();
+        (handle, msg_rx)
+    }
+
+    fn wait_until<F: Fn() -> bool>(f: F) {
+        while !f() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn send_list_disconnect() {
+        let (a, _a_rx) = start_server(16101);
+        let (b, b_rx) = start_server(16102);
+        a.connect(b.local_addr()).unwrap();
+        wait_until(|| b.list_peers().len() == 1);
+        assert_eq!(a.list_peers().len(), 1);
+        // addressed send
+        assert!(a.send(b.local_addr(), message::Message::Ping("hi".to_string())));
+        assert!(!a.send(std::net::SocketAddr::from(([127, 0, 0, 1], 1)), message::Message::Verack));
+        let (bytes, from) = smol::block_on(b_rx.recv()).unwrap();
+        assert!(matches!(message::Message::decode(&bytes), Ok(message::Message::Ping(_))));
+        // both sides forget the peer
+        assert!(b.disconnect_peer(*from.addr()));
+        wait_until(|| a.list_peers().is_empty() && b.list_peers().is_empty());
+        assert!(!b.disconnect_peer(*from.addr()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn connect_does_not_stall() {
+        let (a, _a_rx) = start_server(16106);
+        let (b, _b_rx) = start_server(16107);
+        // a non-routable address, the connection hangs until the timeout
+        let dialer = a.clone();
+        thread::spawn(move || dialer.connect(std::net::SocketAddr::from(([10, 255, 255, 1], 8333))));
+        thread::sleep(Duration::from_millis(100));
+        // the control loop still handles the other connections
+        a.connect(b.local_addr()).unwrap();
+        assert!(a.list_peers().iter().any(|p| *p.addr() == b.local_addr()));
+        wait_until(|| b.list_peers().len() == 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn encrypted_transport() {
+        use crate::types::key_pair;
+        use ring::signature::KeyPair;
+        let keys: Vec<Arc<_>> = (0..3).map(|_| Arc::new(key_pair::random())).collect();
+        let public = |i: usize| keys[i].public_key().as_ref().to_vec();
+        let allow = |i: usize| Some(vec![public(i)].into_iter().collect());
+        let (a, _a_rx) = start_server_with(16103, Some(TransportConfig { identity: Some(keys[0].clone()), allow_list: allow(1) }));
+        let (b, b_rx) = start_server_with(16104, Some(TransportConfig { identity: Some(keys[1].clone()), allow_list: allow(0) }));
+        let (c, _c_rx) = start_server_with(16105, Some(TransportConfig { identity: Some(keys[2].clone()), allow_list: None }));
+        a.connect(b.local_addr()).unwrap();
+        wait_until(|| b.list_peers().iter().any(|p| p.identity().is_some()));
+        assert_eq!(b.list_peers()[0].identity(), Some(public(0)));
+        assert!(a.send(b.local_addr(), message::Message::Ping("secret".to_string())));
+        let (bytes, _) = smol::block_on(b_rx.recv()).unwrap();
+        assert!(matches!(message::Message::decode(&bytes), Ok(message::Message::Ping(nonce)) if nonce == "secret"));
+        // c is not in the allow-list of a
+        c.connect(a.local_addr()).unwrap();
+        wait_until(|| c.list_peers().is_empty());
+        wait_until(|| a.list_peers().len() == 1);
+    }
 }
@@ -0,0 +1,264 @@
+use super::peer;
+use crate::blockchain::Blockchain;
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+use crate::types::block_buffer;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of headers in a `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// Maximum number of blocks requested from a peer and not received yet
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// A requested block not received in time is requested again, possibly from another peer
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers-first block download: the header chain is downloaded with `GetHeaders` and validated
+/// first, then the bodies of the validated headers are requested in parallel from all the peers
+/// having them. The bodies arrive in any order, the block buffer puts them in the blockchain.
+#[derive(Default)]
+pub struct Sync {
+    /// validated headers whose blocks are not in the blockchain yet, with their heights
+    headers: HashMap<H256, (Header, u32)>,
+    /// blocks to request, lowest first
+    queue: VecDeque<H256>,
+    /// requested blocks, the peer they are requested from and when
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    /// peers done the handshake, with the best height they are known to have
+    peers: HashMap<SocketAddr, (peer::Handle, u32)>,
+}
+
+impl Sync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A peer done the handshake can serve blocks up to its height
+    pub fn add_peer(&mut self, peer: &peer::Handle, best_height: u32) {
+        self.peers.insert(*peer.addr(), (peer.clone(), best_height));
+    }
+
+    /// The header or block is being downloaded
+    pub fn is_known(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        !self.headers.is_empty()
+    }
+
+    /// Validate headers received from a peer and queue the new ones for download, return the number of new headers.
+    /// Every header must connect to the blockchain or to a validated header and carry the difficulty of the chain spec with a valid PoW.
+    pub fn add_headers(&mut self, from: &SocketAddr, headers: &[Header], blockchain: &Blockchain) -> Result<usize, String> {
+        let mut added = 0;
+        for header in headers {
+            let hash = header.hash();
+            block_buffer::check_header(header, &blockchain.spec).map_err(|e| format!("{} of header {}", e, hash))?;
+            header.validate_version()?;
+            let parent_height = match blockchain.blocks.get(&header.parent) {
+                Some(parent) => parent.height,
+                None => match self.headers.get(&header.parent) {
+                    Some((_, height)) => *height,
+                    None => return Err(format!("header {} does not connect", hash)),
+                },
+            };
+            let height = parent_height + 1;
+            if let Some((_, best)) = self.peers.get_mut(from) {
+                *best = (*best).max(height);
+            }
+            if blockchain.blocks.contains_key(&hash) || self.headers.contains_key(&hash) {
+                continue;
+            }
+            self.headers.insert(hash, (header.clone(), height));
+            self.queue.push_back(hash);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Requested blocks are received
+    pub fn blocks_received(&mut self, hashes: &[H256]) {
+        for hash in hashes {
+            self.in_flight.remove(hash);
+        }
+    }
+
+    /// A downloaded block is invalid, forget its header and the headers built on it, they are not requested anymore
+    pub fn reject(&mut self, hash: &H256) {
+        if !self.headers.contains_key(hash) {
+            return;
+        }
+        let mut rejected = HashSet::from([*hash]);
+        // the parent of a header is lower, visited before it
+        let mut descendants: Vec<(&H256, &(Header, u32))> = self.headers.iter().collect();
+        descendants.sort_by_key(|(_, (_, height))| *height);
+        for (hash, (header, _)) in descendants {
+            if rejected.contains(&header.parent) {
+                rejected.insert(*hash);
+            }
+        }
+        self.headers.retain(|hash, _| !rejected.contains(hash));
+        self.queue.retain(|hash| !rejected.contains(hash));
+        self.in_flight.retain(|hash, _| !rejected.contains(hash));
+    }
+
+    /// Assign the queued blocks to the peers, return the block hashes to request from each peer.
+    /// Blocks of dropped peers and timed out requests are assigned again.
+    pub fn schedule(&mut self, blockchain: &Blockchain) -> Vec<(peer::Handle, Vec<H256>)> {
+        // forget the blocks in the blockchain
+        self.headers.retain(|hash, _| !blockchain.blocks.contains_key(hash));
+        let headers = &self.headers;
+        self.queue.retain(|hash| headers.contains_key(hash));
+        self.in_flight.retain(|hash, _| headers.contains_key(hash));
+        self.peers.retain(|_, (peer, _)| !peer.is_closed());
+        // requeue the blocks requested from dropped peers or timed out
+        let peers = &self.peers;
+        let mut retry: Vec<H256> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (addr, time))| !peers.contains_key(addr) || time.elapsed() > BLOCK_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        retry.sort_by_key(|hash| std::cmp::Reverse(self.headers[hash].1));
+        for hash in retry {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+
+        let mut assigned: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        let mut load: HashMap<SocketAddr, usize> = HashMap::new();
+        for (addr, _) in self.in_flight.values() {
+            *load.entry(*addr).or_insert(0) += 1;
+        }
+        let mut skipped = VecDeque::new();
+        while let Some(hash) = self.queue.pop_front() {
+            let height = self.headers[&hash].1;
+            // the least loaded peer having the block
+            let peer = self
+                .peers
+                .iter()
+                .filter(|(_, (_, best))| *best >= height)
+                .map(|(addr, _)| (*addr, *load.get(addr).unwrap_or(&0)))
+                .filter(|(_, n)| *n < MAX_BLOCKS_IN_FLIGHT)
+                .min_by_key(|(_, n)| *n);
+            match peer {
+                Some((addr, _)) => {
+                    *load.entry(addr).or_insert(0) += 1;
+                    assigned.entry(addr).or_default().push(hash);
+                    self.in_flight.insert(hash, (addr, Instant::now()));
+                }
+                None => skipped.push_back(hash),
+            }
+        }
+        self.queue = skipped;
+        assigned
+            .into_iter()
+            .map(|(addr, hashes)| (self.peers[&addr].0.clone(), hashes))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_header;
+    use crate::types::chain_spec::ChainSpec;
+
+    /// a blockchain on which any hash is a valid PoW
+    fn easy_blockchain() -> Blockchain {
+        let mut spec = ChainSpec::dev();
+        spec.difficulty = H256([255; 32]);
+        Blockchain::new_with_spec(spec)
+    }
+
+    fn header_chain(blockchain: &Blockchain, parent: H256, n: usize) -> Vec<Header> {
+        let mut headers: Vec<Header> = vec![];
+        for _ in 0..n {
+            let parent = headers.last().map(|h| h.hash()).unwrap_or(parent);
+            let mut header = generate_random_header(&parent);
+            header.difficulty = blockchain.spec.difficulty;
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn add_headers() {
+        let blockchain = easy_blockchain();
+        let mut sync = Sync::new();
+        let from = SocketAddr::from(([127, 0, 0, 1], 6000));
+        let headers = header_chain(&blockchain, blockchain.tip(), 5);
+        assert_eq!(sync.add_headers(&from, &headers, &blockchain), Ok(5));
+        assert_eq!(sync.add_headers(&from, &headers, &blockchain), Ok(0));
+        assert!(sync.is_known(&headers[4].hash()));
+        // extend the validated headers
+        let more = header_chain(&blockchain, headers[4].hash(), 2);
+        assert_eq!(sync.add_headers(&from, &more, &blockchain), Ok(2));
+        assert_eq!(sync.headers[&more[1].hash()].1, 7);
+        // not connected
+        let orphans = header_chain(&blockchain, H256::rand(), 2);
+        assert!(sync.add_headers(&from, &orphans, &blockchain).is_err());
+        // a difficulty other than the one of the chain spec, even a harder one
+        let mut harder = header_chain(&blockchain, blockchain.tip(), 1);
+        harder[0].difficulty.0[0] = 0;
+        assert!(sync.add_headers(&from, &harder, &blockchain).unwrap_err().starts_with("wrong difficulty"));
+        // invalid PoW on the dev network
+        let blockchain = Blockchain::new();
+        let mut headers = header_chain(&blockchain, blockchain.tip(), 1);
+        while headers[0].hash() <= blockchain.get_difficulty() {
+            headers[0].nonce += 1;
+        }
+        assert!(Sync::new().add_headers(&from, &headers, &blockchain).is_err());
+    }
+
+    #[test]
+    fn schedule_in_parallel() {
+        let blockchain = easy_blockchain();
+        let mut sync = Sync::new();
+        let (peer_a, _ra) = peer::Handle::test_handle();
+        let (peer_b, _rb) = peer::Handle::test_handle();
+        // the test handles have the same address by default
+        let peer_b = peer_b.with_addr(SocketAddr::from(([127, 0, 0, 1], 6001)));
+        sync.add_peer(&peer_a, 40);
+        sync.add_peer(&peer_b, 10);
+        let headers = header_chain(&blockchain, blockchain.tip(), 40);
+        sync.add_headers(peer_a.addr(), &headers, &blockchain).unwrap();
+        let assigned = sync.schedule(&blockchain);
+        let hashes_of = |addr: &SocketAddr| -> Vec<H256> {
+            assigned.iter().filter(|(p, _)| p.addr() == addr).flat_map(|(_, h)| h.clone()).collect()
+        };
+        // the first 10 blocks are shared, peer b does not have the others
+        let b_hashes = hashes_of(peer_b.addr());
+        assert_eq!(b_hashes.len(), 5);
+        assert_eq!(hashes_of(peer_a.addr()).len(), MAX_BLOCKS_IN_FLIGHT);
+        assert!(sync.schedule(&blockchain).is_empty());
+        // the blocks of a dropped peer are requested again, the lowest first
+        peer_b.disconnect();
+        sync.blocks_received(&[headers[10].hash(), headers[11].hash()]);
+        let assigned = sync.schedule(&blockchain);
+        assert_eq!(assigned.len(), 1);
+        assert_eq!(assigned[0].1, b_hashes[..2].to_vec());
+    }
+
+    #[test]
+    fn reject_descendants() {
+        let blockchain = easy_blockchain();
+        let mut sync = Sync::new();
+        let (peer, _r) = peer::Handle::test_handle();
+        sync.add_peer(&peer, 6);
+        let headers = header_chain(&blockchain, blockchain.tip(), 5);
+        let fork = header_chain(&blockchain, headers[1].hash(), 2);
+        sync.add_headers(peer.addr(), &headers, &blockchain).unwrap();
+        sync.add_headers(peer.addr(), &fork, &blockchain).unwrap();
+        assert_eq!(sync.schedule(&blockchain)[0].1.len(), 7);
+        // the invalid block and its descendants are forgotten, requested or not
+        sync.reject(&headers[2].hash());
+        assert!(headers[2..].iter().all(|h| !sync.is_known(&h.hash())));
+        assert!(headers[..2].iter().chain(&fork).all(|h| sync.is_known(&h.hash())));
+        assert_eq!(sync.in_flight.len(), 4);
+        assert!(sync.schedule(&blockchain).is_empty());
+        sync.reject(&H256::rand());
+        assert_eq!(sync.headers.len(), 4);
+    }
+}
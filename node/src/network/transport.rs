@@ -0,0 +1,355 @@
+use super::message::{FRAME_HEADER_SIZE, MAX_FRAME_SIZE};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, hkdf, rand, signature};
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Size of the X25519 and Ed25519 public keys
+pub const KEY_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+/// Maximum size of an encrypted record: a whole frame and the tag
+pub const MAX_RECORD_SIZE: usize = FRAME_HEADER_SIZE + MAX_FRAME_SIZE + aead::MAX_TAG_LEN;
+/// Maximum size of the identity record of the handshake, read before the peer is authenticated
+const MAX_HANDSHAKE_RECORD_SIZE: usize = KEY_SIZE + SIGNATURE_SIZE + aead::MAX_TAG_LEN;
+/// A peer not done the transport handshake in time is disconnected
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROTOCOL_INFO: &[u8] = b"rbtc-p2p-transport-v1";
+
+/// Settings of the encrypted transport, shared by all the connections
+pub struct TransportConfig {
+    /// static identity proven to the peers, anonymous if not set
+    pub identity: Option<Arc<Ed25519KeyPair>>,
+    /// if set, only the peers proving one of these identities are accepted
+    pub allow_list: Option<HashSet<Vec<u8>>>,
+}
+
+/// The keys of an encrypted connection and the identity proven by the peer
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    pub remote_identity: Option<Vec<u8>>,
+}
+
+/// Encrypts the records sent, the nonce is the number of records sent before
+pub struct Sealer {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+/// Decrypts the records received, a replayed, reordered or altered record fails
+pub struct Opener {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+fn new_key(bytes: &[u8]) -> aead::LessSafeKey {
+    aead::LessSafeKey::new(aead::UnboundKey::new(&aead::CHACHA20_POLY1305, bytes).unwrap())
+}
+
+impl Sealer {
+    /// Encrypt a record: its length then the ciphertext and tag, the length is authenticated too
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let len = ((plaintext.len() + aead::CHACHA20_POLY1305.tag_len()) as u32).to_be_bytes();
+        let mut in_out = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce(self.counter), aead::Aad::from(len), &mut in_out)
+            .unwrap();
+        self.counter += 1;
+        let mut record = len.to_vec();
+        record.extend(in_out);
+        record
+    }
+}
+
+impl Opener {
+    /// Decrypt the ciphertext of a record with its length
+    pub fn open(&mut self, len: [u8; 4], mut ciphertext: Vec<u8>) -> Result<Vec<u8>, String> {
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce(self.counter), aead::Aad::from(len), &mut ciphertext)
+            .map_err(|_| "record authentication failed".to_string())?
+            .len();
+        self.counter += 1;
+        ciphertext.truncate(plaintext_len);
+        Ok(ciphertext)
+    }
+}
+
+/// Read and decrypt the next record
+pub async fn read_record<R: AsyncRead + Unpin>(reader: &mut R, opener: &mut Opener) -> std::io::Result<Vec<u8>> {
+    read_record_of(reader, opener, MAX_RECORD_SIZE).await
+}
+
+/// Read and decrypt the next record, not larger than max_size
+async fn read_record_of<R: AsyncRead + Unpin>(reader: &mut R, opener: &mut Opener, max_size: usize) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).await?;
+    let size = u32::from_be_bytes(len) as usize;
+    if size > max_size {
+        return Err(invalid_data(format!("record of {} bytes is too large", size)));
+    }
+    let mut ciphertext = vec![0; size];
+    reader.read_exact(&mut ciphertext).await?;
+    opener.open(len, ciphertext).map_err(invalid_data)
+}
+
+pub fn invalid_data(e: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// HKDF output of a given length
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Derive the key of each direction and the session id from the shared secret, bound to the
+/// network and to both ephemeral keys
+fn derive(secret: &[u8], magic: &[u8; 4], initiator_key: &[u8], responder_key: &[u8], label: &[u8]) -> [u8; 32] {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, magic).extract(secret);
+    let info = [PROTOCOL_INFO, initiator_key, responder_key, label];
+    let mut out = [0; 32];
+    prk.expand(&info, Len(out.len())).unwrap().fill(&mut out).unwrap();
+    out
+}
+
+/// What an identity signs: the session id and the role of the signer, so a signature can be
+/// neither replayed in another session nor reflected back
+fn identity_message(session_id: &[u8; 32], initiator: bool) -> Vec<u8> {
+    let mut msg = session_id.to_vec();
+    msg.push(initiator as u8);
+    msg
+}
+
+/// Run the transport handshake on a new connection. Both sides send the network magic and an
+/// ephemeral X25519 key, derive the keys of the connection, then send their identity encrypted:
+/// an Ed25519 public key and its signature of the session id, or nothing if anonymous.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &TransportConfig,
+    magic: [u8; 4],
+    initiator: bool,
+) -> std::io::Result<Session> {
+    let rng = rand::SystemRandom::new();
+    let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+    let public_key = private_key.compute_public_key().unwrap();
+    let mut hello = magic.to_vec();
+    hello.extend_from_slice(public_key.as_ref());
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+
+    let mut remote_hello = [0; 4 + KEY_SIZE];
+    stream.read_exact(&mut remote_hello).await?;
+    if remote_hello[0..4] != magic {
+        return Err(invalid_data(format!("unexpected magic bytes {}", hex::encode(&remote_hello[0..4]))));
+    }
+    let remote_key = &remote_hello[4..];
+    let (initiator_key, responder_key) = if initiator {
+        (public_key.as_ref(), remote_key)
+    } else {
+        (remote_key, public_key.as_ref())
+    };
+    let peer_public_key = agreement::UnparsedPublicKey::new(&agreement::X25519, remote_key);
+    let (i2r, r2i, session_id) = agreement::agree_ephemeral(private_key, &peer_public_key, (), |secret| {
+        Ok((
+            derive(secret, &magic, initiator_key, responder_key, b"initiator"),
+            derive(secret, &magic, initiator_key, responder_key, b"responder"),
+            derive(secret, &magic, initiator_key, responder_key, b"session"),
+        ))
+    })
+    .map_err(|_| invalid_data("invalid ephemeral key".to_string()))?;
+    let (send_key, recv_key) = if initiator { (i2r, r2i) } else { (r2i, i2r) };
+    let mut sealer = Sealer { key: new_key(&send_key), counter: 0 };
+    let mut opener = Opener { key: new_key(&recv_key), counter: 0 };
+
+    let proof = match &config.identity {
+        Some(identity) => {
+            let mut proof = identity.public_key().as_ref().to_vec();
+            proof.extend_from_slice(identity.sign(&identity_message(&session_id, initiator)).as_ref());
+            proof
+        }
+        None => vec![],
+    };
+    stream.write_all(&sealer.seal(&proof)).await?;
+    stream.flush().await?;
+
+    let remote_proof = read_record_of(stream, &mut opener, MAX_HANDSHAKE_RECORD_SIZE).await?;
+    let remote_identity = match remote_proof.len() {
+        0 => None,
+        n if n == KEY_SIZE + SIGNATURE_SIZE => {
+            let (key, sig) = remote_proof.split_at(KEY_SIZE);
+            signature::UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(&identity_message(&session_id, !initiator), sig)
+                .map_err(|_| invalid_data("invalid identity signature".to_string()))?;
+            Some(key.to_vec())
+        }
+        n => return Err(invalid_data(format!("identity proof of {} bytes", n))),
+    };
+    if let Some(allow_list) = &config.allow_list {
+        match &remote_identity {
+            Some(key) if allow_list.contains(key) => {}
+            Some(key) => return Err(invalid_data(format!("identity {} is not allowed", hex::encode(key)))),
+            None => return Err(invalid_data("anonymous peer is not allowed".to_string())),
+        }
+    }
+    Ok(Session { sealer, opener, remote_identity })
+}
+
+/// Load the pkcs8 identity key of the node, a new key is generated and saved if the file does not exist.
+/// The saved key is only readable by its owner.
+pub fn load_identity(path: &str) -> Result<Ed25519KeyPair, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rand::SystemRandom::new()).unwrap();
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(|e| e.to_string())?;
+            file.write_all(pkcs8.as_ref()).map_err(|e| e.to_string())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    Ed25519KeyPair::from_pkcs8(&bytes).map_err(|e| format!("invalid key file {}: {}", path, e))
+}
+
+/// Parse the hex identity keys of an allow-list
+pub fn parse_allow_list<'a, I: IntoIterator<Item = &'a str>>(keys: I) -> Result<HashSet<Vec<u8>>, String> {
+    let mut allow_list = HashSet::new();
+    for key in keys {
+        let bytes = hex::decode(key).map_err(|e| format!("invalid key {}: {}", key, e))?;
+        if bytes.len() != KEY_SIZE {
+            return Err(format!("key {} is not {} bytes", key, KEY_SIZE));
+        }
+        allow_list.insert(bytes);
+    }
+    Ok(allow_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use ntest::timeout;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    fn config(identity: Option<&Arc<Ed25519KeyPair>>, allowed: &[&Arc<Ed25519KeyPair>]) -> TransportConfig {
+        TransportConfig {
+            identity: identity.cloned(),
+            allow_list: if allowed.is_empty() {
+                None
+            } else {
+                Some(allowed.iter().map(|k| k.public_key().as_ref().to_vec()).collect())
+            },
+        }
+    }
+
+    /// run the handshake of both sides over a local connection
+    fn connect(initiator: &TransportConfig, responder: &TransportConfig) -> (std::io::Result<Session>, std::io::Result<Session>) {
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let mut outgoing = Async::<TcpStream>::connect(addr).await.unwrap();
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            futures::join!(
+                handshake(&mut outgoing, initiator, *b"TEST", true),
+                handshake(&mut incoming, responder, *b"TEST", false)
+            )
+        })
+    }
+
+    #[test]
+    fn encrypted_records() {
+        let anonymous = config(None, &[]);
+        let (a, b) = connect(&anonymous, &anonymous);
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.remote_identity.is_none());
+        let record = a.sealer.seal(b"hello");
+        assert_ne!(&record[4..9], b"hello");
+        let mut len = [0; 4];
+        len.copy_from_slice(&record[0..4]);
+        assert_eq!(b.opener.open(len, record[4..].to_vec()).unwrap(), b"hello");
+        // a replayed record fails
+        assert!(b.opener.open(len, record[4..].to_vec()).is_err());
+        // so does an altered one
+        let mut record = a.sealer.seal(b"hello");
+        record[5] ^= 1;
+        assert!(b.opener.open(len, record[4..].to_vec()).is_err());
+        // each direction has its own key
+        let record = b.sealer.seal(b"back");
+        assert!(b.opener.open(len, record[4..].to_vec()).is_err());
+    }
+
+    #[test]
+    fn identities_and_allow_list() {
+        let alice = Arc::new(key_pair::random());
+        let bob = Arc::new(key_pair::random());
+        let eve = Arc::new(key_pair::random());
+        let (a, b) = connect(&config(Some(&alice), &[&bob]), &config(Some(&bob), &[&alice]));
+        assert_eq!(a.unwrap().remote_identity.unwrap(), bob.public_key().as_ref().to_vec());
+        assert_eq!(b.unwrap().remote_identity.unwrap(), alice.public_key().as_ref().to_vec());
+        // an identity out of the allow-list, or none, is refused
+        let (_, b) = connect(&config(Some(&eve), &[]), &config(Some(&bob), &[&alice]));
+        assert!(b.is_err());
+        let (_, b) = connect(&config(None, &[]), &config(Some(&bob), &[&alice]));
+        assert!(b.is_err());
+        // without an allow-list any identity is accepted
+        let (_, b) = connect(&config(Some(&eve), &[]), &config(None, &[]));
+        assert!(b.unwrap().remote_identity.is_some());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn large_handshake_record() {
+        let result = smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let mut outgoing = Async::<TcpStream>::connect(addr).await.unwrap();
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            // a hello, then the length of a whole frame, the record itself never comes
+            let mut hello = b"TEST".to_vec();
+            hello.extend_from_slice(&[1; KEY_SIZE]);
+            outgoing.write_all(&hello).await.unwrap();
+            outgoing.write_all(&(MAX_RECORD_SIZE as u32).to_be_bytes()).await.unwrap();
+            handshake(&mut incoming, &config(None, &[]), *b"TEST", false).await
+        });
+        assert!(result.err().unwrap().to_string().contains("too large"));
+    }
+
+    #[test]
+    fn allow_list_keys() {
+        let key = hex::encode(key_pair::random().public_key().as_ref());
+        assert_eq!(parse_allow_list(vec![key.as_str()]).unwrap().len(), 1);
+        assert!(parse_allow_list(vec!["abcd"]).is_err());
+        assert!(parse_allow_list(vec!["not hex"]).is_err());
+    }
+
+    #[test]
+    fn identity_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("identity_test_{}.key", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let key = load_identity(path).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(load_identity(path).unwrap().public_key().as_ref(), key.public_key().as_ref());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,20 +1,30 @@
-use super::message::Message;
+use super::address_book::{AddressBook, MAX_ADDR};
+use super::ban_list::{BAN_DURATION, BAN_SCORE};
+use super::compact_block::PartialBlock;
+use super::message::{Message, Version, MIN_PROTOCOL_VERSION};
 use super::peer;
+use super::rate_limit::RequestKind;
 use super::server::Handle as ServerHandle;
+use super::sync::{Sync, MAX_HEADERS};
 use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
-use crate::types::block_buffer::BlockBuffer;
+use crate::types::block_buffer::{self, BlockBuffer};
 use crate::Blockchain; 
 use std::os::linux::raw;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use log::{debug, warn, error};
-use crate::types::mempool::Mempool;
+use log::{debug, info, warn, error};
+use crate::types::mempool::{self, Mempool};
 use std::thread;
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use super::peer::TestReceiver as PeerTestReceiver;
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use super::server::TestReceiver as ServerTestReceiver;
+/// Maximum number of compact blocks waiting for their missing txs, the full blocks are requested beyond
+const MAX_PENDING_BLOCKS: usize = 16;
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
@@ -23,6 +33,10 @@ pub struct Worker {
     blockchain: Arc<Mutex<Blockchain>>,
     block_buffer: Arc<Mutex<BlockBuffer>>, 
     mempool: Arc<Mutex<Mempool>>,
+    address_book: Arc<Mutex<AddressBook>>,
+    sync: Arc<Mutex<Sync>>,
+    /// compact blocks waiting for the missing txs requested from the peer
+    pending_blocks: Arc<Mutex<HashMap<H256, (SocketAddr, PartialBlock)>>>,
 }
 
 
@@ -34,6 +48,7 @@ impl Worker {
         _blockchain: &Arc<Mutex<Blockchain>>,
         _block_buffer: &Arc<Mutex<BlockBuffer>>,
         _mempool: &Arc<Mutex<Mempool>>,
+        address_book: &Arc<Mutex<AddressBook>>,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -42,6 +57,9 @@ impl Worker {
             blockchain: Arc::clone(_blockchain),
             block_buffer: Arc::clone(_block_buffer),  
             mempool: Arc::clone(_mempool),
+            address_book: Arc::clone(address_book),
+            sync: Arc::new(Mutex::new(Sync::new())),
+            pending_blocks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -57,15 +75,62 @@ impl Worker {
     }
 
     fn worker_loop(&self) {
+        // messages kept until the handshake of their peer, handled by the worker completing it
+        let mut early_messages = VecDeque::new();
         loop {
-            let result = smol::block_on(self.msg_chan.recv());
-            if let Err(e) = result {
-                error!("network worker terminated {}", e);
-                break;
+            let (msg, mut peer) = match early_messages.pop_front() {
+                Some(early) => early,
+                None => {
+                    let result = smol::block_on(self.msg_chan.recv());
+                    if let Err(e) = result {
+                        error!("network worker terminated {}", e);
+                        break;
+                    }
+                    let (msg, peer) = result.unwrap();
+                    match Message::decode(&msg) {
+                        Ok(msg) => (msg, peer),
+                        Err(e) => {
+                            self.misbehaving(&peer, 100, &format!("malformed message: {}", e));
+                            continue;
+                        }
+                    }
+                }
+            };
+            match msg {
+                Message::Version(version) => {
+                    debug!("Version: {:?}", version);
+                    self.handle_version(&mut peer, version);
+                    early_messages.extend(peer.take_early_messages().into_iter().map(|m| (m, peer.clone())));
+                    continue;
+                }
+                Message::Verack => {
+                    debug!("Verack from {}", peer.addr());
+                    if peer.set_verack_received() {
+                        self.handshake_done(&mut peer);
+                    }
+                    early_messages.extend(peer.take_early_messages().into_iter().map(|m| (m, peer.clone())));
+                    continue;
+                }
+                _ => {}
+            }
+            // nothing but the handshake is handled before the handshake is done, a few messages wait for it
+            let msg = match peer.defer_until_handshake(msg) {
+                peer::EarlyMessage::Ready(msg) => msg,
+                peer::EarlyMessage::Kept => continue,
+                peer::EarlyMessage::Dropped => {
+                    self.misbehaving(&peer, 10, "too many messages before the handshake");
+                    peer.disconnect();
+                    continue;
+                }
+            };
+            // drop the requests over the rate limits of the peer
+            if let Some((kind, cost)) = RequestKind::of(&msg) {
+                if !peer.allow_request(kind, cost) {
+                    debug!("Drop {:?} of {} items from {}, rate limited", kind, cost, peer.addr());
+                    self.server.record_dropped(kind);
+                    continue;
+                }
             }
-            let msg = result.unwrap();
-            let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -73,6 +138,9 @@ impl Worker {
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    if let Some(latency) = peer.pong_received(&nonce) {
+                        debug!("Peer {} latency {} ms", peer.addr(), latency.as_millis());
+                    }
                 }
                 // receive other nodes' new block hashes. 
                 // if I don't have the block, send a request to get the block
@@ -95,55 +163,20 @@ impl Worker {
                     }
                     debug!("GetBlocks: {:?}", hashes);
                     let mut blocks = Vec::new();
+                    let blockchain = self.blockchain.lock().unwrap();
                     for hash in hashes {
-                        if let Some(block) = self.blockchain.lock().unwrap().blocks.get(&hash) {
+                        if let Some(block) = blockchain.blocks.get(&hash) {
                             blocks.push(block.block.clone());
                         }
                     }
+                    drop(blockchain);
                     peer.write(Message::Blocks(blocks));
                 }
                 Message::Blocks(input_blocks) => {
                     if input_blocks.is_empty(){
                         continue;
                     }
-                    debug!("Blocks: {:?}", input_blocks);
-                    // filter the input blocks, remove the under_mined blocks
-                    // get current difficulty
-                    let dif = self.blockchain.lock().unwrap().get_difficulty();
-                    let mut input_blocks = input_blocks;
-                    input_blocks.retain(|block| block.header.difficulty >= dif);
-                    
-                    //remove duplicated blocks which we already have
-                    let mut blocks_I_dont_have = Vec::new();
-                    let mut orphan_blocks = Vec::new(); 
-                    let mut new_block_hashes = Vec::new(); 
-                    for block in input_blocks {
-                        if !self.blockchain.lock().unwrap().blocks.contains_key(&block.hash()) {
-                            new_block_hashes.push(block.hash());
-                            blocks_I_dont_have.push(block);
-                        }
-                    }
-                    //broadcast the newcomming block hashes  
-                    if !new_block_hashes.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
-                    }
-                    //  for each new block, send it to the block buffer, this buffer will handle the process of pushing the block to the blockchain
-                    for block in blocks_I_dont_have {
-                        // In this working thread, only have 1 working loop and 1 buffer, so buffer can borrow the blockchain, no need for clone  
-                        let parent_hash = block.header.parent; 
-                        let have_parents = self.block_buffer.lock().unwrap().send_block(block, &self.blockchain, &self.mempool);
-
-                        // if the newcoming block is an orphan block, send a request to get the parent block
-                        if !have_parents {
-                            orphan_blocks.push(parent_hash);
-                        }
-
-                    }
-                    // request the parent block of the orphan blocks 
-                    if !orphan_blocks.is_empty() {
-                        peer.write(Message::GetBlocks(orphan_blocks));
-                    }
-                    // boardcast new incoming blocks that I not have 
+                    self.handle_blocks(&mut peer, input_blocks);
                 }
                 Message::NewTransactionHashes(hashes) => {
                     if hashes.is_empty(){
@@ -186,22 +219,291 @@ impl Worker {
                     debug!("Transactions: {:?}", input_txs);
                     for tx in input_txs {
                         let res = self.mempool.lock().unwrap().add_tx(&tx);
-                        if res.is_err() {
-                            warn!("Received an invalid Tx {:?}", tx.get_tx_hash());
+                        match res {
+                            // a conflicting or unknown input may be an honest race
+                            Err(e) if e == mempool::INVALID_TX => {
+                                self.misbehaving(&peer, 10, &format!("invalid tx {:?}", tx.get_tx_hash()));
+                            }
+                            Err(e) => debug!("Rejected tx {:?}: {}", tx.get_tx_hash(), e),
+                            Ok(_) => {}
                         }
                     }
                 }
-                _ => unimplemented!(),
+                Message::GetAddr => {
+                    debug!("GetAddr");
+                    let addrs = self.address_book.lock().unwrap().sample(MAX_ADDR);
+                    peer.write(Message::Addr(addrs));
+                }
+                Message::Addr(addrs) => {
+                    debug!("Addr: {:?}", addrs);
+                    let local = self.server.local_addr();
+                    let mut address_book = self.address_book.lock().unwrap();
+                    for addr in addrs.into_iter().filter(|a| *a != local) {
+                        address_book.add(addr);
+                    }
+                }
+                Message::GetHeaders(locator) => {
+                    debug!("GetHeaders: {:?}", locator);
+                    let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    if headers.is_empty() {
+                        continue;
+                    }
+                    debug!("Headers: {} headers from {}", headers.len(), peer.addr());
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let result = self.sync.lock().unwrap().add_headers(peer.addr(), &headers, &blockchain);
+                    match result {
+                        Ok(added) => {
+                            info!("Received {} new headers from {}", added, peer.addr());
+                            // a full message means the peer has more headers
+                            if headers.len() == MAX_HEADERS {
+                                let mut locator = vec![headers.last().unwrap().hash()];
+                                locator.extend(blockchain.block_locator());
+                                peer.write(Message::GetHeaders(locator));
+                            }
+                        }
+                        Err(e) => {
+                            self.misbehaving(&peer, 100, &format!("invalid headers: {}", e));
+                            continue;
+                        }
+                    }
+                    drop(blockchain);
+                    self.request_blocks();
+                }
+                Message::CompactBlock(compact) => {
+                    let hash = compact.header.hash();
+                    debug!("CompactBlock {} from {}", hash, peer.addr());
+                    {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        if blockchain.blocks.contains_key(&hash) {
+                            continue;
+                        }
+                        // no tx is requested for a block without PoW
+                        if let Err(e) = block_buffer::check_header(&compact.header, &blockchain.spec) {
+                            drop(blockchain);
+                            self.misbehaving(&peer, 100, &format!("compact block {}: {}", hash, e));
+                            continue;
+                        }
+                    }
+                    let partial = compact.reconstruct(&self.mempool.lock().unwrap().txs);
+                    let partial = match partial {
+                        Ok(partial) => partial,
+                        Err(e) => {
+                            self.misbehaving(&peer, 100, &format!("invalid compact block {}: {}", hash, e));
+                            continue;
+                        }
+                    };
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        self.complete_compact_block(&mut peer, partial);
+                        continue;
+                    }
+                    let mut pending = self.pending_blocks.lock().unwrap();
+                    if pending.len() >= MAX_PENDING_BLOCKS && !pending.contains_key(&hash) {
+                        drop(pending);
+                        peer.write(Message::GetBlocks(vec![hash]));
+                        continue;
+                    }
+                    debug!("Request {} missing txs of compact block {}", missing.len(), hash);
+                    pending.insert(hash, (*peer.addr(), partial));
+                    drop(pending);
+                    peer.write(Message::GetBlockTxs(hash, missing));
+                }
+                Message::GetBlockTxs(hash, indexes) => {
+                    debug!("GetBlockTxs: {} txs of {}", indexes.len(), hash);
+                    let txs: Option<Vec<_>> = match self.blockchain.lock().unwrap().blocks.get(&hash) {
+                        Some(b) => indexes.iter().map(|i| b.block.body.txs.get(*i as usize).cloned()).collect(),
+                        None => continue,
+                    };
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTxs(hash, txs)),
+                        None => self.misbehaving(&peer, 10, &format!("tx index out of block {}", hash)),
+                    }
+                }
+                Message::BlockTxs(hash, txs) => {
+                    debug!("BlockTxs: {} txs of {}", txs.len(), hash);
+                    let partial = {
+                        let mut pending = self.pending_blocks.lock().unwrap();
+                        match pending.get(&hash) {
+                            Some((from, _)) if from == peer.addr() => pending.remove(&hash).map(|(_, p)| p),
+                            _ => None,
+                        }
+                    };
+                    let mut partial = match partial {
+                        Some(partial) => partial,
+                        None => {
+                            debug!("Unrequested txs of block {} from {}", hash, peer.addr());
+                            continue;
+                        }
+                    };
+                    if let Err(e) = partial.fill(txs) {
+                        self.misbehaving(&peer, 100, &format!("invalid txs of block {}: {}", hash, e));
+                        continue;
+                    }
+                    self.complete_compact_block(&mut peer, partial);
+                }
+                Message::Version(_) | Message::Verack => unreachable!(),
+            }
+        }
+    }
+
+    /// Validate the received blocks and put them in the block buffer, the new ones joining the blockchain are relayed
+    fn handle_blocks(&self, peer: &mut peer::Handle, input_blocks: Vec<Block>) {
+        debug!("Blocks: {:?}", input_blocks);
+        let hashes: Vec<H256> = input_blocks.iter().map(|b| b.hash()).collect();
+        self.sync.lock().unwrap().blocks_received(&hashes);
+        //remove duplicated blocks which we already have
+        let mut blocks_to_add = Vec::new();
+        let mut orphan_blocks = Vec::new(); 
+        for block in input_blocks {
+            if !self.blockchain.lock().unwrap().blocks.contains_key(&block.hash()) {
+                // the blocks downloaded by the sync are old, not worth relaying
+                let relay = !self.sync.lock().unwrap().is_known(&block.hash());
+                blocks_to_add.push((block, relay));
+            }
+        }
+        //  for each new block, send it to the block buffer, this buffer will handle the process of pushing the block to the blockchain
+        for (block, relay) in blocks_to_add {
+            // In this working thread, only have 1 working loop and 1 buffer, so buffer can borrow the blockchain, no need for clone  
+            let parent_hash = block.header.parent; 
+            // a block without PoW is forged, but a block breaking the rules on its parent may come from an
+            // honest node following other rules
+            let checked = block_buffer::check_block(&block, &self.blockchain.lock().unwrap().spec);
+            if let Err(e) = checked {
+                self.sync.lock().unwrap().reject(&block.hash());
+                self.misbehaving(peer, 100, &format!("invalid block {}: {}", block.hash(), e));
+                continue;
+            }
+            // relayed once valid and in the blockchain, the peers score the sender of an invalid block
+            let (hash, relayed) = (block.hash(), if relay { Some(block.clone()) } else { None });
+            let have_parents = match self.block_buffer.lock().unwrap().send_block(block, &self.blockchain, &self.mempool) {
+                Ok(have_parents) => have_parents,
+                Err(e) => {
+                    self.sync.lock().unwrap().reject(&hash);
+                    self.misbehaving(peer, 10, &format!("invalid block {}: {}", hash, e));
+                    continue;
+                }
+            };
+            if have_parents {
+                if let Some(block) = relayed {
+                    self.server.broadcast_block(&block);
+                }
+            }
+
+            // if the newcoming block is an orphan block, send a request to get the parent block,
+            // unless the sync downloads it
+            if !have_parents && !self.sync.lock().unwrap().is_known(&parent_hash) {
+                orphan_blocks.push(parent_hash);
+            }
+
+        }
+        // request the parent block of the orphan blocks 
+        if !orphan_blocks.is_empty() {
+            peer.write(Message::GetBlocks(orphan_blocks));
+        }
+        self.request_blocks();
+    }
+
+    /// Handle a rebuilt compact block like a received block. The txs not matching the merkle root
+    /// were picked by a short id collision, the full block is requested instead.
+    fn complete_compact_block(&self, peer: &mut peer::Handle, partial: PartialBlock) {
+        let hash = partial.header.hash();
+        match partial.into_block() {
+            Ok(block) => self.handle_blocks(peer, vec![block]),
+            Err(e) => {
+                debug!("Compact block {} not rebuilt: {}, request the full block", hash, e);
+                peer.write(Message::GetBlocks(vec![hash]));
+            }
+        }
+    }
+
+    /// Add to the misbehavior score of the peer, a peer reaching `BAN_SCORE` is banned, unless its ip is a
+    /// loopback one, and disconnected
+    fn misbehaving(&self, peer: &peer::Handle, points: u32, reason: &str) {
+        let score = peer.misbehave(points);
+        warn!("Peer {} misbehaving, score {} (+{}): {}", peer.addr(), score, points, reason);
+        if score >= BAN_SCORE {
+            // the nodes of a machine share the loopback ip, they are only disconnected
+            if peer.addr().ip().is_loopback() {
+                warn!("Disconnect peer {}, a loopback ip is not banned", peer.addr());
+            } else {
+                warn!("Ban peer {} for {} seconds", peer.addr().ip(), BAN_DURATION.as_secs());
+                self.server.ban(peer.addr().ip(), BAN_DURATION);
             }
+            peer.disconnect();
+        }
+    }
+
+    /// Request the blocks of the validated headers from the peers having them
+    fn request_blocks(&self) {
+        let assigned = {
+            let blockchain = self.blockchain.lock().unwrap();
+            self.sync.lock().unwrap().schedule(&blockchain)
+        };
+        for (mut peer, hashes) in assigned {
+            debug!("Request {} blocks from {}", hashes.len(), peer.addr());
+            peer.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Check the version of a peer, the peers of another network or too old are disconnected.
+    /// An incoming peer gets our version before the verack.
+    fn handle_version(&self, peer: &mut peer::Handle, version: Version) {
+        if peer.version().is_some() {
+            self.misbehaving(peer, 1, "duplicated version");
+            peer.disconnect();
+            return;
+        }
+        let local = Version::local(&self.blockchain.lock().unwrap(), self.server.local_addr().port());
+        if version.genesis != local.genesis || version.network != local.network {
+            warn!(
+                "Peer {} is on network {} with genesis {}, disconnecting",
+                peer.addr(), version.network, version.genesis
+            );
+            peer.disconnect();
+            return;
+        }
+        if version.protocol_version < MIN_PROTOCOL_VERSION {
+            warn!("Peer {} has obsolete protocol version {}, disconnecting", peer.addr(), version.protocol_version);
+            peer.disconnect();
+            return;
+        }
+        info!("Peer {} {} at height {}", peer.addr(), version.user_agent, version.best_height);
+        if peer.direction() == peer::Direction::Incoming {
+            peer.write(Message::Version(local));
+        }
+        peer.write(Message::Verack);
+        // the verack of an outgoing peer may be handled before its version
+        if peer.set_version(version) {
+            self.handshake_done(peer);
+        }
+    }
+
+    /// Called once per peer when the handshake is done
+    fn handshake_done(&self, peer: &mut peer::Handle) {
+        info!("Handshake with peer {} done", peer.addr());
+        self.address_book.lock().unwrap().mark_seen(peer.listen_addr().unwrap());
+        // learn more peers from the peers we choose
+        if peer.direction() == peer::Direction::Outgoing {
+            peer.write(Message::GetAddr);
+        }
+        // download the blocks we miss, headers first
+        let best_height = peer.version().unwrap().best_height;
+        self.sync.lock().unwrap().add_peer(peer, best_height);
+        let blockchain = self.blockchain.lock().unwrap();
+        if best_height > blockchain.height {
+            peer.write(Message::GetHeaders(blockchain.block_locator()));
         }
     }
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 impl TestMsgSender {
     fn new() -> (TestMsgSender, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
         let (s,r) = smol::channel::unbounded();
@@ -214,8 +516,18 @@ impl TestMsgSender {
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
+
+    /// send a message from a given peer, e.g. a peer which has not done the handshake
+    fn send_from(&self, msg: Message, handle: &peer::Handle) {
+        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_raw(bytes, handle);
+    }
+
+    fn send_raw(&self, bytes: Vec<u8>, handle: &peer::Handle) {
+        smol::block_on(self.s.send((bytes, handle.clone()))).unwrap();
+    }
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
     let (server, server_receiver) = ServerHandle::new_for_test();
@@ -223,7 +535,10 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let blockchain = Arc::new(Mutex::new(Blockchain::new()));
     let block_buffer = Arc::new(Mutex::new(BlockBuffer::new()));
     let mempool = Arc::new(Mutex::new(Mempool::new()));
-    let worker = Worker::new(1, msg_chan, &server, &blockchain, &block_buffer, &mempool);
+    let genesis = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
+    mempool.lock().unwrap().add_genesis(&genesis);
+    let address_book = Arc::new(Mutex::new(AddressBook::new()));
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &block_buffer, &mempool, &address_book);
     let init_hash = blockchain.lock().unwrap().tip();
     worker.start(); 
     (test_msg_sender, server_receiver, vec![init_hash])
@@ -237,8 +552,32 @@ mod test {
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
 
-    use super::super::message::Message;
+    use super::super::message::{Message, Version};
+    use super::super::peer;
     use super::generate_test_worker_and_start;
+    use super::super::ban_list::BAN_SCORE;
+    use std::net::SocketAddr;
+    use super::super::rate_limit::RequestKind;
+    use super::super::compact_block::CompactBlock;
+    use crate::blockchain::Blockchain;
+    use crate::types::block::Block;
+    use crate::types::hash::H256;
+    use crate::types::ico::{self, IcoGenerator};
+    use crate::types::transaction::{self, Input, Output, SignedTransaction, Transaction, Witness};
+    use ring::signature::KeyPair;
+
+    /// A valid tx of the dev network, alice spending her genesis output without fee
+    fn genesis_spend() -> SignedTransaction {
+        let genesis = Block::genesis();
+        let ico_tx = &genesis.body.txs[0];
+        let alice = &IcoGenerator::load_key(ico::CONFIG_PATH)[0];
+        let transaction = Transaction {
+            inputs: vec![Input::new(&ico_tx.get_tx_hash(), 0)],
+            outputs: vec![Output { pk_hash: H256::rand(), value: ico_tx.transaction.outputs[0].value }],
+        };
+        let sig = transaction::sign(&transaction, alice).as_ref().to_vec();
+        SignedTransaction { transaction, fee: 0, witnesses: vec![Witness { pubkey: alice.public_key().as_ref().to_vec(), sig }] }
+    }
 
     #[test]
     #[timeout(60000)]
@@ -272,7 +611,12 @@ mod test {
     #[timeout(60000)]
     fn reply_blocks() {
         let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
-        let random_block = generate_random_block(v.last().unwrap());
+        // only valid blocks are relayed, the merkle root must commit to the empty body
+        let mut random_block = Block::new_block_from_txs(v.last().unwrap(), &vec![]);
+        let difficulty = Blockchain::new().get_difficulty();
+        while random_block.hash() > difficulty {
+            random_block.header.nonce += 1;
+        }
         let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![random_block.clone()]));
         let reply = server_receiver.recv().unwrap();
         if let Message::NewBlockHashes(v) = reply {
@@ -281,6 +625,269 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn handshake() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle_without_handshake();
+        let version = Version::local(&Blockchain::new(), 6001);
+        test_msg_sender.send_from(Message::Version(version.clone()), &handle);
+        // an incoming peer gets our version, then the verack
+        if let Message::Version(reply) = peer_receiver.recv() {
+            assert_eq!(reply.genesis, version.genesis);
+        } else {
+            panic!();
+        }
+        assert!(matches!(peer_receiver.recv(), Message::Verack));
+        assert!(!handle.handshake_done());
+        test_msg_sender.send_from(Message::Verack, &handle);
+        test_msg_sender.send_from(Message::GetBlocks(vec![v[0]]), &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Blocks(_)));
+        assert!(handle.handshake_done());
+        // the listening address of the peer is shared
+        test_msg_sender.send_from(Message::GetAddr, &handle);
+        if let Message::Addr(addrs) = peer_receiver.recv() {
+            assert_eq!(addrs, vec![handle.listen_addr().unwrap()]);
+            assert_eq!(addrs[0].port(), 6001);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_addr() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let addrs: Vec<std::net::SocketAddr> = vec!["10.0.0.1:6000".parse().unwrap(), "10.0.0.2:6000".parse().unwrap()];
+        test_msg_sender.send(Message::Addr(addrs.clone()));
+        let mut peer_receiver = test_msg_sender.send(Message::GetAddr);
+        if let Message::Addr(mut reply) = peer_receiver.recv() {
+            reply.sort();
+            assert_eq!(reply, addrs);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn headers_first_sync() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let blockchain = Blockchain::new();
+        // a block of the dev network on top of genesis
+        let mut block = Block::new_block_from_txs(&v[0], &vec![]);
+        while block.hash() > blockchain.get_difficulty() {
+            block.header.nonce += 1;
+        }
+        let (handle, mut peer_receiver) = peer::Handle::test_handle_without_handshake();
+        let mut version = Version::local(&blockchain, 6001);
+        version.best_height = 1;
+        test_msg_sender.send_from(Message::Version(version), &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Version(_)));
+        assert!(matches!(peer_receiver.recv(), Message::Verack));
+        // the peer is higher, ask for its headers
+        test_msg_sender.send_from(Message::Verack, &handle);
+        if let Message::GetHeaders(locator) = peer_receiver.recv() {
+            assert_eq!(locator, vec![v[0]]);
+        } else {
+            panic!();
+        }
+        // then the bodies of the validated headers
+        test_msg_sender.send_from(Message::Headers(vec![block.header.clone()]), &handle);
+        if let Message::GetBlocks(hashes) = peer_receiver.recv() {
+            assert_eq!(hashes, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        test_msg_sender.send_from(Message::Blocks(vec![block.clone()]), &handle);
+        test_msg_sender.send_from(Message::GetHeaders(vec![v[0]]), &handle);
+        if let Message::Headers(headers) = peer_receiver.recv() {
+            assert_eq!(headers.len(), 1);
+            assert_eq!(headers[0].hash(), block.hash());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn invalid_headers() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        // not enough PoW for the dev network
+        let mut block = generate_random_block(&v[0]);
+        while block.hash() <= Blockchain::new().get_difficulty() {
+            block.header.nonce += 1;
+        }
+        test_msg_sender.send_from(Message::Headers(vec![block.header]), &handle);
+        assert!(peer_receiver.closed());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn malformed_message_bans() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        let handle = handle.with_addr(SocketAddr::from(([10, 0, 0, 1], 6000)));
+        test_msg_sender.send_raw(vec![255; 7], &handle);
+        assert!(peer_receiver.closed());
+        assert!(handle.score() >= BAN_SCORE);
+        assert!(server_receiver.is_banned(&handle.addr().ip()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn loopback_not_banned() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        assert!(handle.addr().ip().is_loopback());
+        test_msg_sender.send_raw(vec![255; 7], &handle);
+        assert!(peer_receiver.closed());
+        assert!(handle.score() >= BAN_SCORE);
+        assert!(!server_receiver.is_banned(&handle.addr().ip()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn invalid_block_bans() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        let handle = handle.with_addr(SocketAddr::from(([10, 0, 0, 1], 6000)));
+        let mut block = generate_random_block(&v[0]);
+        while block.hash() <= Blockchain::new().get_difficulty() {
+            block.header.nonce += 1;
+        }
+        test_msg_sender.send_from(Message::Blocks(vec![block]), &handle);
+        assert!(peer_receiver.closed());
+        assert!(server_receiver.is_banned(&handle.addr().ip()));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn invalid_block_not_relayed() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let (bad_peer, _bad_receiver) = peer::Handle::test_handle();
+        let (good_peer, _good_receiver) = peer::Handle::test_handle();
+        let difficulty = Blockchain::new().get_difficulty();
+        // enough PoW, but the coinbase claims more than the subsidy
+        let coinbase = SignedTransaction::coinbase(1, H256::rand(), u64::MAX);
+        let mut invalid = Block::new_block_from_txs(&v[0], &vec![coinbase]);
+        while invalid.hash() > difficulty {
+            invalid.header.nonce += 1;
+        }
+        let mut valid = Block::new_block_from_txs(&v[0], &vec![]);
+        while valid.hash() > difficulty {
+            valid.header.nonce += 1;
+        }
+        test_msg_sender.send_from(Message::Blocks(vec![invalid]), &bad_peer);
+        test_msg_sender.send_from(Message::Blocks(vec![valid.clone()]), &good_peer);
+        // the first block announced is the valid one, the ban of the bad peer is no broadcast
+        let announced = loop {
+            if let Some(msg) = server_receiver.recv() {
+                break msg;
+            }
+        };
+        if let Message::NewBlockHashes(hashes) = announced {
+            assert_eq!(hashes, vec![valid.hash()]);
+        } else {
+            panic!();
+        }
+        // a block breaking the rules on its parent is not enough to ban its sender
+        assert_eq!(bad_peer.score(), 10);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn rate_limited_requests() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        // the burst of GetAddr is 2
+        for _ in 0..3 {
+            test_msg_sender.send_from(Message::GetAddr, &handle);
+        }
+        test_msg_sender.send_from(Message::Ping("after".to_string()), &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Addr(_)));
+        assert!(matches!(peer_receiver.recv(), Message::Addr(_)));
+        assert!(matches!(peer_receiver.recv(), Message::Pong(_)));
+        assert_eq!(handle.dropped_requests(), 1);
+        assert_eq!(server_receiver.dropped_requests().get(&RequestKind::GetAddr), Some(&1));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn handshake_genesis_mismatch() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle_without_handshake();
+        let mut version = Version::local(&Blockchain::new(), 6001);
+        version.genesis = H256::rand();
+        test_msg_sender.send_from(Message::Version(version), &handle);
+        assert!(peer_receiver.closed());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn message_before_handshake() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        // a message before the handshake is handled once it is done
+        let (handle, mut peer_receiver) = peer::Handle::test_handle_without_handshake();
+        test_msg_sender.send_from(Message::GetBlocks(vec![v[0]]), &handle);
+        test_msg_sender.send_from(Message::Version(Version::local(&Blockchain::new(), 6001)), &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Version(_)));
+        assert!(matches!(peer_receiver.recv(), Message::Verack));
+        test_msg_sender.send_from(Message::Verack, &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Blocks(_)));
+        // too many of them
+        let (handle, mut peer_receiver) = peer::Handle::test_handle_without_handshake();
+        for _ in 0..=peer::MAX_EARLY_MESSAGES {
+            test_msg_sender.send_from(Message::GetBlocks(vec![v[0]]), &handle);
+        }
+        assert!(peer_receiver.closed());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_missing_txs() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        let tx = genesis_spend();
+        let coinbase = SignedTransaction::coinbase(1, H256::rand(), 0);
+        let mut block = Block::new_block_from_txs(&v[0], &vec![coinbase, tx.clone()]);
+        let difficulty = Blockchain::new().get_difficulty();
+        while block.hash() > difficulty {
+            block.header.nonce += 1;
+        }
+        // the tx is not in the mempool, the coinbase is prefilled
+        test_msg_sender.send_from(Message::CompactBlock(CompactBlock::new(&block)), &handle);
+        if let Message::GetBlockTxs(hash, indexes) = peer_receiver.recv() {
+            assert_eq!(hash, block.hash());
+            assert_eq!(indexes, vec![1]);
+        } else {
+            panic!();
+        }
+        // the rebuilt block is relayed
+        test_msg_sender.send_from(Message::BlockTxs(block.hash(), vec![tx.clone()]), &handle);
+        if let Some(Message::NewBlockHashes(hashes)) = server_receiver.recv() {
+            assert_eq!(hashes, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        // and its txs are served
+        test_msg_sender.send_from(Message::GetBlockTxs(block.hash(), vec![1]), &handle);
+        if let Message::BlockTxs(hash, txs) = peer_receiver.recv() {
+            assert_eq!(hash, block.hash());
+            assert_eq!(txs[0].get_wtxid(), tx.get_wtxid());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn pong_latency() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let (handle, mut peer_receiver) = peer::Handle::test_handle();
+        let nonce = handle.start_ping(std::time::Duration::from_secs(30)).unwrap();
+        // a ping is pending until its pong
+        assert!(handle.start_ping(std::time::Duration::from_secs(0)).is_none());
+        test_msg_sender.send_from(Message::Pong("not the nonce".to_string()), &handle);
+        test_msg_sender.send_from(Message::Pong(nonce), &handle);
+        // the messages of the peer are handled in order by the single worker
+        test_msg_sender.send_from(Message::Ping("sync".to_string()), &handle);
+        assert!(matches!(peer_receiver.recv(), Message::Pong(_)));
+        assert!(handle.latency().is_some());
+        assert!(handle.ping_wait().is_none());
+        // the next ping waits for the interval
+        assert!(handle.start_ping(std::time::Duration::from_secs(30)).is_none());
+        assert!(handle.info().latency_ms.is_some());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,371 @@
+//! Mining attacks on a simulated network. The miners find the blocks in turn, drawn by their hash
+//! power, the honest ones publish them at once while the adversaries withhold them following their
+//! strategy. The report tells how often blocks are orphaned and how deep the honest nodes reorganize
+//! compared to `blockchain::K`, the depth at which a block is considered final.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{reorg_depth, Node};
+use crate::miner::clock::MockClock;
+use crate::types::block::Block;
+use crate::types::chain_spec::ChainSpec;
+use crate::types::hash::{Hashable, H256};
+
+/// Time between two blocks of the simulation
+const BLOCK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// mine on the tip and publish the blocks at once
+    Honest,
+    /// selfish mining of Eyal and Sirer: keep the blocks private while ahead of the public chain,
+    /// publish just enough of them to orphan each honest block, race on a tie
+    Selfish,
+    /// keep the blocks private until the public chain has this many blocks after the fork and the
+    /// private branch is longer, then publish it at once to revert them; give up when the public chain
+    /// gets longer. With `K + 1` blocks a success reverts a final block, like a double spend would.
+    Withhold(u32),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Miner {
+    /// share of the hash power, the shares of all miners need not sum to 1
+    pub hash_power: f64,
+    pub strategy: Strategy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub spec: ChainSpec,
+    pub miners: Vec<Miner>,
+    /// number of blocks to mine
+    pub blocks: u32,
+    /// share of the honest nodes receiving the block published by an adversary before the honest block
+    /// of the same height, the honest miners mine on the first block they receive
+    pub gamma: f64,
+    pub seed: u64,
+}
+
+impl Config {
+    /// A simulation of `blocks` blocks on the simulated chain, with the given miners
+    pub fn new(miners: Vec<Miner>, blocks: u32, seed: u64) -> Config {
+        Config { spec: ChainSpec::simulated(), miners, blocks, gamma: 0.0, seed }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MinerReport {
+    pub hash_power: f64,
+    pub strategy: Strategy,
+    pub mined: u32,
+    /// blocks of the final longest chain
+    pub in_chain: u32,
+    /// share of the blocks of the final longest chain, to compare with the hash power
+    pub revenue_share: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    /// confirmation depth of the chain spec
+    pub k: u32,
+    pub blocks_mined: u32,
+    /// height of the longest chain of the first honest node at the end
+    pub chain_length: u32,
+    /// share of the mined blocks which are not in the final longest chain
+    pub orphan_rate: f64,
+    /// number of reorganizations of the honest nodes by depth, every node counting its own
+    pub reorgs: BTreeMap<u32, u32>,
+    pub max_reorg_depth: u32,
+    /// reorganizations deeper than K, reverting a block considered final
+    pub deep_reorgs: u32,
+    pub miners: Vec<MinerReport>,
+}
+
+/// Blocks an adversary keeps private
+#[derive(Default)]
+struct Withheld {
+    blocks: Vec<Block>,
+    /// its published branch ties with another published branch, see `Strategy::Selfish`
+    racing: bool,
+}
+
+struct Simulation {
+    nodes: Vec<Node>,
+    strategies: Vec<Strategy>,
+    withheld: Vec<Withheld>,
+    /// height of the highest published block
+    public_height: u32,
+    reorgs: BTreeMap<u32, u32>,
+    gamma: f64,
+    rng: StdRng,
+}
+
+/// Run the simulation, the same config always gives the same report
+pub fn run(config: &Config) -> Report {
+    assert!(
+        config.miners.iter().any(|m| m.strategy == Strategy::Honest),
+        "a simulation needs an honest miner"
+    );
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(config.spec.genesis_timestamp)));
+    let nodes: Vec<Node> = (0..config.miners.len())
+        .map(|i| Node::new(&config.spec, H256([i as u8 + 1; 32]), clock.clone()))
+        .collect();
+    let mut sim = Simulation {
+        nodes,
+        strategies: config.miners.iter().map(|m| m.strategy).collect(),
+        withheld: config.miners.iter().map(|_| Withheld::default()).collect(),
+        public_height: 0,
+        reorgs: BTreeMap::new(),
+        gamma: config.gamma,
+        rng: StdRng::seed_from_u64(config.seed),
+    };
+    let total_power: f64 = config.miners.iter().map(|m| m.hash_power).sum();
+    let mut miner_of = HashMap::new();
+    for _ in 0..config.blocks {
+        // the next block is found by a miner drawn by hash power
+        let mut draw = sim.rng.gen::<f64>() * total_power;
+        let winner = config
+            .miners
+            .iter()
+            .position(|m| {
+                draw -= m.hash_power;
+                draw < 0.0
+            })
+            .unwrap_or(config.miners.len() - 1);
+        clock.advance(BLOCK_INTERVAL);
+        let block = sim.nodes[winner].mine();
+        miner_of.insert(block.hash(), winner);
+        sim.block_found(winner, block);
+    }
+
+    let honest = sim.strategies.iter().position(|s| *s == Strategy::Honest).unwrap();
+    let chain = sim.nodes[honest].blockchain.lock().unwrap().all_blocks_in_longest_chain();
+    let chain_length = chain.len() as u32 - 1;
+    let mut in_chain = vec![0; config.miners.len()];
+    for hash in &chain[1..] {
+        in_chain[miner_of[hash]] += 1;
+    }
+    let mut mined = vec![0; config.miners.len()];
+    for miner in miner_of.values() {
+        mined[*miner] += 1;
+    }
+    let max_reorg_depth = sim.reorgs.keys().next_back().copied().unwrap_or(0);
+    let deep_reorgs = sim.reorgs.range(config.spec.k + 1..).map(|(_, count)| count).sum();
+    Report {
+        k: config.spec.k,
+        blocks_mined: config.blocks,
+        chain_length,
+        orphan_rate: if config.blocks == 0 { 0.0 } else { 1.0 - chain_length as f64 / config.blocks as f64 },
+        reorgs: sim.reorgs,
+        max_reorg_depth,
+        deep_reorgs,
+        miners: config
+            .miners
+            .iter()
+            .enumerate()
+            .map(|(i, m)| MinerReport {
+                hash_power: m.hash_power,
+                strategy: m.strategy,
+                mined: mined[i],
+                in_chain: in_chain[i],
+                revenue_share: if chain_length == 0 { 0.0 } else { in_chain[i] as f64 / chain_length as f64 },
+            })
+            .collect(),
+    }
+}
+
+impl Simulation {
+    /// The miner found a block, it is already in the blockchain of its node
+    fn block_found(&mut self, miner: usize, block: Block) {
+        let height = self.height_of(miner, &block);
+        match self.strategies[miner] {
+            Strategy::Honest => self.publish_honest(miner, block, height),
+            Strategy::Selfish => {
+                let withheld = &mut self.withheld[miner];
+                withheld.blocks.push(block);
+                // winning the race
+                if withheld.racing {
+                    withheld.racing = false;
+                    let blocks = std::mem::take(&mut withheld.blocks);
+                    self.publish_adversary(miner, blocks);
+                }
+            }
+            Strategy::Withhold(depth) => {
+                let withheld = &mut self.withheld[miner];
+                withheld.blocks.push(block);
+                let fork_height = height - withheld.blocks.len() as u32;
+                if self.public_height >= fork_height + depth && height > self.public_height {
+                    let blocks = std::mem::take(&mut withheld.blocks);
+                    self.publish_adversary(miner, blocks);
+                }
+            }
+        }
+    }
+
+    /// The adversaries hear of an honest block first and may answer with their withheld blocks, which
+    /// reach a share `gamma` of the other honest nodes before the honest block
+    fn publish_honest(&mut self, miner: usize, block: Block, height: u32) {
+        self.public_height = self.public_height.max(height);
+        let mut released = vec![];
+        for adversary in 0..self.nodes.len() {
+            if self.strategies[adversary] == Strategy::Honest {
+                continue;
+            }
+            self.deliver(adversary, std::slice::from_ref(&block));
+            released.extend(self.respond(adversary));
+        }
+        for node in 0..self.nodes.len() {
+            if self.strategies[node] != Strategy::Honest {
+                continue;
+            }
+            if node == miner {
+                self.deliver(node, &released);
+            } else if !released.is_empty() && self.rng.gen_bool(self.gamma) {
+                self.deliver(node, &released);
+                self.deliver(node, std::slice::from_ref(&block));
+            } else {
+                self.deliver(node, std::slice::from_ref(&block));
+                self.deliver(node, &released);
+            }
+        }
+        // the adversaries learn the blocks of each other
+        for adversary in 0..self.nodes.len() {
+            if self.strategies[adversary] != Strategy::Honest {
+                self.deliver(adversary, &released);
+            }
+        }
+    }
+
+    /// Publish the blocks of an adversary to every node
+    fn publish_adversary(&mut self, miner: usize, blocks: Vec<Block>) {
+        if let Some(last) = blocks.last() {
+            self.public_height = self.public_height.max(self.height_of(miner, last));
+        }
+        for node in 0..self.nodes.len() {
+            if node != miner {
+                self.deliver(node, &blocks);
+            }
+        }
+    }
+
+    /// The withheld blocks an adversary publishes when the public chain grows
+    fn respond(&mut self, adversary: usize) -> Vec<Block> {
+        let private_height = match self.withheld[adversary].blocks.last() {
+            Some(last) => self.height_of(adversary, last),
+            None => return vec![],
+        };
+        let public_height = self.public_height;
+        let withheld = &mut self.withheld[adversary];
+        withheld.racing = false;
+        let released = match self.strategies[adversary] {
+            Strategy::Honest => unreachable!(),
+            Strategy::Selfish => {
+                if private_height < public_height {
+                    // behind, the node already mines on the public chain
+                    withheld.blocks.clear();
+                    vec![]
+                } else if private_height <= public_height + 1 {
+                    // a tie starts a race, a lead of one wins it
+                    withheld.racing = private_height == public_height;
+                    std::mem::take(&mut withheld.blocks)
+                } else {
+                    // far ahead, match the public chain only
+                    let count = withheld.blocks.len().saturating_sub((private_height - public_height) as usize);
+                    withheld.blocks.drain(..count).collect()
+                }
+            }
+            Strategy::Withhold(_) => {
+                // behind, the node already mines on the public chain
+                if private_height < public_height {
+                    withheld.blocks.clear();
+                }
+                vec![]
+            }
+        };
+        if let Some(last) = released.last() {
+            let height = self.height_of(adversary, last);
+            self.public_height = self.public_height.max(height);
+        }
+        released
+    }
+
+    /// Hand the blocks to a node, counting the reorganization of an honest node
+    fn deliver(&mut self, node: usize, blocks: &[Block]) {
+        for block in blocks {
+            let (old_tip, old_height) = self.nodes[node].tip();
+            if let Err(e) = self.nodes[node].receive(block) {
+                panic!("node {} rejected block {:?}: {}", node, block.hash(), e);
+            }
+            if self.strategies[node] != Strategy::Honest {
+                continue;
+            }
+            let depth = reorg_depth(&self.nodes[node].blockchain.lock().unwrap(), old_tip, old_height);
+            if depth > 0 {
+                *self.reorgs.entry(depth).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Height of a block known by the node
+    fn height_of(&self, node: usize, block: &Block) -> u32 {
+        self.nodes[node].blockchain.lock().unwrap().blocks.get(&block.hash()).unwrap().height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn miner(hash_power: f64, strategy: Strategy) -> Miner {
+        Miner { hash_power, strategy }
+    }
+
+    #[test]
+    fn honest_network() {
+        let config = Config::new(vec![miner(0.5, Strategy::Honest), miner(0.3, Strategy::Honest), miner(0.2, Strategy::Honest)], 100, 1);
+        let report = run(&config);
+        // the blocks reach everyone at once, nothing is orphaned
+        assert_eq!(report.chain_length, 100);
+        assert_eq!(report.orphan_rate, 0.0);
+        assert!(report.reorgs.is_empty());
+        assert_eq!(report.miners.iter().map(|m| m.mined).sum::<u32>(), 100);
+        assert_eq!(report.miners.iter().map(|m| m.in_chain).sum::<u32>(), 100);
+        // the same seed gives the same run
+        let again = run(&config);
+        assert_eq!(
+            report.miners.iter().map(|m| m.mined).collect::<Vec<_>>(),
+            again.miners.iter().map(|m| m.mined).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn selfish_mining_pays() {
+        let mut config = Config::new(vec![miner(0.4, Strategy::Selfish), miner(0.3, Strategy::Honest), miner(0.3, Strategy::Honest)], 600, 2);
+        config.gamma = 0.5;
+        let report = run(&config);
+        // the theory gives the selfish miner about 53% of the blocks with 40% of the hash power
+        assert!(report.miners[0].revenue_share > 0.45, "{:?}", report);
+        assert!(report.orphan_rate > 0.1);
+        assert!(report.max_reorg_depth >= 1);
+    }
+
+    #[test]
+    fn majority_withholding_reverts_final_blocks() {
+        let k = ChainSpec::simulated().k;
+        let config = Config::new(vec![miner(0.6, Strategy::Withhold(k + 1)), miner(0.4, Strategy::Honest)], 300, 3);
+        let report = run(&config);
+        assert!(report.deep_reorgs > 0, "{:?}", report);
+        assert!(report.max_reorg_depth > k);
+        // a small withholder hardly ever goes deeper than K
+        let config = Config::new(vec![miner(0.1, Strategy::Withhold(k + 1)), miner(0.9, Strategy::Honest)], 300, 3);
+        let report = run(&config);
+        assert_eq!(report.deep_reorgs, 0);
+        assert!(report.miners[0].revenue_share < 0.1);
+    }
+}
@@ -0,0 +1,97 @@
+//! In-process simulations of many nodes, without sockets nor real PoW, to study the consensus
+
+pub mod attack;
+pub mod network;
+
+use crossbeam::channel::Receiver;
+use std::sync::{Arc, Mutex};
+
+use crate::miner::{self, clock::Clock};
+use crate::types::block::Block;
+use crate::types::block_buffer::{self, BlockBuffer};
+use crate::types::chain_spec::ChainSpec;
+use crate::types::hash::{Hashable, H256};
+use crate::types::mempool::Mempool;
+use crate::Blockchain;
+
+/// A node of a simulation: the blockchain, mempool and block buffer of a full node, with a miner
+/// mining on command. Blocks are handed to it directly instead of through the p2p worker.
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub block_buffer: Arc<Mutex<BlockBuffer>>,
+    pub miner: miner::Handle,
+    finished_block_chan: Receiver<Block>,
+}
+
+impl Node {
+    /// A node of the network of the chain spec, its blocks paying `payout` and stamped by the clock
+    pub fn new(spec: &ChainSpec, payout: H256, clock: Arc<dyn Clock>) -> Node {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new_with_spec(spec.clone())));
+        let mempool = Arc::new(Mutex::new(Mempool::new_with_k(spec.k)));
+        let genesis = blockchain.lock().unwrap().get_all_blocks_from_genesis_to_finialized()[0].clone();
+        mempool.lock().unwrap().add_genesis(&genesis);
+        let (miner_ctx, miner, finished_block_chan) = miner::new_simulated(&blockchain, &mempool, Some(payout), clock);
+        miner_ctx.start();
+        Node {
+            blockchain,
+            mempool,
+            block_buffer: Arc::new(Mutex::new(BlockBuffer::new())),
+            miner,
+            finished_block_chan,
+        }
+    }
+
+    /// Mine a block on the tip, it is in the blockchain of the node but nobody else knows it
+    pub fn mine(&self) -> Block {
+        let block = self.miner.mine_block();
+        // nobody else waits for the mined blocks
+        _ = self.finished_block_chan.recv();
+        block
+    }
+
+    /// Receive a block from the network, checked like the p2p worker does. Return false if the block
+    /// is known or is an orphan kept in the block buffer.
+    pub fn receive(&self, block: &Block) -> Result<bool, String> {
+        {
+            let blockchain = self.blockchain.lock().unwrap();
+            if blockchain.blocks.contains_key(&block.hash()) {
+                return Ok(false);
+            }
+            block_buffer::check_block(block, &blockchain.spec)?;
+        }
+        self.block_buffer.lock().unwrap().send_block(block.clone(), &self.blockchain, &self.mempool)
+    }
+
+    /// Tip of the longest chain and its height
+    pub fn tip(&self) -> (H256, u32) {
+        let blockchain = self.blockchain.lock().unwrap();
+        (blockchain.tip(), blockchain.height)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.miner.exit();
+    }
+}
+
+/// Number of blocks removed from the longest chain when its tip moved from `old_tip` at `old_height`
+/// to the current tip, 0 if the new chain extends the old one
+pub fn reorg_depth(blockchain: &Blockchain, old_tip: H256, old_height: u32) -> u32 {
+    let parent = |hash: &H256| blockchain.blocks.get(hash).unwrap().block.get_parent();
+    let mut new = blockchain.tip();
+    let mut height = blockchain.height;
+    while height > old_height {
+        new = parent(&new);
+        height -= 1;
+    }
+    let mut old = old_tip;
+    let mut depth = 0;
+    while new != old {
+        new = parent(&new);
+        old = parent(&old);
+        depth += 1;
+    }
+    depth
+}
@@ -0,0 +1,429 @@
+//! Many nodes in one process, each one with its p2p worker and miner, talking over simulated links.
+//! The links delay and drop messages and can be cut by a partition, the nodes are then expected to
+//! converge to the same chain and UTXO set once the links are healed.
+
+use crossbeam::channel::unbounded;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use super::Node;
+use crate::miner::clock::MockClock;
+use crate::network::address_book::AddressBook;
+use crate::network::message::{Message, Version};
+use crate::network::peer::{self, Direction};
+use crate::network::server::Handle as ServerHandle;
+use crate::network::worker::Worker;
+use crate::types::block::Block;
+use crate::types::chain_spec::ChainSpec;
+use crate::types::hash::H256;
+use crate::types::mempool::Mempool;
+use crate::types::transaction::SignedTransaction;
+
+/// Port all the simulated nodes listen at, each one has its own ip
+const PORT: u16 = 6000;
+/// A connection fails if the handshake is not done by then
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the waits check the nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Behavior of a link, the same in both directions
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    /// probability to drop a message, the handshake is never dropped
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig { latency: Duration::from_millis(10), loss: 0.0 }
+    }
+}
+
+struct LinkState {
+    config: LinkConfig,
+    /// false when a partition cuts the link, every message is then dropped
+    up: bool,
+    rng: StdRng,
+}
+
+/// A connection between two nodes: the handle of each node for the other one
+struct Link {
+    state: Arc<Mutex<LinkState>>,
+    handles: (peer::Handle, peer::Handle),
+}
+
+/// A simulated node on the network
+pub struct NetNode {
+    pub node: Node,
+    pub addr: SocketAddr,
+    server: ServerHandle,
+    msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    /// connected peers, the broadcasts of the node go to them
+    peers: Arc<Mutex<HashMap<SocketAddr, peer::Handle>>>,
+}
+
+pub struct Network {
+    pub nodes: Vec<NetNode>,
+    /// by the pair of nodes, lowest first
+    links: HashMap<(usize, usize), Link>,
+    clock: Arc<MockClock>,
+    seed: u64,
+}
+
+impl Network {
+    /// Start `n` nodes of the network of the chain spec, not connected to each other
+    pub fn new(n: usize, spec: &ChainSpec, seed: u64) -> Network {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(spec.genesis_timestamp)));
+        let nodes = (0..n)
+            .map(|i| {
+                let node = Node::new(spec, H256([i as u8 + 1; 32]), clock.clone());
+                let (server, server_receiver) = ServerHandle::new_for_test();
+                let (msg_sink, msg_chan) = smol::channel::unbounded();
+                let address_book = Arc::new(Mutex::new(AddressBook::new()));
+                let worker = Worker::new(1, msg_chan, &server, &node.blockchain, &node.block_buffer, &node.mempool, &address_book);
+                worker.start();
+                // the broadcasts of the worker reach the peers like through the p2p server
+                let peers = Arc::new(Mutex::new(HashMap::new()));
+                {
+                    let peers = Arc::clone(&peers);
+                    thread::spawn(move || while server_receiver.relay(&peers) {});
+                }
+                NetNode {
+                    node,
+                    addr: SocketAddr::from(([10, 0, 0, i as u8 + 1], PORT)),
+                    server,
+                    msg_sink,
+                    peers,
+                }
+            })
+            .collect();
+        Network { nodes, links: HashMap::new(), clock, seed }
+    }
+
+    /// Start `n` nodes, each one connected to all the others
+    pub fn full_mesh(n: usize, spec: &ChainSpec, link: LinkConfig, seed: u64) -> Network {
+        let mut network = Network::new(n, spec, seed);
+        for a in 0..n {
+            for b in a + 1..n {
+                network.connect(a, b, link);
+            }
+        }
+        network
+    }
+
+    /// Connect node `a` to node `b` and do the handshake, the node behind downloads the chain of the other one
+    pub fn connect(&mut self, a: usize, b: usize, config: LinkConfig) {
+        assert!(a != b, "a node can not connect to itself");
+        let key = (a.min(b), a.max(b));
+        if let Some(link) = self.links.remove(&key) {
+            link.handles.0.disconnect();
+            link.handles.1.disconnect();
+        }
+        let rng = StdRng::seed_from_u64(self.seed ^ ((key.0 as u64) << 32 | key.1 as u64));
+        let state = Arc::new(Mutex::new(LinkState { config, up: true, rng }));
+        // the handle of a for b and the messages a writes to b, and the other way around
+        let (a_to_b, a_to_b_receiver) = peer::Handle::test_handle_without_handshake();
+        let mut a_to_b = a_to_b.with_addr(self.nodes[b].addr).with_direction(Direction::Outgoing);
+        let (b_to_a, b_to_a_receiver) = peer::Handle::test_handle_without_handshake();
+        let b_to_a = b_to_a.with_addr(self.nodes[a].addr);
+        forward(a_to_b_receiver, self.nodes[b].msg_sink.clone(), b_to_a.clone(), Arc::clone(&state));
+        forward(b_to_a_receiver, self.nodes[a].msg_sink.clone(), a_to_b.clone(), Arc::clone(&state));
+        self.nodes[a].peers.lock().unwrap().insert(self.nodes[b].addr, a_to_b.clone());
+        self.nodes[b].peers.lock().unwrap().insert(self.nodes[a].addr, b_to_a.clone());
+        let version = Version::local(&self.nodes[a].node.blockchain.lock().unwrap(), PORT);
+        a_to_b.write(Message::Version(version));
+        // the blocks are only announced to the peers done the handshake
+        assert!(
+            a_to_b.wait_handshake(HANDSHAKE_TIMEOUT) && b_to_a.wait_handshake(HANDSHAKE_TIMEOUT),
+            "handshake of nodes {} and {} not done",
+            a,
+            b
+        );
+        self.links.insert(key, Link { state, handles: (a_to_b, b_to_a) });
+    }
+
+    /// Change the latency and loss of the link between two nodes
+    pub fn set_link(&self, a: usize, b: usize, config: LinkConfig) {
+        let link = self.links.get(&(a.min(b), a.max(b))).expect("the nodes are not connected");
+        link.state.lock().unwrap().config = config;
+    }
+
+    /// Cut the links between the groups of nodes, the nodes of a group still talk to each other.
+    /// The nodes in no group are cut from all the others.
+    pub fn partition(&self, groups: &[Vec<usize>]) {
+        let group_of = |node: usize| groups.iter().position(|g| g.contains(&node));
+        for ((a, b), link) in &self.links {
+            let up = group_of(*a).is_some() && group_of(*a) == group_of(*b);
+            link.state.lock().unwrap().up = up;
+        }
+    }
+
+    /// Reconnect the links cut by a partition, with a new handshake
+    pub fn heal(&mut self) {
+        let cut: Vec<((usize, usize), LinkConfig)> = self
+            .links
+            .iter()
+            .filter_map(|(key, link)| {
+                let state = link.state.lock().unwrap();
+                if state.up {
+                    None
+                } else {
+                    Some((*key, state.config))
+                }
+            })
+            .collect();
+        for ((a, b), config) in cut {
+            self.connect(a, b, config);
+        }
+    }
+
+    /// The node mines a block on its tip and broadcasts it, the clock moves 10 seconds before
+    pub fn mine(&self, node: usize) -> Block {
+        self.clock.advance(Duration::from_secs(10));
+        let block = self.nodes[node].node.mine();
+        self.nodes[node].server.broadcast_block(&block);
+        block
+    }
+
+    /// The node takes the tx in its mempool and announces it to its peers
+    pub fn submit_tx(&self, node: usize, tx: &SignedTransaction) -> Result<(), String> {
+        self.nodes[node].node.mempool.lock().unwrap().add_tx(tx)?;
+        self.nodes[node].server.broadcast(Message::NewTransactionHashes(vec![tx.get_tx_hash()]));
+        Ok(())
+    }
+
+    /// Tip and height of every node
+    pub fn tips(&self) -> Vec<(H256, u32)> {
+        self.nodes.iter().map(|n| n.node.tip()).collect()
+    }
+
+    /// Wait until the condition holds, return false on timeout
+    pub fn wait_until<F: Fn(&Network) -> bool>(&self, timeout: Duration, condition: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition(self) {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        true
+    }
+
+    /// Wait until the nodes have the same tip, return false on timeout
+    pub fn wait_converged(&self, timeout: Duration) -> bool {
+        self.wait_until(timeout, |network| network.converged(&(0..network.nodes.len()).collect::<Vec<_>>()))
+    }
+
+    /// Whether the nodes have the same tip
+    pub fn converged(&self, nodes: &[usize]) -> bool {
+        let tips: Vec<H256> = nodes.iter().map(|i| self.nodes[*i].node.tip().0).collect();
+        tips.windows(2).all(|w| w[0] == w[1])
+    }
+
+    /// Check that every node has the UTXO set of its finalized chain, and that the nodes on the same
+    /// tip have the same UTXO set
+    pub fn check_utxo(&self) -> Result<(), String> {
+        let utxo_sets: Vec<HashMap<(H256, usize), u64>> = self
+            .nodes
+            .iter()
+            .map(|n| utxo_values(&n.node.mempool.lock().unwrap()))
+            .collect();
+        for (i, n) in self.nodes.iter().enumerate() {
+            let blockchain = n.node.blockchain.lock().unwrap();
+            let mut rebuilt = Mempool::new_with_k(blockchain.spec.k);
+            rebuilt.rebuild_utxo(&blockchain.get_all_blocks_from_genesis_to_finialized());
+            if utxo_values(&rebuilt) != utxo_sets[i] {
+                return Err(format!("node {} has not the UTXO set of its finalized chain", i));
+            }
+        }
+        for i in 1..self.nodes.len() {
+            if self.nodes[i].node.tip() == self.nodes[0].node.tip() && utxo_sets[i] != utxo_sets[0] {
+                return Err(format!("nodes 0 and {} have the same tip and different UTXO sets", i));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        // the forwarding threads stop with the links, the workers once their message channel is closed
+        for link in self.links.values() {
+            link.handles.0.disconnect();
+            link.handles.1.disconnect();
+        }
+        for n in &self.nodes {
+            n.msg_sink.close();
+        }
+    }
+}
+
+fn utxo_values(mempool: &Mempool) -> HashMap<(H256, usize), u64> {
+    mempool.utxo.iter().map(|(key, utxo)| (*key, utxo.output.value)).collect()
+}
+
+/// Pass the messages one side writes to the worker of the other side, as coming from `from`, after the
+/// latency of the link. The lost messages and those sent while the link is cut are dropped.
+fn forward(
+    mut receiver: peer::TestReceiver,
+    sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    from: peer::Handle,
+    state: Arc<Mutex<LinkState>>,
+) {
+    let (delayed_sender, delayed) = unbounded::<(Instant, Vec<u8>)>();
+    thread::spawn(move || {
+        for (at, bytes) in delayed {
+            thread::sleep(at.saturating_duration_since(Instant::now()));
+            if smol::block_on(sink.send((bytes, from.clone()))).is_err() {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        // the messages stay in order, like on a TCP connection
+        let mut last = Instant::now();
+        while let Some(msg) = receiver.next_message() {
+            let latency = {
+                let mut state = state.lock().unwrap();
+                let handshake = matches!(msg, Message::Version(_) | Message::Verack);
+                let loss = state.config.loss;
+                if !state.up || (!handshake && state.rng.gen_bool(loss)) {
+                    continue;
+                }
+                state.config.latency
+            };
+            last = last.max(Instant::now() + latency);
+            if delayed_sender.send((last, bincode::serialize(&msg).unwrap())).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ico::{self, IcoGenerator};
+    use crate::types::transaction_generator::Wallet;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Wait until every node knows every block, then mine one more block so that no tie is left
+    fn settle(network: &Network) {
+        let known = |n: &NetNode| n.node.blockchain.lock().unwrap().blocks.len();
+        assert!(network.wait_until(TIMEOUT, |network| network.nodes.iter().all(|n| known(n) == known(&network.nodes[0]))));
+        network.mine(0);
+        assert!(network.wait_converged(TIMEOUT), "{:?}", network.tips());
+    }
+
+    #[test]
+    fn converge_with_latency() {
+        let spec = ChainSpec::simulated();
+        let network = Network::full_mesh(4, &spec, LinkConfig { latency: Duration::from_millis(30), loss: 0.0 }, 1);
+        let mut mined = 0;
+        for i in 0..12 {
+            network.mine(i % 4);
+            mined += 1;
+            // the blocks mined before the previous one arrives are forks
+            if i % 2 == 1 {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        settle(&network);
+        mined += 1;
+        let (_, height) = network.tips()[0];
+        assert!(height <= mined && height > spec.k);
+        network.check_utxo().unwrap();
+    }
+
+    #[test]
+    fn partition_and_heal() {
+        let spec = ChainSpec::simulated();
+        let mut network = Network::full_mesh(4, &spec, LinkConfig::default(), 2);
+        for i in 0..spec.k + 2 {
+            network.mine(i as usize % 4);
+            assert!(network.wait_converged(TIMEOUT));
+        }
+        let (fork_tip, fork_height) = network.tips()[0];
+
+        network.partition(&[vec![0, 1], vec![2, 3]]);
+        for _ in 0..spec.k + 1 {
+            network.mine(0);
+        }
+        for _ in 0..2 {
+            network.mine(2);
+        }
+        assert!(network.wait_until(TIMEOUT, |n| n.converged(&[0, 1]) && n.converged(&[2, 3])));
+        assert!(network.wait_until(TIMEOUT, |n| n.tips()[1].1 == fork_height + spec.k + 1 && n.tips()[3].1 == fork_height + 2));
+        assert!(!network.converged(&[0, 2]));
+
+        // the short side downloads the long chain and reverts its 2 blocks
+        network.heal();
+        assert!(network.wait_converged(TIMEOUT), "{:?}", network.tips());
+        assert_eq!(network.tips()[2].1, fork_height + spec.k + 1);
+        let blockchain = network.nodes[2].node.blockchain.lock().unwrap();
+        assert!(blockchain.all_blocks_in_longest_chain().contains(&fork_tip));
+        drop(blockchain);
+        network.check_utxo().unwrap();
+    }
+
+    #[test]
+    fn lossy_links() {
+        let spec = ChainSpec::simulated();
+        let network = Network::full_mesh(4, &spec, LinkConfig { latency: Duration::from_millis(5), loss: 0.1 }, 3);
+        for i in 0..10 {
+            network.mine(i % 4);
+            thread::sleep(Duration::from_millis(50));
+        }
+        // a block lost on every link is requested when its child arrives
+        network.mine(0);
+        network.mine(0);
+        assert!(network.wait_converged(TIMEOUT), "{:?}", network.tips());
+        network.check_utxo().unwrap();
+    }
+
+    #[test]
+    fn wallet_transfers_converge() {
+        let spec = ChainSpec::simulated();
+        let network = Network::full_mesh(4, &spec, LinkConfig { latency: Duration::from_millis(5), loss: 0.0 }, 4);
+        let mut wallet = Wallet::new(IcoGenerator::load_key(ico::CONFIG_PATH));
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut height = 0;
+        for round in 0..2 {
+            // the wallet spends the outputs final on one node, the txs enter the mesh by the others
+            wallet.sync(&network.nodes[round].node.mempool.lock().unwrap());
+            let txs: Vec<SignedTransaction> = (0..3).map(|_| wallet.transfer(100, 1, &mut rng).unwrap()).collect();
+            for (i, tx) in txs.iter().enumerate() {
+                network.submit_tx((round + i) % 4, tx).unwrap();
+            }
+            let pending = |n: &NetNode| {
+                let mempool = n.node.mempool.lock().unwrap();
+                txs.iter().all(|tx| mempool.txs.iter().any(|t| t.get_tx_hash() == tx.get_tx_hash()))
+            };
+            assert!(network.wait_until(TIMEOUT, |network| network.nodes.iter().all(pending)));
+
+            // any miner takes them, they are final after k more blocks
+            for i in 0..spec.k + 1 {
+                network.mine(i as usize % 4);
+                height += 1;
+                assert!(network.wait_until(TIMEOUT, |n| n.converged(&[0, 1, 2, 3]) && n.tips()[0].1 == height));
+            }
+            for n in &network.nodes {
+                let mempool = n.node.mempool.lock().unwrap();
+                assert!(mempool.txs.is_empty());
+                for tx in &txs {
+                    let hash = tx.get_tx_hash();
+                    assert!((0..tx.transaction.outputs.len()).all(|index| mempool.utxo.contains_key(&(hash, index))));
+                    assert!(tx.transaction.inputs.iter().all(|input| !mempool.utxo.contains_key(&(input.source_tx_hash, input.index))));
+                }
+            }
+        }
+        network.check_utxo().unwrap();
+    }
+}
@@ -1,11 +1,28 @@
 use serde::{Serialize, Deserialize};
 use crate::types::{hash::{H256, Hashable }, transaction::Transaction};
-use super::{transaction::{SignedTransaction, Output}, merkle::MerkleTree, key_pair::PublicKey};
+use super::{transaction::{SignedTransaction, Output}, merkle::MerkleTree};
+use super::chain_spec::{Allocation, ChainSpec};
+use super::pow::Pow;
 use rand::prelude::*;
-use super::ico::IcoGenerator;
-use super::ico; 
+/// The first versionbits header version, headers below it are legacy ones
+pub const VERSION_BASE: u32 = 0x2000_0000;
+/// The top 3 bits of a versionbits header version must be 001
+pub const VERSION_TOP_MASK: u32 = 0xe000_0000;
+/// Number of feature bits a versionbits header can signal
+pub const VERSION_FEATURE_BITS: u32 = 29;
+/// The oldest header version still accepted
+pub const VERSION_MIN: u32 = 1;
+/// Number of recent blocks in the soft-fork signalling window
+pub const SIGNAL_WINDOW: u32 = 100;
+/// Number of signalling blocks in a window to lock in a soft fork
+pub const SIGNAL_THRESHOLD: u32 = 95;
+/// Feature bit of the soft fork requiring a block to be timestamped after its parent
+pub const FEATURE_TIMESTAMP_AFTER_PARENT: u32 = 0;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
+    /// consensus version, the low 29 bits of a versionbits header are feature bits
+    pub version: u32,
     /// prev block hash 
     pub parent: H256,
     pub difficulty: H256,
@@ -20,6 +37,32 @@ impl Hashable for Header {
     }
 }
 
+impl Header {
+    /// Feature bits signalled by this header, always 0 for a legacy header
+    pub fn feature_bits(&self) -> u32 {
+        if self.version & VERSION_TOP_MASK == VERSION_BASE {
+            self.version & !VERSION_TOP_MASK
+        } else {
+            0
+        }
+    }
+    /// Check if this header signals the feature bit
+    pub fn signals(&self, bit: u32) -> bool {
+        bit < VERSION_FEATURE_BITS && self.feature_bits() & (1 << bit) != 0
+    }
+    /// Check the header version, unknown feature bits are allowed, so nodes which don't
+    /// know a soft fork still accept the blocks signalling it
+    pub fn validate_version(&self) -> Result<(), String> {
+        if self.version < VERSION_MIN {
+            return Err(format!("obsolete header version {:#x}", self.version));
+        }
+        if self.version >= VERSION_BASE && self.version & VERSION_TOP_MASK != VERSION_BASE {
+            return Err(format!("unknown header version {:#x}", self.version));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Body {
     pub tx_count: usize, 
@@ -49,13 +92,39 @@ impl Block {
         //unimplemented!()
         self.header.difficulty
     }
-    /// genesis will return a static block 
+    /// Validate the block against the consensus rules of its header version
+    pub fn validate_rules(&self) -> Result<(), String> {
+        self.header.validate_version()?;
+        // blocks of every version must commit their txs, the header PoW covers the body through it
+        if self.body.tx_count != self.body.txs.len() {
+            return Err("tx count mismatch".to_string());
+        }
+        if MerkleTree::new(&self.body.txs).root() != self.header.merkle_root {
+            return Err("merkle root mismatch".to_string());
+        }
+        Ok(())
+    }
+    /// Validate the block against the rules of the soft forks active on its parent, `features` are the
+    /// active feature bits, see `Blockchain::active_features`
+    pub fn validate_soft_forks(&self, parent: &Header, features: u32) -> Result<(), String> {
+        if features & (1 << FEATURE_TIMESTAMP_AFTER_PARENT) != 0 && self.header.timestamp <= parent.timestamp {
+            return Err("timestamp not after the parent".to_string());
+        }
+        Ok(())
+    }
+    /// genesis will return a static block of the dev network
     pub fn genesis() -> Block{
-        let body = generate_ico_body();
-        let header = generate_genesis_header();
+        Block::genesis_from_spec(&ChainSpec::dev())
+    }
+    /// genesis block of the network described by the chain spec
+    pub fn genesis_from_spec(spec: &ChainSpec) -> Block{
+        let body = generate_ico_body(&spec.genesis_allocations);
+        let mut header = generate_genesis_header(spec);
+        // commit the ico tx, so light clients can prove genesis utxos
+        header.merkle_root = MerkleTree::new(&body.txs).root();
         let mut gb = Block { header: header, body: body }; 
         loop {
-            if gb.hash() < gb.header.difficulty {
+            if spec.pow.pow_hash(&gb.header) < gb.header.difficulty {
                 break;
             }
             gb.header.nonce += 1;
@@ -75,27 +144,15 @@ impl Block {
         Block { header: header, body: body }
     }
 }
-fn generate_genesis_header() -> Header{
-    // generate a 256 bits byte list with 16 bits zero, rest 1 
-    let mut difficulty = H256::default();
-    
-    for i in 0..32 {
-        difficulty.0[i] = 255;
-    }
-    difficulty.0[0] = 0;
-    difficulty.0[1] = 0;
-    difficulty.0[2] = 32; 
+fn generate_genesis_header(spec: &ChainSpec) -> Header{
     // make a static time stamp 
-    use chrono::{TimeZone, Utc};
-    let genesis_time = Utc.ymd(2023, 10, 01).and_hms(0,0,0); 
-    let timestamp = std::time::SystemTime::from(genesis_time); 
+    let timestamp = std::time::UNIX_EPOCH + std::time::Duration::from_secs(spec.genesis_timestamp); 
     let merkle_root = H256::default(); 
-    Header { parent: H256::default(), difficulty: difficulty, merkle_root: merkle_root, timestamp: timestamp, nonce: 0}
+    Header { version: VERSION_BASE, parent: H256::default(), difficulty: spec.difficulty, merkle_root: merkle_root, timestamp: timestamp, nonce: 0}
 
 }
 pub fn generate_random_header(parent: &H256) -> Header{
-    //gen a difficulty with 10 bit zero in the first 256 bits in H256 
-
+    // the difficulty of the dev network, 16 bits zero, then 0x20, rest 1
     let mut difficulty = H256::default();
    
     for i in 0..32 {
@@ -103,6 +160,7 @@ pub fn generate_random_header(parent: &H256) -> Header{
     } 
     difficulty.0[0] = 0;
     difficulty.0[1] = 0;
+    difficulty.0[2] = 32;
 
     // nounce should be a random u32 using crate ring 
     let mut rng = rand::thread_rng();
@@ -110,6 +168,7 @@ pub fn generate_random_header(parent: &H256) -> Header{
     let timestamp = std::time::SystemTime::now();
     let merkle_root = H256::default();
     Header {
+        version: VERSION_BASE,
         parent: parent.clone(),
         difficulty,
         merkle_root,
@@ -126,18 +185,12 @@ pub fn generate_empty_body() -> Body {
     }
 }
 // generate a body with init ico utxo, this will be used in genesis block 
-pub fn generate_ico_body() -> Body {
-    use ring::signature::KeyPair;
-    let mut ico = IcoGenerator::load_key(ico::CONFIG_PATH);
-    // generate a tx with 0 input and 3 output, each 1000,000 btc
-    // construct the output 
-    let mut outputs:Vec<Output> = Vec::new();
-    for i in 0..3 {
-        let pk: PublicKey = ico[i].public_key().as_ref().to_vec();
-        let pk_hash = pk.hash();
-        let output = Output{pk_hash: pk_hash, value: 1000000};
-        outputs.push(output);
-    }
+pub fn generate_ico_body(allocations: &[Allocation]) -> Body {
+    // generate a tx with 0 input and an output for each allocation
+    let outputs: Vec<Output> = allocations
+        .iter()
+        .map(|a| Output{pk_hash: a.pk_hash, value: a.value})
+        .collect();
     let ico_tx = Transaction{inputs: Vec::new(), outputs: outputs};
     let signed_ico_tx = SignedTransaction{transaction: ico_tx, fee: 0, witnesses: Vec::new()};
     Body{
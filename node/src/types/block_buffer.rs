@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use super::block::Block;
+use super::block::{Block, Header};
+use super::transaction::{Output, SignedTransaction};
 use super::hash::{Hashable, H256};
 use crate::types::mempool::Mempool;
-use crate::{blockchain::K, Blockchain};
-use log::{debug, info};
+use crate::types::chain_spec::ChainSpec;
+use crate::types::pow::Pow;
+use crate::Blockchain;
+use log::warn;
 #[derive(Clone)]
 pub struct BlockBuffer {
     buffer: HashMap<H256, Block>,
@@ -17,13 +20,14 @@ impl BlockBuffer {
             buffer: HashMap::new(),
         }
     }
-    /// if the parent of the block is in the blockchain, add the block to the blockchain, or add to the buffer, return true if the block is added to the blockchain
+    /// if the parent of the block is in the blockchain, validate the block on it and add it to the blockchain, or add to the buffer,
+    /// return true if the block is added to the blockchain. The block must have passed `check_block`.
     pub fn send_block(
         &mut self,
         _block: Block,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
-    ) -> bool {
+    ) -> Result<bool, String> {
         // check if the parent of the block is in the blockchain
         let parent_hash = _block.header.parent;
         let mut blockchain_unlocked = blockchain.lock().unwrap();
@@ -31,11 +35,11 @@ impl BlockBuffer {
         if !if_in {
             // for orphan blocks, just insert into blocks
             self.buffer.insert(_block.hash(), _block);
-            return false;
+            return Ok(false);
         }
         //This thread will hold the blockchain lock until the block is added to the blockchain
-        self.push_block(_block, &mut blockchain_unlocked, mempool);
-        true
+        self.push_block(_block, &mut blockchain_unlocked, mempool)?;
+        Ok(true)
     }
     /// push the block with parent in buffer to chain, is function is like a closure which can only be called by fn send_block
     fn push_block(
@@ -43,12 +47,10 @@ impl BlockBuffer {
         _block: Block,
         blockchain_unlocked: &mut Blockchain,
         mempool: &Arc<Mutex<Mempool>>,
-    ) {
-        //just throw invalid PoW block with parents, for currently invalid orphan PoW block, we save them in buffer
-        if _block.hash() > blockchain_unlocked.get_difficulty() {
-            return;
-        }
+    ) -> Result<(), String> {
         let mut unlocked_mempool = mempool.lock().unwrap();
+        //just throw invalid block with parents, for currently invalid orphan block, we save them in buffer
+        validate_on_parent(&_block, blockchain_unlocked, &unlocked_mempool)?;
         blockchain_insert_with_mempool_atomic(
             _block,
             blockchain_unlocked,
@@ -66,7 +68,7 @@ impl BlockBuffer {
                     .blocks
                     .contains_key(&block.header.parent)
                 {
-                    if block.hash() <= blockchain_unlocked.get_difficulty() {
+                    if validate_on_parent(&block, blockchain_unlocked, &unlocked_mempool).is_ok() {
                         blockchain_insert_with_mempool_atomic(
                             block.clone(),
                             blockchain_unlocked,
@@ -75,7 +77,7 @@ impl BlockBuffer {
                         to_remove.push(hash);
                         added = true;
                     } else {
-                        //once the invalid pow orphan block find its mom, remove invalid PoW block
+                        //once the invalid orphan block find its mom, remove invalid block
                         to_remove.push(hash);
                     }
                 }
@@ -87,17 +89,167 @@ impl BlockBuffer {
                 break;
             }
         }
+        Ok(())
+    }
+}
+/// Check what does not depend on the parent of a block: the difficulty and PoW of the chain spec and the rules
+/// of its header version
+pub fn check_block(block: &Block, spec: &ChainSpec) -> Result<(), String> {
+    check_header(&block.header, spec)?;
+    block.validate_rules()
+}
+/// Check a header has the difficulty of the chain spec and a valid PoW for it
+pub fn check_header(header: &Header, spec: &ChainSpec) -> Result<(), String> {
+    if header.difficulty != spec.difficulty {
+        return Err("wrong difficulty".to_string());
+    }
+    if spec.pow.pow_hash(header) > spec.difficulty {
+        return Err("invalid PoW".to_string());
+    }
+    Ok(())
+}
+/// Check a block whose parent is in the blockchain: PoW, the rules of its header version, its txs
+/// against the UTXO set at the parent and the coinbase
+pub fn validate_block(block: &Block, blockchain: &Blockchain, mempool: &Mempool) -> Result<(), String> {
+    check_block(block, &blockchain.spec)?;
+    validate_on_parent(block, blockchain, mempool)
+}
+/// Check what depends on the parent of a block, which is in the blockchain: the rules of the soft forks active
+/// on the parent, its txs against the UTXO set at the parent and the coinbase
+pub fn validate_on_parent(block: &Block, blockchain: &Blockchain, mempool: &Mempool) -> Result<(), String> {
+    let height = match blockchain.blocks.get(&block.get_parent()) {
+        Some(parent) => {
+            block.validate_soft_forks(&parent.block.header, blockchain.active_features(&block.get_parent()))?;
+            parent.height + 1
+        }
+        None => return Err("unknown parent".to_string()),
+    };
+    // the UTXO set is only replayed for a block spending something
+    let fees = if block.body.txs.iter().any(|tx| !tx.is_coinbase()) {
+        validate_txs(block, utxo_after(&block.get_parent(), blockchain, mempool))?
+    } else {
+        0
+    };
+    validate_coinbase(block, height, fees, &blockchain.spec)
+}
+/// Unspent outputs after a block of the blockchain: the finalized UTXO set of the mempool and the txs of the
+/// at most k blocks after the finalized block. The chain of a block off the finalized block is replayed from genesis.
+pub fn utxo_after(hash: &H256, blockchain: &Blockchain, mempool: &Mempool) -> HashMap<(H256, usize), Output> {
+    let finalized_height = blockchain.blocks.get(&blockchain.finalized_block).unwrap().height;
+    if mempool.synced_block_height != finalized_height {
+        return blockchain.utxo_after(hash);
+    }
+    let mut suffix = vec![];
+    let mut block_hash = *hash;
+    while block_hash != blockchain.finalized_block {
+        let block = blockchain.blocks.get(&block_hash).unwrap();
+        if block.height <= finalized_height {
+            return blockchain.utxo_after(hash);
+        }
+        suffix.push(&block.block);
+        block_hash = block.block.get_parent();
+    }
+    let mut utxo: HashMap<(H256, usize), Output> =
+        mempool.utxo.iter().map(|(key, utxo)| (*key, utxo.output.clone())).collect();
+    for block in suffix.into_iter().rev() {
+        for tx in &block.body.txs {
+            for input in &tx.transaction.inputs {
+                utxo.remove(&(input.source_tx_hash, input.index));
+            }
+            let tx_hash = tx.get_tx_hash();
+            for (index, output) in tx.transaction.outputs.iter().enumerate() {
+                utxo.insert((tx_hash, index), output.clone());
+            }
+        }
+    }
+    utxo
+}
+/// Check the txs of a block spend unspent outputs with valid witnesses, in the order of the block, and
+/// pay the fee they state, i.e. their inputs minus their outputs. Return the total fee.
+fn validate_txs(block: &Block, mut utxo: HashMap<(H256, usize), Output>) -> Result<u64, String> {
+    let mut fees: u64 = 0;
+    for tx in &block.body.txs {
+        if tx.is_coinbase() {
+            // a later tx of the block can spend the outputs
+            let tx_hash = tx.get_tx_hash();
+            for (index, output) in tx.transaction.outputs.iter().enumerate() {
+                utxo.insert((tx_hash, index), output.clone());
+            }
+        } else {
+            fees = fees.checked_add(spend_tx(tx, &mut utxo)?).ok_or("fee overflow")?;
+        }
+    }
+    Ok(fees)
+}
+/// Check a tx spends unspent outputs with valid witnesses and pays the fee it states, then apply it to
+/// the UTXO set. Return its fee, the UTXO set is left unchanged if the tx is invalid.
+pub fn spend_tx(tx: &SignedTransaction, utxo: &mut HashMap<(H256, usize), Output>) -> Result<u64, String> {
+    let tx_hash = tx.get_tx_hash();
+    let mut spent = vec![];
+    for (i, input) in tx.transaction.inputs.iter().enumerate() {
+        let key = (input.source_tx_hash, input.index);
+        // an output spent twice by the tx is only unspent the first time
+        let spent_before = tx.transaction.inputs[..i].iter().any(|input| (input.source_tx_hash, input.index) == key);
+        match utxo.get(&key).filter(|_| !spent_before) {
+            Some(output) => spent.push(output.clone()),
+            None => return Err(format!("tx {} spends an unknown or spent output", tx_hash)),
+        }
+    }
+    let mut input_value: u64 = 0;
+    for output in &spent {
+        input_value = input_value.checked_add(output.value).ok_or("input value overflow")?;
+    }
+    let mut output_value: u64 = 0;
+    for output in &tx.transaction.outputs {
+        output_value = output_value.checked_add(output.value).ok_or("output value overflow")?;
+    }
+    if output_value > input_value {
+        return Err(format!("tx {} pays {} out of {}", tx_hash, output_value, input_value));
+    }
+    if tx.fee != input_value - output_value {
+        return Err(format!("tx {} states fee {}, pays {}", tx_hash, tx.fee, input_value - output_value));
+    }
+    if tx.verify(&spent) < 0 {
+        return Err(format!("tx {} has an invalid witness", tx_hash));
+    }
+    for input in &tx.transaction.inputs {
+        utxo.remove(&(input.source_tx_hash, input.index));
+    }
+    for (index, output) in tx.transaction.outputs.iter().enumerate() {
+        utxo.insert((tx_hash, index), output.clone());
+    }
+    Ok(tx.fee)
+}
+/// Only the first tx can be a coinbase, and it can not claim more than the subsidy plus the fees of the block
+fn validate_coinbase(block: &Block, height: u32, fees: u64, spec: &ChainSpec) -> Result<(), String> {
+    for (i, tx) in block.body.txs.iter().enumerate() {
+        if tx.is_coinbase() && i != 0 {
+            return Err("coinbase is not the first tx".to_string());
+        }
+    }
+    if let Some(coinbase) = block.body.txs.first().filter(|tx| tx.is_coinbase()) {
+        if coinbase.transaction.inputs[0].index != height as usize {
+            return Err("coinbase height mismatch".to_string());
+        }
+        let mut value: u64 = 0;
+        for output in &coinbase.transaction.outputs {
+            value = value.checked_add(output.value).ok_or("coinbase value overflow")?;
+        }
+        if value > spec.reward.subsidy(height).saturating_add(fees) {
+            return Err(format!("coinbase claims {}, more than the subsidy and fees", value));
+        }
     }
+    Ok(())
 }
 pub fn blockchain_insert_with_mempool_atomic(
     _block: Block,
     blockchain_unlocked: &mut Blockchain,
     unlocked_mempool: &mut Mempool,
 ) {
+    let old_tip = blockchain_unlocked.tip();
     let (not_fork, new_finalized_block_hash) = blockchain_unlocked.insert(&_block);
     // this means the new finalized block is not the child of the current fn blk, so we need to rebuild utxo and flush the mempool
     if !not_fork {
-        use log::warn; 
         warn!("A fork happened, rebuild the utxo from genesis to {:?}", new_finalized_block_hash);
         //rebuild utxo and flush the mempool
         // get the new block from genesis to the fn block
@@ -107,8 +259,9 @@ pub fn blockchain_insert_with_mempool_atomic(
     } else {
         // just update utxo and mempool
         // check if the new finalized the block is higher
-        if blockchain_unlocked.height > K
-            && blockchain_unlocked.height - K > unlocked_mempool.synced_block_height
+        let k = blockchain_unlocked.spec.k;
+        if blockchain_unlocked.height > k
+            && blockchain_unlocked.height - k > unlocked_mempool.synced_block_height
         {
             // update the mempool using the new finalized block
             let new_fb = blockchain_unlocked
@@ -121,4 +274,168 @@ pub fn blockchain_insert_with_mempool_atomic(
                 .unwrap();
         }
     }
+    // the txs of the blocks joining the longest chain leave the mempool, the ones of the blocks leaving it come back
+    let (left, joined) = blockchain_unlocked.branch_change(&old_tip);
+    for hash in &joined {
+        unlocked_mempool.remove_txs(&blockchain_unlocked.blocks.get(hash).unwrap().block.body.txs);
+    }
+    if !left.is_empty() {
+        let restored = left
+            .iter()
+            .flat_map(|hash| blockchain_unlocked.blocks.get(hash).unwrap().block.body.txs.iter())
+            .cloned()
+            .collect();
+        let utxo = utxo_after(&blockchain_unlocked.tip(), blockchain_unlocked, unlocked_mempool);
+        unlocked_mempool.restore_txs(restored, &utxo);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ico::{self, IcoGenerator};
+    use crate::types::transaction::{self, Input, SignedTransaction, Transaction, Witness};
+    use ring::signature::KeyPair;
+
+    fn block_with_txs(txs: Vec<SignedTransaction>) -> Block {
+        Block::new_block_from_txs(&H256::default(), &txs)
+    }
+
+    /// A block with the difficulty of the simulated network
+    fn simulated_block(parent: &H256, txs: &Vec<SignedTransaction>) -> Block {
+        let mut block = Block::new_block_from_txs(parent, txs);
+        block.header.difficulty = H256([255; 32]);
+        block
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        let spec = ChainSpec::dev();
+        let subsidy = spec.reward.subsidy(3);
+        let pk_hash = H256::rand();
+        let paying_tx = SignedTransaction::default();
+        // subsidy plus the fees paid by the txs is fine
+        let block = block_with_txs(vec![SignedTransaction::coinbase(3, pk_hash, subsidy + 5), paying_tx.clone()]);
+        assert!(validate_coinbase(&block, 3, 5, &spec).is_ok());
+        // no coinbase is fine
+        let block = block_with_txs(vec![paying_tx.clone()]);
+        assert!(validate_coinbase(&block, 3, 5, &spec).is_ok());
+        // claims too much
+        let block = block_with_txs(vec![SignedTransaction::coinbase(3, pk_hash, subsidy + 6), paying_tx.clone()]);
+        assert!(validate_coinbase(&block, 3, 5, &spec).is_err());
+        // wrong height
+        let block = block_with_txs(vec![SignedTransaction::coinbase(4, pk_hash, subsidy)]);
+        assert!(validate_coinbase(&block, 3, 5, &spec).is_err());
+        // coinbase must be the first tx
+        let block = block_with_txs(vec![paying_tx, SignedTransaction::coinbase(3, pk_hash, subsidy)]);
+        assert!(validate_coinbase(&block, 3, 5, &spec).is_err());
+    }
+
+    #[test]
+    fn fees_paid_by_the_txs() {
+        let blockchain = Blockchain::new_with_spec(ChainSpec::simulated());
+        let genesis = blockchain.tip();
+        let mut mempool = Mempool::new();
+        mempool.add_genesis(&blockchain.blocks[&genesis].block);
+        let ico_tx = blockchain.blocks[&genesis].block.body.txs[0].clone();
+        let alice = &IcoGenerator::load_key(ico::CONFIG_PATH)[0];
+        let pubkey = alice.public_key().as_ref().to_vec();
+        // alice spends her genesis output, leaving a fee of 10
+        let spend = |fee: u64| {
+            let transaction = Transaction {
+                inputs: vec![Input::new(&ico_tx.get_tx_hash(), 0)],
+                outputs: vec![Output { pk_hash: H256::rand(), value: ico_tx.transaction.outputs[0].value - 10 }],
+            };
+            let sig = transaction::sign(&transaction, alice).as_ref().to_vec();
+            SignedTransaction { transaction, fee, witnesses: vec![Witness { pubkey: pubkey.clone(), sig }] }
+        };
+        let subsidy = blockchain.spec.reward.subsidy(1);
+        let block = simulated_block(&genesis, &vec![SignedTransaction::coinbase(1, H256::rand(), subsidy + 10), spend(10)]);
+        assert!(validate_block(&block, &blockchain, &mempool).is_ok());
+        // the coinbase claims the fee the tx states, not the one it pays
+        let block = simulated_block(&genesis, &vec![SignedTransaction::coinbase(1, H256::rand(), subsidy + 1000000), spend(1000000)]);
+        assert!(validate_block(&block, &blockchain, &mempool).unwrap_err().contains("states fee"));
+        // spending the same output twice
+        let block = simulated_block(&genesis, &vec![spend(10), spend(10)]);
+        assert!(validate_block(&block, &blockchain, &mempool).unwrap_err().contains("unknown or spent"));
+        // more outputs than inputs
+        let mut tx = spend(0);
+        tx.transaction.outputs[0].value += 11;
+        let block = simulated_block(&genesis, &vec![tx]);
+        assert!(validate_block(&block, &blockchain, &mempool).unwrap_err().contains("pays"));
+    }
+
+    #[test]
+    fn difficulty_of_the_spec() {
+        let blockchain = Blockchain::new_with_spec(ChainSpec::simulated());
+        let mempool = Mempool::new();
+        let mut block = simulated_block(&blockchain.tip(), &vec![]);
+        assert!(validate_block(&block, &blockchain, &mempool).is_ok());
+        // any hash meets the target of the simulated network, but a block must claim it
+        block.header.difficulty.0[0] = 0;
+        assert_eq!(validate_block(&block, &blockchain, &mempool), Err("wrong difficulty".to_string()));
+    }
+
+    #[test]
+    fn txs_follow_the_longest_chain() {
+        let spec = ChainSpec::simulated();
+        let mut blockchain = Blockchain::new_with_spec(spec.clone());
+        let mut mempool = Mempool::new_with_k(spec.k);
+        let genesis = blockchain.tip();
+        let ico_tx = blockchain.blocks[&genesis].block.body.txs[0].clone();
+        mempool.add_genesis(&blockchain.blocks[&genesis].block);
+        let alice = &IcoGenerator::load_key(ico::CONFIG_PATH)[0];
+        let transaction = Transaction {
+            inputs: vec![Input::new(&ico_tx.get_tx_hash(), 0)],
+            outputs: vec![Output { pk_hash: H256::rand(), value: ico_tx.transaction.outputs[0].value }],
+        };
+        let sig = transaction::sign(&transaction, alice).as_ref().to_vec();
+        let tx = SignedTransaction { transaction, fee: 0, witnesses: vec![Witness { pubkey: alice.public_key().as_ref().to_vec(), sig }] };
+        mempool.add_tx(&tx).unwrap();
+        let coinbase = |height: u32| SignedTransaction::coinbase(height, H256::rand(), spec.reward.subsidy(height));
+
+        // the tx leaves the mempool once in the longest chain, and is not valid on top of it
+        let block = simulated_block(&genesis, &vec![coinbase(1), tx.clone()]);
+        assert!(validate_block(&block, &blockchain, &mempool).is_ok());
+        blockchain_insert_with_mempool_atomic(block.clone(), &mut blockchain, &mut mempool);
+        assert!(mempool.txs.is_empty());
+        mempool.txs.push(tx.clone());
+        assert_eq!(mempool.valid_block_body(&utxo_after(&block.hash(), &blockchain, &mempool)).0.tx_count, 0);
+        mempool.txs.clear();
+
+        // a block of a shorter fork does not change the mempool
+        let fork = simulated_block(&genesis, &vec![coinbase(1)]);
+        blockchain_insert_with_mempool_atomic(fork.clone(), &mut blockchain, &mut mempool);
+        assert!(mempool.txs.is_empty());
+        // the fork becomes the longest chain, the tx comes back without the coinbase
+        let fork_child = simulated_block(&fork.hash(), &vec![coinbase(2)]);
+        blockchain_insert_with_mempool_atomic(fork_child.clone(), &mut blockchain, &mut mempool);
+        assert_eq!(blockchain.tip(), fork_child.hash());
+        assert_eq!(mempool.txs.len(), 1);
+        assert_eq!(mempool.txs[0].get_tx_hash(), tx.get_tx_hash());
+        let (body, _, _) = mempool.valid_block_body(&utxo_after(&fork_child.hash(), &blockchain, &mempool));
+        assert_eq!(body.tx_count, 1);
+    }
+
+    #[test]
+    fn utxo_after_the_finalized_utxo() {
+        let spec = ChainSpec::simulated();
+        let mut blockchain = Blockchain::new_with_spec(spec.clone());
+        let mut mempool = Mempool::new_with_k(spec.k);
+        let genesis = blockchain.tip();
+        mempool.add_genesis(&blockchain.blocks[&genesis].block);
+        let mut hashes = vec![genesis];
+        for height in 1..=spec.k + 3 {
+            let coinbase = SignedTransaction::coinbase(height, H256::rand(), spec.reward.subsidy(height));
+            let block = simulated_block(hashes.last().unwrap(), &vec![coinbase]);
+            blockchain_insert_with_mempool_atomic(block.clone(), &mut blockchain, &mut mempool);
+            hashes.push(block.hash());
+        }
+        assert_eq!(mempool.synced_block_height, 3);
+        let keys = |utxo: HashMap<(H256, usize), Output>| utxo.into_keys().collect::<std::collections::HashSet<_>>();
+        // after the tip, a block not final yet and a block before the finalized one, replayed from genesis
+        for hash in [blockchain.tip(), hashes[spec.k as usize], hashes[1]] {
+            assert_eq!(keys(utxo_after(&hash, &blockchain, &mempool)), keys(blockchain.utxo_after(&hash)));
+        }
+    }
 }
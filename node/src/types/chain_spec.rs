@@ -0,0 +1,242 @@
+use serde::{Serialize, Deserialize};
+use ring::signature::KeyPair;
+use super::hash::{Hashable, H256};
+use super::ico::{self, IcoGenerator};
+use super::key_pair::PublicKey;
+use super::pow::PowAlgorithm;
+use crate::blockchain::{K, REWARD};
+use std::fs;
+use std::path::Path;
+
+/// A genesis output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Allocation {
+    #[serde(with = "hex_h256")]
+    pub pk_hash: H256,
+    pub value: u64,
+}
+
+/// Block subsidy of the miners, halved every `halving_interval` blocks until it drops to the tail emission
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RewardSchedule {
+    /// subsidy of the blocks right after genesis
+    pub initial: u64,
+    /// number of blocks between two halvings, 0 means never halve
+    #[serde(default)]
+    pub halving_interval: u32,
+    /// the subsidy never drops below it, 0 means the supply is capped
+    #[serde(default)]
+    pub tail_emission: u64,
+}
+
+impl RewardSchedule {
+    /// Subsidy of the block at the height, genesis has no subsidy
+    pub fn subsidy(&self, height: u32) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+        let halvings = if self.halving_interval == 0 { 0 } else { (height - 1) / self.halving_interval };
+        let subsidy = if halvings >= 64 { 0 } else { self.initial >> halvings };
+        subsidy.max(self.tail_emission)
+    }
+
+    /// Total subsidy of the blocks from height 1 to height
+    pub fn total_subsidy(&self, height: u32) -> u64 {
+        if self.halving_interval == 0 {
+            return self.subsidy(1) * height as u64;
+        }
+        // sum era by era, the subsidy is constant inside an era
+        let mut total: u64 = 0;
+        let mut start = 1;
+        while start <= height {
+            let end = height.min(start.saturating_add(self.halving_interval - 1));
+            total = total.saturating_add(self.subsidy(start) * (end - start + 1) as u64);
+            if end == u32::MAX {
+                break;
+            }
+            start = end + 1;
+        }
+        total
+    }
+}
+
+/// Parameters of a network, loaded from a JSON chain spec file, so separate test networks
+/// never share a genesis
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainSpec {
+    /// network name, e.g. "dev", "testnet"
+    pub network: String,
+    /// magic bytes of the p2p frames
+    #[serde(with = "hex_magic")]
+    pub magic: [u8; 4],
+    /// genesis timestamp in seconds since unix epoch
+    pub genesis_timestamp: u64,
+    pub genesis_allocations: Vec<Allocation>,
+    /// PoW target, the PoW hash of a block must not be larger than it
+    #[serde(with = "hex_h256")]
+    pub difficulty: H256,
+    /// PoW hash of the headers, SHA-256 if not set
+    #[serde(default)]
+    pub pow: PowAlgorithm,
+    /// confirmation block number
+    pub k: u32,
+    pub reward: RewardSchedule,
+}
+
+impl ChainSpec {
+    /// Load a chain spec from a JSON file
+    pub fn load(path: &str) -> Result<ChainSpec, String> {
+        let content = fs::read_to_string(Path::new(path)).map_err(|e| format!("error reading {}: {}", path, e))?;
+        let spec: ChainSpec = serde_json::from_str(&content).map_err(|e| format!("error parsing {}: {}", path, e))?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    /// The default dev network, 1000000 btc for each of the test keys in `ico::CONFIG_PATH`
+    pub fn dev() -> ChainSpec {
+        let genesis_allocations = IcoGenerator::load_key(ico::CONFIG_PATH)
+            .iter()
+            .map(|key| {
+                let pk: PublicKey = key.public_key().as_ref().to_vec();
+                Allocation { pk_hash: pk.hash(), value: 1000000 }
+            })
+            .collect();
+        // 256 bits with 16 bits zero, then 0x20, rest 1
+        let mut difficulty = H256([255; 32]);
+        difficulty.0[0] = 0;
+        difficulty.0[1] = 0;
+        difficulty.0[2] = 32;
+        ChainSpec {
+            network: "dev".to_string(),
+            magic: *b"RBTC",
+            // 2023-10-01 00:00:00 UTC
+            genesis_timestamp: 1696118400,
+            genesis_allocations,
+            difficulty,
+            pow: PowAlgorithm::Sha256,
+            k: K,
+            reward: RewardSchedule { initial: REWARD as u64, halving_interval: 210, tail_emission: 0 },
+        }
+    }
+
+    /// The dev network with a trivial target, any block hash meets it, for the simulated mining of the tests
+    pub fn simulated() -> ChainSpec {
+        ChainSpec {
+            network: "simulated".to_string(),
+            difficulty: H256([255; 32]),
+            ..ChainSpec::dev()
+        }
+    }
+
+    /// Total amount of coins created from genesis to the height, by genesis allocations and block subsidy
+    pub fn scheduled_supply(&self, height: u32) -> u64 {
+        let genesis: u64 = self.genesis_allocations.iter().map(|a| a.value).sum();
+        genesis + self.reward.total_subsidy(height)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.k == 0 {
+            return Err("k must be positive".to_string());
+        }
+        if self.genesis_allocations.is_empty() {
+            return Err("no genesis allocation".to_string());
+        }
+        if let PowAlgorithm::MemoryHard { memory_kib: 0 } = self.pow {
+            return Err("memory_kib of the memory hard PoW must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// H256 as a hex string in the spec file
+mod hex_h256 {
+    use super::H256;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(h: &H256, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&h.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<H256, D::Error> {
+        let s = String::deserialize(d)?;
+        let bytes = hex::decode(&s).map_err(serde::de::Error::custom)?;
+        if bytes.len() != 32 {
+            return Err(serde::de::Error::custom("expect 32 bytes"));
+        }
+        let mut buffer = [0; 32];
+        buffer.copy_from_slice(&bytes);
+        Ok(H256(buffer))
+    }
+}
+
+/// magic bytes as a hex string in the spec file
+mod hex_magic {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(magic: &[u8; 4], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(magic))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 4], D::Error> {
+        let s = String::deserialize(d)?;
+        let bytes = hex::decode(&s).map_err(serde::de::Error::custom)?;
+        if bytes.len() != 4 {
+            return Err(serde::de::Error::custom("expect 4 bytes"));
+        }
+        let mut buffer = [0; 4];
+        buffer.copy_from_slice(&bytes);
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::Block;
+
+    #[test]
+    fn spec_json_round_trip() {
+        let spec = ChainSpec::dev();
+        let json = serde_json::to_string_pretty(&spec).unwrap();
+        let parsed: ChainSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.network, spec.network);
+        assert_eq!(parsed.magic, spec.magic);
+        assert_eq!(parsed.difficulty, spec.difficulty);
+        assert_eq!(parsed.pow, spec.pow);
+        assert_eq!(parsed.genesis_allocations.len(), 3);
+        assert_eq!(Block::genesis_from_spec(&parsed).hash(), Block::genesis().hash());
+    }
+
+    #[test]
+    fn spec_files() {
+        // the shipped dev spec is the same network as the built-in one
+        let dev = ChainSpec::load("../specs/dev.json").unwrap();
+        assert_eq!(Block::genesis_from_spec(&dev).hash(), Block::genesis().hash());
+        let testnet = ChainSpec::load("../specs/testnet.json").unwrap();
+        assert_ne!(testnet.magic, dev.magic);
+        assert_ne!(Block::genesis_from_spec(&testnet).hash(), Block::genesis().hash());
+    }
+
+    #[test]
+    fn subsidy_schedule() {
+        let reward = RewardSchedule { initial: 50, halving_interval: 10, tail_emission: 0 };
+        assert_eq!(reward.subsidy(0), 0);
+        assert_eq!(reward.subsidy(1), 50);
+        assert_eq!(reward.subsidy(10), 50);
+        assert_eq!(reward.subsidy(11), 25);
+        assert_eq!(reward.subsidy(21), 12);
+        assert_eq!(reward.subsidy(1000), 0);
+        assert_eq!(reward.total_subsidy(12), 50 * 10 + 25 * 2);
+        let total: u64 = (1..=1000).map(|h| reward.subsidy(h)).sum();
+        assert_eq!(reward.total_subsidy(1000), total);
+        // tail emission keeps the subsidy positive forever
+        let reward = RewardSchedule { initial: 50, halving_interval: 10, tail_emission: 1 };
+        assert_eq!(reward.subsidy(1000), 1);
+        let total: u64 = (1..=1000).map(|h| reward.subsidy(h)).sum();
+        assert_eq!(reward.total_subsidy(1000), total);
+        // never halve
+        let reward = RewardSchedule { initial: 50, halving_interval: 0, tail_emission: 0 };
+        assert_eq!(reward.subsidy(100000), 50);
+        assert_eq!(reward.total_subsidy(100), 5000);
+    }
+}
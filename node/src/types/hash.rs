@@ -1,7 +1,5 @@
 use serde::{Serialize, Deserialize};
 use std::{convert::TryInto, str::FromStr};
-#[cfg(any(test, test_utilities))]
-use rand::Rng;
 use rand::prelude::*;
 
 /// An object that can be meaningfully hashed.
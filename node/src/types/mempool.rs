@@ -1,17 +1,23 @@
 use log::{info, debug};
 
 use super::block::{Body, Block};
+use super::block_buffer;
 use super::merkle::MerkleTree;
 use super::transaction::{Transaction, SignedTransaction, Input, Output};
 use crate::blockchain::K;
 use super::hash::{H256, Hashable};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+/// Error of `Mempool::add_tx` for a tx with a bad signature or value, unlike the other errors it
+/// is never caused by a conflicting or unknown input
+pub const INVALID_TX: &str = "Invalid tx";
 pub struct Mempool {
     // k: tx_hash, v: signed_tx
     pub txs: Vec<SignedTransaction>,
     // utxo, this utxo is aligned with the finialized block and current mempool txs 
     pub utxo: HashMap<(H256, usize), UTXO>,
     pub synced_block_height: u32,
+    /// confirmation block number of the network
+    k: u32,
 }
 #[derive(Debug, Clone)]
 pub struct UTXO{
@@ -22,16 +28,33 @@ pub struct UTXO{
 }
 impl Mempool {
     pub fn new() -> Self {
+        Mempool::new_with_k(K)
+    }
+    /// Create a mempool for a network with confirmation block number k
+    pub fn new_with_k(k: u32) -> Self {
         Mempool {
             txs: Vec::new(),
             utxo: HashMap::new(),
             synced_block_height: 0, 
+            k,
         }
     }
     /// a back-door function for test and genesis initaliation
     pub fn add_utxo(&mut self, key: (H256, usize), utxo:  UTXO) {
         self.utxo.insert(key, utxo);
     }
+    /// add the utxos of the genesis block, genesis is not counted in synced_block_height
+    pub fn add_genesis(&mut self, genesis: &Block) {
+        for tx in &genesis.body.txs {
+            for (index, output) in tx.transaction.outputs.iter().enumerate() {
+                self.add_utxo((tx.get_tx_hash(), index), UTXO {output: output.clone(), used_in_mempool: false, used_height: 0});
+            }
+        }
+    }
+    /// total value of the utxo set
+    pub fn utxo_supply(&self) -> u64 {
+        self.utxo.values().map(|u| u.output.value).sum()
+    }
     /// get the correspoinding output for the tx from utxo 
     pub fn get_utxo(&self, tx: &SignedTransaction) -> Result< Vec<Output>, String> {
         let mut outputs = Vec::new();
@@ -61,7 +84,7 @@ impl Mempool {
         let outputs = self.get_utxo(tx)?;
         // check if the tx is valid
         if tx.verify(&outputs) < 0 {
-            return Err("Invalid tx".to_string());
+            return Err(INVALID_TX.to_string());
         }
         // update utxo, set the utxo used in the x used in the mempool to true
         for (_, input) in tx.transaction.inputs.iter().enumerate() {
@@ -69,35 +92,66 @@ impl Mempool {
             let output = self.utxo.get_mut(&(input.source_tx_hash, input.index)).unwrap();
             output.used_in_mempool = true;
             // This utxo will be used in height = synced_block_height + K + 1 if the tx is added to the finalized chain 
-            output.used_height = self.synced_block_height + K +  1; 
+            output.used_height = self.synced_block_height + self.k +  1; 
         } 
         //add the tx in txs 
         self.txs.push(tx.clone());
         
         Ok(())
     }
-    /// generate a block body for the miner, return blody, merkle root and total fee 
-    pub fn propose_block_body(&mut self) -> (Body, H256, u64){
-        //no block size limitation, put all tx in the mempool to next block 
-        debug!("propose {} txs", self.txs.len()); 
-        let body = Body{
-            tx_count: self.txs.len(),
-            txs: self.txs.clone(),
-        };
-        // get total tx_fee in this block 
+    /// Block body of the pending txs valid against the UTXO set at the tip of the longest chain, in the order
+    /// they arrived, return the body, its merkle root and the total fee
+    pub fn valid_block_body(&self, utxo: &HashMap<(H256, usize), Output>) -> (Body, H256, u64) {
+        let mut utxo = utxo.clone();
         let mut total_fee = 0;
+        let mut txs = vec![];
         for tx in &self.txs {
-            total_fee += tx.fee;
+            // a tx already in the chain or conflicting with it is left out
+            if let Ok(fee) = block_buffer::spend_tx(tx, &mut utxo) {
+                total_fee += fee;
+                txs.push(tx.clone());
+            }
         }
-        // add a coin  base which containts all tx fee + a fixed reward
-        // build a merkle tree for the txs
-        let merkle_tree = MerkleTree::new(&self.txs);
-        let merkle_root = merkle_tree.root();
-        // remove all txs 
-        self.txs.clear();
-        //
-        (body, merkle_root, total_fee)
-
+        let merkle_root = MerkleTree::new(&txs).root();
+        (Body { tx_count: txs.len(), txs }, merkle_root, total_fee)
+    }
+    /// generate a block body for the miner from the pending txs valid against the UTXO set at the tip,
+    /// return blody, merkle root and total fee
+    pub fn propose_block_body(&mut self, utxo: &HashMap<(H256, usize), Output>) -> (Body, H256, u64){
+        let proposed = self.valid_block_body(utxo);
+        debug!("propose {} txs out of {}", proposed.0.tx_count, self.txs.len());
+        // remove the proposed txs
+        self.remove_txs(&proposed.0.txs);
+        proposed
+    }
+    /// Remove the pending txs included in a block, their inputs stay used until the block is finalized
+    pub fn remove_txs(&mut self, txs: &[SignedTransaction]) {
+        let mined: HashSet<H256> = txs.iter().map(|tx| tx.get_tx_hash()).collect();
+        self.txs.retain(|tx| !mined.contains(&tx.get_tx_hash()));
+    }
+    /// Put back the txs of a proposed block which was not mined, or of a block which left the longest chain,
+    /// ahead of the txs which arrived since. Only the txs still valid against the UTXO set at the tip come
+    /// back, the ones already in the chain or spending an output the chain spent are dropped. Their inputs
+    /// are marked as used.
+    pub fn restore_txs(&mut self, txs: Vec<SignedTransaction>, utxo: &HashMap<(H256, usize), Output>) {
+        let pending: HashSet<H256> = self.txs.iter().map(|tx| tx.get_tx_hash()).collect();
+        let mut utxo = utxo.clone();
+        let mut restored: Vec<SignedTransaction> = txs
+            .into_iter()
+            .filter(|tx| !tx.is_coinbase() && !pending.contains(&tx.get_tx_hash()))
+            .filter(|tx| block_buffer::spend_tx(tx, &mut utxo).is_ok())
+            .collect();
+        for tx in &restored {
+            for input in &tx.transaction.inputs {
+                if let Some(output) = self.utxo.get_mut(&(input.source_tx_hash, input.index)) {
+                    output.used_in_mempool = true;
+                    output.used_height = self.synced_block_height + self.k + 1;
+                }
+            }
+        }
+        restored.append(&mut self.txs);
+        self.txs = restored;
+        self.check_mempool();
     }
     /// querying UTXO by public key hash 
     pub fn query_utxo(&self, pk_hash: &H256) -> Vec<Output> {
@@ -185,10 +239,16 @@ impl Mempool {
     /// 1. remove all txs from the mempool
     /// 2. from the block height zero to the fork height, add all blocks to the mempool 
     pub fn rebuild_utxo(&mut self, blocks: &Vec<Block>) {
-        // remove all txs from the mempool
+        // remove all txs and utxos from the mempool
         self.txs.clear();
+        self.utxo.clear();
+        self.synced_block_height = 0;
         // from the block height zero to the fork height, add all blocks to the mempool 
-        for block in blocks {
+        for (height, block) in blocks.iter().enumerate() {
+            if height == 0 {
+                self.add_genesis(block);
+                continue;
+            }
             _ = self.receive_finalized_block(&block);
         }
     }
@@ -300,12 +360,24 @@ mod tests{
         let utxo3 = mempool.utxo.get(&(signed_tx.get_tx_hash(), 2)).unwrap();
         assert_eq!(utxo3.used_in_mempool, true);
         // call propose block body, check the result
-        let (body, _, total_fee) = mempool.propose_block_body();
+        let utxo: HashMap<(H256, usize), Output> = mempool.utxo.iter().map(|(key, utxo)| (*key, utxo.output.clone())).collect();
+        let (body, merkle_root, total_fee) = mempool.propose_block_body(&utxo);
         // check the block size, block merkle and block fee
         assert_eq!(body.tx_count, 1);
         assert_eq!(body.txs[0].get_tx_hash(), tx_tobe_add.get_tx_hash());
-        //assert_eq!(merkle_root, body.merkle_root);
+        assert_eq!(merkle_root, MerkleTree::new(&body.txs).root());
         assert_eq!(total_fee, 10);
+        assert_eq!(mempool.txs.len(), 0);
+        // the txs of a block which was not mined go back to the mempool, without the coinbase
+        let mut txs = body.txs.clone();
+        txs.insert(0, SignedTransaction::coinbase(1, H256::rand(), 50));
+        mempool.restore_txs(txs, &utxo);
+        let (body, restored_root, _) = mempool.propose_block_body(&utxo);
+        assert_eq!(body.tx_count, 1);
+        assert_eq!(restored_root, merkle_root);
+        // a tx whose inputs the chain spent meanwhile does not come back
+        mempool.restore_txs(body.txs, &HashMap::new());
+        assert_eq!(mempool.txs.len(), 0);
 
     }
     /// Test then mempool receive a finalized block from the blockchain, update utxo and txs
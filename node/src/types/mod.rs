@@ -7,4 +7,6 @@ pub mod transaction;
 pub mod block_buffer; 
 pub mod mempool;
 pub mod transaction_generator;
-pub mod ico; 
\ No newline at end of file
+pub mod ico; 
+pub mod chain_spec;
+pub mod pow;
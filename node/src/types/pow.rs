@@ -0,0 +1,154 @@
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt;
+
+use super::block::Header;
+use super::hash::H256;
+
+/// Proof-of-work hash of a header, a header is solved when it is not larger than the target.
+/// The block id stays `Header::hash`, only the PoW checks of the miner and the validation use it.
+pub trait Pow: Send + Sync {
+    fn pow_hash(&self, header: &Header) -> H256;
+    /// Cost of a hash in SHA-256 of a header, so the miner checks its stop flag as often whatever the algorithm
+    fn cost(&self) -> u32 {
+        1
+    }
+}
+
+/// SHA-256 of the bincode header, the PoW hash is the block id
+pub struct Sha256;
+
+impl Pow for Sha256 {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        digest(&SHA256, &bincode::serialize(header).unwrap()).into()
+    }
+}
+
+/// SHA-256 of the SHA-256 of the bincode header, like bitcoin
+pub struct DoubleSha256;
+
+impl Pow for DoubleSha256 {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        let first = digest(&SHA256, &bincode::serialize(header).unwrap());
+        digest(&SHA256, first.as_ref()).into()
+    }
+
+    fn cost(&self) -> u32 {
+        2
+    }
+}
+
+/// A sequential memory-hard hash in the way of scrypt's ROMix, with SHA-256 as the mixing function:
+/// a table of `memory_kib` KiB of chained hashes of the header is filled, then read at positions
+/// depending on the previous reads, so a hash needs the whole table in memory or many recomputations
+pub struct MemoryHard {
+    pub memory_kib: u32,
+}
+
+impl MemoryHard {
+    /// Number of 32-byte entries of the table
+    fn entries(&self) -> usize {
+        (self.memory_kib as usize * 1024 / 32).max(1)
+    }
+}
+
+impl Pow for MemoryHard {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        let sha256 = |bytes: &[u8]| -> [u8; 32] { digest(&SHA256, bytes).as_ref().try_into().unwrap() };
+        let n = self.entries();
+        let mut x = sha256(&bincode::serialize(header).unwrap());
+        let mut table = Vec::with_capacity(n);
+        for _ in 0..n {
+            table.push(x);
+            x = sha256(&x);
+        }
+        for _ in 0..n {
+            let j = (u64::from_le_bytes(x[..8].try_into().unwrap()) % n as u64) as usize;
+            for (a, b) in x.iter_mut().zip(table[j].iter()) {
+                *a ^= b;
+            }
+            x = sha256(&x);
+        }
+        x.into()
+    }
+
+    fn cost(&self) -> u32 {
+        (2 * self.entries()).min(u32::MAX as usize) as u32
+    }
+}
+
+/// PoW algorithm of a chain, set by the `pow` field of the chain spec, SHA-256 if not set
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum PowAlgorithm {
+    #[default]
+    Sha256,
+    DoubleSha256,
+    MemoryHard { memory_kib: u32 },
+}
+
+impl Pow for PowAlgorithm {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        match *self {
+            PowAlgorithm::Sha256 => Sha256.pow_hash(header),
+            PowAlgorithm::DoubleSha256 => DoubleSha256.pow_hash(header),
+            PowAlgorithm::MemoryHard { memory_kib } => MemoryHard { memory_kib }.pow_hash(header),
+        }
+    }
+
+    fn cost(&self) -> u32 {
+        match *self {
+            PowAlgorithm::Sha256 => Sha256.cost(),
+            PowAlgorithm::DoubleSha256 => DoubleSha256.cost(),
+            PowAlgorithm::MemoryHard { memory_kib } => MemoryHard { memory_kib }.cost(),
+        }
+    }
+}
+
+impl fmt::Display for PowAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowAlgorithm::Sha256 => write!(f, "sha256"),
+            PowAlgorithm::DoubleSha256 => write!(f, "double_sha256"),
+            PowAlgorithm::MemoryHard { memory_kib } => write!(f, "memory_hard ({} KiB)", memory_kib),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_header;
+    use crate::types::hash::Hashable;
+
+    #[test]
+    fn sha256_is_the_block_id() {
+        let header = generate_random_header(&H256::rand());
+        assert_eq!(PowAlgorithm::Sha256.pow_hash(&header), header.hash());
+        assert_eq!(PowAlgorithm::DoubleSha256.pow_hash(&header), header.hash().hash());
+    }
+
+    #[test]
+    fn memory_hard_hash() {
+        let mut header = generate_random_header(&H256::rand());
+        let pow = PowAlgorithm::MemoryHard { memory_kib: 16 };
+        let hash = pow.pow_hash(&header);
+        assert_eq!(pow.pow_hash(&header), hash);
+        assert_ne!(hash, header.hash());
+        // the table size is part of the algorithm
+        assert_ne!(PowAlgorithm::MemoryHard { memory_kib: 32 }.pow_hash(&header), hash);
+        header.nonce = header.nonce.wrapping_add(1);
+        assert_ne!(pow.pow_hash(&header), hash);
+        assert_eq!(pow.cost(), 1024);
+    }
+
+    #[test]
+    fn spec_field() {
+        let pow: PowAlgorithm = serde_json::from_str(r#"{"algorithm": "memory_hard", "memory_kib": 64}"#).unwrap();
+        assert_eq!(pow, PowAlgorithm::MemoryHard { memory_kib: 64 });
+        let pow: PowAlgorithm = serde_json::from_str(r#"{"algorithm": "double_sha256"}"#).unwrap();
+        assert_eq!(pow, PowAlgorithm::DoubleSha256);
+        assert!(serde_json::from_str::<PowAlgorithm>(r#"{"algorithm": "scrypt"}"#).is_err());
+    }
+}
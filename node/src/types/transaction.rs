@@ -116,6 +116,19 @@ impl SignedTransaction{
         }
         res
     }
+    /// Create a coinbase tx paying the block subsidy and fees of the block at the height to pk_hash.
+    /// A coinbase has a single input with a zero source tx hash, its index is the block height,
+    /// so two coinbase txs never share the same tx hash
+    pub fn coinbase(height: u32, pk_hash: H256, value: u64) -> Self {
+        let transaction = Transaction{
+            inputs: vec![Input::new(&H256::default(), height as usize)],
+            outputs: vec![Output{pk_hash, value}],
+        };
+        SignedTransaction{transaction, fee: 0, witnesses: Vec::new()}
+    }
+    pub fn is_coinbase(&self) -> bool {
+        self.transaction.inputs.len() == 1 && self.transaction.inputs[0].source_tx_hash == H256::default()
+    }
     pub fn get_tx_hash(&self) -> H256 {
         self.transaction.hash()
     }
@@ -160,7 +173,7 @@ pub fn generate_empty_transaction() -> Transaction {
     Transaction{inputs: inputs, outputs: outputs}
 
 }
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub fn generate_random_transaction() -> Transaction {
     // gen two rand hash 
     let h1 = H256::rand();
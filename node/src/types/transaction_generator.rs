@@ -1,26 +1,433 @@
-use std::{thread, time};
-use crate::network::server::Handle as NetworkServerHandle;
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{debug, info};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::network::message::Message;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::types::hash::{Hashable, H256};
+use crate::types::key_pair::PublicKey;
 use crate::types::mempool::Mempool;
-use crate::types::transaction::*; 
-use crate::types::key_pair; 
-pub struct TransactionGenerator{}
-
-impl TransactionGenerator{
-    pub fn start(theta: u32, network: NetworkServerHandle, mempool : Arc<Mutex<Mempool>>){
-        thread::spawn(move || {
-            let mut i = 0; 
-          loop{
-                if theta != 0{
-                    //gen tx 
-                    // if i = 0, A => B 10 btc 
-                    // if i = 1, B => C 10 btc
-                    // if i = 2, C => A 10 btc
-                    i = (i + 1) % 3; 
-                    let interval = time::Duration::from_micros(theta as u64);
-                    thread::sleep(interval);
-                }  
+use crate::types::transaction::{self, Input, Output, SignedTransaction, Transaction, Witness};
+
+/// Below this number of spendable outputs, the change is split to have more outputs to spend once
+/// the txs are final, the change of a tx can not be spent before
+const MIN_OUTPUTS: usize = 64;
+/// Number of outputs the change is split into
+const SPLIT: u64 = 4;
+/// Longest wait between two txs, whatever the rate
+const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Value of the generated transfers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueDistribution {
+    Fixed(u64),
+    /// between the two values, both included
+    Uniform(u64, u64),
+    /// exponential with this mean, many small transfers and a few large ones
+    Exponential(u64),
+}
+
+impl ValueDistribution {
+    /// Draw a value, never 0
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let value = match *self {
+            ValueDistribution::Fixed(value) => value,
+            ValueDistribution::Uniform(min, max) => rng.gen_range(min..=max),
+            ValueDistribution::Exponential(mean) => (-(mean as f64) * (1.0 - rng.gen::<f64>()).ln()).round() as u64,
+        };
+        value.max(1)
+    }
+}
+
+/// The form of the API parameter: `fixed:<VALUE>`, `uniform:<MIN>-<MAX>` or `exp:<MEAN>`
+impl FromStr for ValueDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| v.parse::<u64>().map_err(|e| format!("invalid value {}: {}", v, e));
+        match s.split_once(':') {
+            Some(("fixed", value)) => Ok(ValueDistribution::Fixed(parse(value)?)),
+            Some(("uniform", range)) => {
+                let (min, max) = range.split_once('-').ok_or("uniform needs <MIN>-<MAX>")?;
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err("uniform min is larger than max".to_string());
+                }
+                Ok(ValueDistribution::Uniform(min, max))
+            }
+            Some(("exp", mean)) => Ok(ValueDistribution::Exponential(parse(mean)?)),
+            _ => Err(format!("unknown value distribution {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ValueDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueDistribution::Fixed(value) => write!(f, "fixed:{}", value),
+            ValueDistribution::Uniform(min, max) => write!(f, "uniform:{}-{}", min, max),
+            ValueDistribution::Exponential(mean) => write!(f, "exp:{}", mean),
+        }
+    }
+}
+
+impl Serialize for ValueDistribution {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// transfers per second
+    pub rate: f64,
+    pub values: ValueDistribution,
+    pub fee: u64,
+}
+
+impl Config {
+    /// Time between two txs, a rate too low for a duration waits the longest interval
+    fn interval(&self) -> Duration {
+        Duration::try_from_secs_f64(1.0 / self.rate).map_or(MAX_INTERVAL, |d| d.min(MAX_INTERVAL))
+    }
+}
+
+/// The keys of the generator and their outputs spendable now
+pub struct Wallet {
+    keys: Vec<Ed25519KeyPair>,
+    /// public key and its hash, by key
+    public_keys: Vec<(PublicKey, H256)>,
+    /// value and key of each spendable output
+    outputs: HashMap<(H256, usize), (u64, usize)>,
+}
+
+impl Wallet {
+    pub fn new(keys: Vec<Ed25519KeyPair>) -> Self {
+        let public_keys = keys
+            .iter()
+            .map(|key| {
+                let pk: PublicKey = key.public_key().as_ref().to_vec();
+                let pk_hash = pk.hash();
+                (pk, pk_hash)
+            })
+            .collect();
+        Wallet { keys, public_keys, outputs: HashMap::new() }
+    }
+
+    /// Take the outputs of the keys from the finalized UTXO set, except those spent by pending txs
+    pub fn sync(&mut self, mempool: &Mempool) {
+        let owner: HashMap<H256, usize> = self.public_keys.iter().enumerate().map(|(i, (_, h))| (*h, i)).collect();
+        self.outputs = mempool
+            .utxo
+            .iter()
+            .filter(|(_, utxo)| !utxo.used_in_mempool)
+            .filter_map(|(key, utxo)| owner.get(&utxo.output.pk_hash).map(|i| (*key, (utxo.output.value, *i))))
+            .collect();
+    }
+
+    /// Total value of the spendable outputs
+    pub fn balance(&self) -> u64 {
+        self.outputs.values().map(|(value, _)| value).sum()
+    }
+
+    /// Number of spendable outputs
+    pub fn output_count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// A signed tx sending `value` from the largest outputs to another key of the wallet, the change
+    /// goes back to the key of the first input. The spent outputs leave the wallet.
+    pub fn transfer<R: Rng>(&mut self, value: u64, fee: u64, rng: &mut R) -> Result<SignedTransaction, String> {
+        let mut outputs: Vec<((H256, usize), (u64, usize))> = self.outputs.iter().map(|(k, v)| (*k, *v)).collect();
+        // largest first, the order of the equal ones does not depend on the hash map
+        outputs.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0 .0 .0.cmp(&b.0 .0 .0)).then(a.0 .1.cmp(&b.0 .1)));
+        let needed = value.checked_add(fee).ok_or("value overflow")?;
+        let mut inputs = vec![];
+        let mut total: u64 = 0;
+        for (outpoint, (output_value, key)) in outputs {
+            if total >= needed {
+                break;
+            }
+            inputs.push((outpoint, key));
+            total += output_value;
+        }
+        if total < needed {
+            return Err(format!("not enough spendable value, {} for {}", total, needed));
+        }
+        let sender = inputs[0].1;
+        let receiver = if self.keys.len() > 1 {
+            (sender + rng.gen_range(1..self.keys.len())) % self.keys.len()
+        } else {
+            sender
+        };
+        let mut tx_outputs = vec![Output { pk_hash: self.public_keys[receiver].1, value }];
+        let change = total - needed;
+        let left = self.outputs.len() - inputs.len();
+        let split = if left < MIN_OUTPUTS && change >= SPLIT { SPLIT } else { 1 };
+        for i in 0..split {
+            // the first share takes the remainder
+            let share = change / split + if i == 0 { change % split } else { 0 };
+            if share > 0 {
+                tx_outputs.push(Output { pk_hash: self.public_keys[sender].1, value: share });
+            }
+        }
+        let transaction = Transaction {
+            inputs: inputs.iter().map(|((hash, index), _)| Input::new(hash, *index)).collect(),
+            outputs: tx_outputs,
+        };
+        let witnesses = inputs
+            .iter()
+            .map(|(_, key)| Witness {
+                pubkey: self.public_keys[*key].0.clone(),
+                sig: transaction::sign(&transaction, &self.keys[*key]).as_ref().to_vec(),
+            })
+            .collect();
+        for (outpoint, _) in &inputs {
+            self.outputs.remove(outpoint);
+        }
+        Ok(SignedTransaction { transaction, fee, witnesses })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Status {
+    pub running: bool,
+    pub config: Option<Config>,
+    /// txs accepted by the mempool and broadcast
+    pub sent: u64,
+    /// txs not built for lack of spendable value, or refused by the mempool
+    pub failed: u64,
+    pub sent_value: u64,
+    pub fees: u64,
+    /// spendable now, the outputs of the pending txs are spendable once final
+    pub balance: u64,
+    pub outputs: usize,
+}
+
+enum ControlSignal {
+    Start(Config),
+    Stop,
+    Exit,
+}
+
+pub struct Context {
+    control_chan: Receiver<ControlSignal>,
+    wallet: Wallet,
+    mempool: Arc<Mutex<Mempool>>,
+    network: NetworkServerHandle,
+    status: Arc<Mutex<Status>>,
+    rng: StdRng,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
+}
+
+/// Create a generator of transfers between the keys, e.g. those of `IcoGenerator::load_key`, submitted
+/// to the mempool and broadcast like the txs of `/mempool/submit_tx`
+pub fn new(keys: Vec<Ed25519KeyPair>, mempool: &Arc<Mutex<Mempool>>, network: &NetworkServerHandle) -> (Context, Handle) {
+    let (control_sender, control_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status::default()));
+    let ctx = Context {
+        control_chan: control_receiver,
+        wallet: Wallet::new(keys),
+        mempool: Arc::clone(mempool),
+        network: network.clone(),
+        status: Arc::clone(&status),
+        rng: StdRng::from_entropy(),
+    };
+    (ctx, Handle { control_chan: control_sender, status })
+}
+
+impl Handle {
+    /// Start generating, or change the config of the running generator
+    pub fn start(&self, config: Config) {
+        self.control_chan.send(ControlSignal::Start(config)).unwrap();
+    }
+
+    pub fn stop(&self) {
+        self.control_chan.send(ControlSignal::Stop).unwrap();
+    }
+
+    pub fn exit(&self) {
+        self.control_chan.send(ControlSignal::Exit).unwrap();
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("tx-generator".to_string())
+            .spawn(move || {
+                self.generator_loop();
+            })
+            .unwrap();
+        info!("Transaction generator initialized into paused mode");
+    }
+
+    fn generator_loop(&mut self) {
+        let mut config: Option<Config> = None;
+        loop {
+            // wait for a signal while paused, until the next tx otherwise
+            let signal = match config {
+                None => self.control_chan.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(c) => self.control_chan.recv_timeout(c.interval()),
+            };
+            match signal {
+                Ok(ControlSignal::Start(c)) => {
+                    info!("Transaction generator started, {} tx/s of {}", c.rate, c.values);
+                    config = Some(c);
+                }
+                Ok(ControlSignal::Stop) => {
+                    info!("Transaction generator stopped");
+                    config = None;
+                }
+                Ok(ControlSignal::Exit) | Err(RecvTimeoutError::Disconnected) => {
+                    info!("Transaction generator exited");
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => self.generate(&config.unwrap()),
             }
-        });
+            let mut status = self.status.lock().unwrap();
+            status.running = config.is_some();
+            status.config = config;
+        }
+    }
+
+    /// Submit a transfer to the mempool and broadcast it
+    fn generate(&mut self, config: &Config) {
+        let value = config.values.sample(&mut self.rng);
+        let result = {
+            let mut mempool = self.mempool.lock().unwrap();
+            self.wallet.sync(&mempool);
+            self.wallet
+                .transfer(value, config.fee, &mut self.rng)
+                .and_then(|tx| mempool.add_tx(&tx).map(|_| tx))
+        };
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(tx) => {
+                debug!("Generated tx {:?} of {}", tx.get_tx_hash(), value);
+                self.network.broadcast(Message::NewTransactionHashes(vec![tx.get_tx_hash()]));
+                status.sent += 1;
+                status.sent_value += value;
+                status.fees += config.fee;
+            }
+            Err(e) => {
+                debug!("No tx generated: {}", e);
+                status.failed += 1;
+            }
+        }
+        status.balance = self.wallet.balance();
+        status.outputs = self.wallet.output_count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::clock::MockClock;
+    use crate::simulation::Node;
+    use crate::types::chain_spec::ChainSpec;
+    use crate::types::ico::{self, IcoGenerator};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn value_distributions() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for s in ["fixed:10", "uniform:5-20", "exp:50"] {
+            let values: ValueDistribution = s.parse().unwrap();
+            assert_eq!(values.to_string(), s);
+        }
+        assert!("uniform:20-5".parse::<ValueDistribution>().is_err());
+        assert!("normal:5".parse::<ValueDistribution>().is_err());
+        assert_eq!(ValueDistribution::Fixed(0).sample(&mut rng), 1);
+        for _ in 0..100 {
+            let value = ValueDistribution::Uniform(5, 20).sample(&mut rng);
+            assert!((5..=20).contains(&value));
+        }
+        let mean = (0..2000).map(|_| ValueDistribution::Exponential(50).sample(&mut rng)).sum::<u64>() / 2000;
+        assert!((40..60).contains(&mean));
+    }
+
+    #[test]
+    fn interval_of_the_rate() {
+        let config = |rate| Config { rate, values: ValueDistribution::Fixed(10), fee: 1 };
+        assert_eq!(config(4.0).interval(), Duration::from_millis(250));
+        assert_eq!(config(1e-6).interval(), MAX_INTERVAL);
+        assert_eq!(config(1e-300).interval(), MAX_INTERVAL);
+    }
+
+    #[test]
+    fn transfers_between_the_keys() {
+        let spec = ChainSpec::simulated();
+        let node = Node::new(&spec, H256::rand(), Arc::new(MockClock::new(UNIX_EPOCH)));
+        let mut wallet = Wallet::new(IcoGenerator::load_key(ico::CONFIG_PATH));
+        let mut rng = StdRng::seed_from_u64(2);
+        let genesis_value: u64 = spec.genesis_allocations.iter().map(|a| a.value).sum();
+        wallet.sync(&node.mempool.lock().unwrap());
+        assert_eq!(wallet.output_count(), 3);
+        assert_eq!(wallet.balance(), genesis_value);
+
+        // each genesis output is spent once, the outputs of the txs are not final yet
+        for _ in 0..3 {
+            let tx = wallet.transfer(100, 1, &mut rng).unwrap();
+            node.mempool.lock().unwrap().add_tx(&tx).unwrap();
+        }
+        assert!(wallet.transfer(100, 1, &mut rng).is_err());
+        wallet.sync(&node.mempool.lock().unwrap());
+        assert_eq!(wallet.output_count(), 0);
+
+        // the txs are mined and final, the receivers and the split change can spend
+        for _ in 0..spec.k + 1 {
+            node.mine();
+        }
+        wallet.sync(&node.mempool.lock().unwrap());
+        assert_eq!(wallet.output_count(), 3 * (1 + SPLIT as usize));
+        assert_eq!(wallet.balance(), genesis_value - 3);
+        let tx = wallet.transfer(wallet.balance() - 10, 10, &mut rng).unwrap();
+        assert_eq!(tx.transaction.inputs.len(), 3 * (1 + SPLIT as usize));
+        node.mempool.lock().unwrap().add_tx(&tx).unwrap();
+    }
+
+    #[test]
+    fn generate_at_rate() {
+        let spec = ChainSpec::simulated();
+        let node = Node::new(&spec, H256::rand(), Arc::new(MockClock::new(UNIX_EPOCH)));
+        let (server, server_receiver) = NetworkServerHandle::new_for_test();
+        let (ctx, generator) = new(IcoGenerator::load_key(ico::CONFIG_PATH), &node.mempool, &server);
+        ctx.start();
+        generator.start(Config { rate: 200.0, values: ValueDistribution::Uniform(1, 1000), fee: 2 });
+        // the txs are announced to the peers
+        for _ in 0..3 {
+            assert!(matches!(server_receiver.recv(), Some(Message::NewTransactionHashes(_))));
+        }
+        // only the 3 genesis outputs are spendable until a block is final
+        while generator.status().failed == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        generator.stop();
+        thread::sleep(Duration::from_millis(100));
+        let status = generator.status();
+        assert!(!status.running);
+        assert_eq!(status.sent, 3);
+        assert_eq!(status.fees, 6);
+        assert_eq!(node.mempool.lock().unwrap().txs.len(), 3);
+        generator.exit();
     }
-}
\ No newline at end of file
+}
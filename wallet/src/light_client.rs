@@ -0,0 +1,260 @@
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle;
+use crate::types::pow::{Pow, PowAlgorithm};
+use crate::types::transaction::{Output, SignedTransaction};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// The fields of the node's chain spec file the header chain is checked against
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChainSpec {
+    /// PoW target, every header of the chain must have it
+    #[serde(deserialize_with = "hex_h256")]
+    pub difficulty: H256,
+    /// PoW hash of the headers, SHA-256 if not set
+    #[serde(default)]
+    pub pow: PowAlgorithm,
+    /// confirmation block number
+    pub k: u32,
+}
+
+impl ChainSpec {
+    /// Load the chain spec of the network from the same JSON file as the node
+    pub fn load(path: &str) -> Result<ChainSpec, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("error reading {}: {}", path, e))?;
+        let spec: ChainSpec = serde_json::from_str(&content).map_err(|e| format!("error parsing {}: {}", path, e))?;
+        if spec.k == 0 {
+            return Err("k must be positive".to_string());
+        }
+        Ok(spec)
+    }
+}
+
+/// H256 as a hex string in the spec file
+fn hex_h256<'de, D: serde::Deserializer<'de>>(d: D) -> Result<H256, D::Error> {
+    let s = String::deserialize(d)?;
+    let bytes = hex::decode(&s).map_err(serde::de::Error::custom)?;
+    if bytes.len() != 32 {
+        return Err(serde::de::Error::custom("expect 32 bytes"));
+    }
+    let mut buffer = [0; 32];
+    buffer.copy_from_slice(&bytes);
+    Ok(H256(buffer))
+}
+
+/// Merkle inclusion proof of a tx, the same as `blockchain::TxProof` of the node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    pub block_hash: H256,
+    /// index of the tx in the block body
+    pub index: usize,
+    /// number of txs in the block body
+    pub leaf_size: usize,
+    pub proof: Vec<H256>,
+    pub tx: SignedTransaction,
+}
+
+/// A headers-only light client, it never trusts the utxo returned by the node,
+/// every utxo must be proved to be created in a confirmed block of a valid header chain
+pub struct LightClient {
+    rpc_addr: String,
+    spec: ChainSpec,
+    /// if set, the header chain must start from this genesis
+    genesis: Option<H256>,
+    /// validated headers from genesis to tip
+    headers: Vec<Header>,
+    /// block hash => height
+    heights: HashMap<H256, usize>,
+}
+
+impl LightClient {
+    pub fn new(rpc_addr: &str, spec: ChainSpec, genesis: Option<H256>) -> Self {
+        LightClient {
+            rpc_addr: rpc_addr.to_string(),
+            spec,
+            genesis,
+            headers: Vec::new(),
+            heights: HashMap::new(),
+        }
+    }
+
+    /// Download the header chain from the node and validate it
+    pub fn sync_headers(&mut self) -> Result<(), String> {
+        let url = self.rpc_addr.clone() + "/blockchain/headers";
+        let resp = ureq::get(&url)
+            .call()
+            .map_err(|e| format!("request error {}", e))?
+            .into_string()
+            .map_err(|e| format!("response error {}", e))?;
+        let headers: Vec<Header> = serde_json::from_str(&resp).map_err(|e| format!("invalid headers: {}", e))?;
+        validate_header_chain(&headers, self.genesis, &self.spec.difficulty, &self.spec.pow)?;
+        self.heights = headers.iter().enumerate().map(|(i, h)| (h.hash(), i)).collect();
+        self.headers = headers;
+        Ok(())
+    }
+
+    /// Height of the validated tip, genesis is 0
+    pub fn height(&self) -> usize {
+        self.headers.len().saturating_sub(1)
+    }
+
+    /// Fetch the merkle proof of the utxo's source tx and verify it against the header chain
+    pub fn verify_utxo(&self, utxo: &(H256, usize, Output)) -> Result<(), String> {
+        let url = self.rpc_addr.clone() + "/blockchain/tx-proof?tx=" + &utxo.0.to_string();
+        let resp = ureq::get(&url)
+            .call()
+            .map_err(|e| format!("request error {}", e))?
+            .into_string()
+            .map_err(|e| format!("response error {}", e))?;
+        let tx_proof: TxProof = serde_json::from_str(&resp).map_err(|e| format!("invalid proof: {}", e))?;
+        self.verify_tx_proof(utxo, &tx_proof)
+    }
+
+    /// Check the proof proves the utxo is created in a block buried at least k blocks deep
+    pub fn verify_tx_proof(&self, utxo: &(H256, usize, Output), tx_proof: &TxProof) -> Result<(), String> {
+        if tx_proof.tx.get_tx_hash() != utxo.0 {
+            return Err("proof is for another tx".to_string());
+        }
+        let output = match tx_proof.tx.transaction.outputs.get(utxo.1) {
+            Some(output) => output,
+            None => return Err("output index out of range".to_string()),
+        };
+        if output.pk_hash != utxo.2.pk_hash || output.value != utxo.2.value {
+            return Err("output mismatch".to_string());
+        }
+        let height = match self.heights.get(&tx_proof.block_hash) {
+            Some(height) => *height,
+            None => return Err("block is not in the header chain".to_string()),
+        };
+        // genesis utxos are confirmed by definition
+        if height != 0 && self.height() - height < self.spec.k as usize {
+            return Err("block is not confirmed".to_string());
+        }
+        let header = &self.headers[height];
+        if !merkle::verify(&header.merkle_root, &tx_proof.tx.hash(), &tx_proof.proof, tx_proof.index, tx_proof.leaf_size) {
+            return Err("invalid merkle proof".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Validate PoW, parent links and difficulty of a header chain ordered from genesis to tip,
+/// every header must have the difficulty of the chain spec so the node cannot forge an easier chain
+pub fn validate_header_chain(headers: &[Header], genesis: Option<H256>, difficulty: &H256, pow: &dyn Pow) -> Result<(), String> {
+    if headers.is_empty() {
+        return Err("empty header chain".to_string());
+    }
+    if headers[0].parent != H256::default() {
+        return Err("the first header is not a genesis".to_string());
+    }
+    if let Some(genesis) = genesis {
+        if headers[0].hash() != genesis {
+            return Err("unexpected genesis".to_string());
+        }
+    }
+    for (i, header) in headers.iter().enumerate() {
+        if header.difficulty != *difficulty {
+            return Err(format!("unexpected difficulty at height {}", i));
+        }
+        if pow.pow_hash(header) > header.difficulty {
+            return Err(format!("invalid PoW at height {}", i));
+        }
+        if i == 0 {
+            continue;
+        }
+        let prev = &headers[i - 1];
+        if header.parent != prev.hash() {
+            return Err(format!("broken parent link at height {}", i));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_header;
+
+    fn mine(mut header: Header, pow: &dyn Pow) -> Header {
+        while pow.pow_hash(&header) > header.difficulty {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        header
+    }
+
+    fn gen_header_chain(n: usize, pow: &dyn Pow) -> Vec<Header> {
+        let mut headers = vec![mine(generate_random_header(&H256::default()), pow)];
+        for _ in 1..n {
+            let mut header = generate_random_header(&headers.last().unwrap().hash());
+            header.difficulty = headers[0].difficulty;
+            headers.push(mine(header, pow));
+        }
+        headers
+    }
+
+    fn spec(pow: &str) -> ChainSpec {
+        let json = format!(
+            r#"{{"network": "test", "difficulty": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", "pow": {{"algorithm": "{}"}}, "k": 6}}"#,
+            pow
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn load_spec_file() {
+        let spec = ChainSpec::load("../specs/dev.json").unwrap();
+        assert_eq!(spec.pow, PowAlgorithm::Sha256);
+        assert_eq!(spec.k, 6);
+        assert_eq!(&spec.difficulty.to_string()[..6], "000020");
+    }
+
+    #[test]
+    fn valid_header_chain() {
+        let spec = spec("sha256");
+        let headers = gen_header_chain(5, &spec.pow);
+        assert!(validate_header_chain(&headers, None, &spec.difficulty, &spec.pow).is_ok());
+        assert!(validate_header_chain(&headers, Some(headers[0].hash()), &spec.difficulty, &spec.pow).is_ok());
+        assert!(validate_header_chain(&headers, Some(headers[1].hash()), &spec.difficulty, &spec.pow).is_err());
+    }
+
+    #[test]
+    fn pow_of_the_spec() {
+        let spec = spec("double_sha256");
+        let headers = gen_header_chain(5, &spec.pow);
+        assert!(validate_header_chain(&headers, None, &spec.difficulty, &spec.pow).is_ok());
+        // the block ids do not meet the target
+        assert!(validate_header_chain(&headers, None, &spec.difficulty, &PowAlgorithm::Sha256).is_err());
+    }
+
+    #[test]
+    fn difficulty_of_the_spec() {
+        let spec = spec("sha256");
+        // a whole chain mined at an easier target than the spec
+        let mut easy = generate_random_header(&H256::default());
+        easy.difficulty.0[1] = 255;
+        let mut headers = vec![mine(easy, &spec.pow)];
+        for _ in 1..5 {
+            let mut header = generate_random_header(&headers.last().unwrap().hash());
+            header.difficulty = headers[0].difficulty;
+            headers.push(mine(header, &spec.pow));
+        }
+        assert!(validate_header_chain(&headers, None, &headers[0].difficulty, &spec.pow).is_ok());
+        assert!(validate_header_chain(&headers, None, &spec.difficulty, &spec.pow).is_err());
+    }
+
+    #[test]
+    fn invalid_header_chain() {
+        let spec = spec("sha256");
+        let headers = gen_header_chain(5, &spec.pow);
+        // broken parent link
+        let mut broken = headers.clone();
+        broken.remove(2);
+        assert!(validate_header_chain(&broken, None, &spec.difficulty, &spec.pow).is_err());
+        // tampered merkle root breaks the PoW or the link of the child
+        let mut tampered = headers.clone();
+        tampered[3].merkle_root = H256::rand();
+        assert!(validate_header_chain(&tampered, None, &spec.difficulty, &spec.pow).is_err());
+    }
+}
@@ -1,15 +1,18 @@
 #[macro_use] extern crate hex_literal;
 mod types; 
+mod light_client;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use types::hash::{Hashable, H256};
 use types::key_pair::{self, PublicKey};
 use std::fs;
 use std::io::{self, Read, Write, BufReader, BufRead};
 use std::path::Path; 
+use std::process;
 use crate::types::transaction; 
 use ureq; 
 use serde_json; 
 use types::transaction::{Output, Transaction, Input, Witness, SignedTransaction};
+use light_client::{ChainSpec, LightClient};
 
 use clap::{Arg, App, SubCommand};
 pub struct Wallet{
@@ -20,6 +23,8 @@ pub struct Wallet{
     //neighbors pkh 
     neighbors: Vec<H256>, 
     balance: u64, 
+    /// if set, every utxo is verified against the header chain before counted in the balance
+    light_client: Option<LightClient>,
 }
 
 impl Wallet{
@@ -38,8 +43,13 @@ impl Wallet{
             pkh: pkh,
             neighbors:n,
             balance: 0,
+            light_client: None,
         }
     }
+    /// Switch to the headers-only light client mode
+    pub fn enable_light_client(&mut self, spec: ChainSpec, genesis: Option<H256>){
+        self.light_client = Some(LightClient::new(&self.rpc_addr, spec, genesis));
+    }
    
     pub fn update_utxo(& mut self){
         // call rpc to get utxo 
@@ -55,9 +65,26 @@ impl Wallet{
 
         let resp = response.into_string().unwrap();
         let utxos: Vec<(H256, usize, Output)>  = serde_json::from_str(&resp).unwrap(); 
+        // in light client mode, sync the header chain first, then only keep the proved utxos
+        if let Some(light_client) = &mut self.light_client {
+            // without a valid header chain nothing can be verified, keep the previous utxos
+            if let Err(e) = light_client.sync_headers() {
+                println!("Header sync error, keep the previous utxos: {}", e);
+                return;
+            }
+            println!("Validated header chain, height: {}", light_client.height());
+        }
+        let mut my_utxo = Vec::new();
         for utxo in utxos{
-            self.my_utxo.push(utxo);
+            if let Some(light_client) = &self.light_client {
+                if let Err(e) = light_client.verify_utxo(&utxo) {
+                    println!("Skip unverified utxo {}-{}: {}", utxo.0, utxo.1, e);
+                    continue;
+                }
+            }
+            my_utxo.push(utxo);
         }
+        self.my_utxo = my_utxo;
         //get the total amount of all utxos 
         let mut total_amount = 0;
         for utxo in &self.my_utxo{
@@ -125,6 +152,22 @@ fn main() {
             .help("Sets the neighbors file")
             .takes_value(true)
             .default_value("../pks.txt"))
+        .arg(Arg::with_name("light")
+            .short("l")
+            .long("light")
+            .help("Validates the header chain and verifies every UTXO with merkle proofs"))
+        .arg(Arg::with_name("chain_spec")
+            .long("chain-spec")
+            .value_name("FILE")
+            .help("Sets the chain spec file of the network in light mode")
+            .takes_value(true)
+            .default_value("../specs/dev.json"))
+        .arg(Arg::with_name("genesis")
+            .short("g")
+            .long("genesis")
+            .value_name("HASH")
+            .help("Sets the expected genesis hash in light mode")
+            .takes_value(true))
             .subcommand(SubCommand::with_name("show_utxo_detail")
             .about("Shows UTXO details"))
         .subcommand(SubCommand::with_name("transfer")
@@ -166,6 +209,19 @@ fn main() {
     println!("Open Wallet: "); 
     let mut wallet = Wallet::new(key_file, address);
     println!("My public key hash is {:?}", wallet.pkh);
+    if matches.is_present("light") {
+        let spec = ChainSpec::load(matches.value_of("chain_spec").unwrap()).unwrap_or_else(|e| {
+            eprintln!("Error loading chain spec: {}", e);
+            process::exit(1);
+        });
+        let genesis = matches.value_of("genesis").map(|g| {
+            g.parse::<H256>().unwrap_or_else(|e| {
+                eprintln!("Error parsing genesis hash: {}", e);
+                process::exit(1);
+            })
+        });
+        wallet.enable_light_client(spec, genesis);
+    }
     wallet.update_utxo();
     println!("UTXO count: {:?}", wallet.my_utxo.len());
     wallet.load_neighbors(neighbors_file);
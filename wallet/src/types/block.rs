@@ -4,8 +4,12 @@ use super::{transaction::{SignedTransaction, Output}, merkle::MerkleTree, key_pa
 use rand::prelude::*;
 use super::ico::IcoGenerator;
 use super::ico; 
+/// The first versionbits header version, the same as the node
+pub const VERSION_BASE: u32 = 0x2000_0000;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
+    /// consensus version, the low 29 bits of a versionbits header are feature bits
+    pub version: u32,
     /// prev block hash 
     pub parent: H256,
     pub difficulty: H256,
@@ -87,7 +91,7 @@ fn generate_genesis_header() -> Header{
     let genesis_time = Utc.ymd(2023, 10, 01).and_hms(0,0,0); 
     let timestamp = std::time::SystemTime::from(genesis_time); 
     let merkle_root = H256::default(); 
-    Header { parent: H256::default(), difficulty: difficulty, merkle_root: merkle_root, timestamp: timestamp, nonce: 0}
+    Header { version: VERSION_BASE, parent: H256::default(), difficulty: difficulty, merkle_root: merkle_root, timestamp: timestamp, nonce: 0}
 
 }
 pub fn generate_random_header(parent: &H256) -> Header{
@@ -106,6 +110,7 @@ pub fn generate_random_header(parent: &H256) -> Header{
     let timestamp = std::time::SystemTime::now();
     let merkle_root = H256::default();
     Header {
+        version: VERSION_BASE,
         parent: parent.clone(),
         difficulty,
         merkle_root,
@@ -51,6 +51,9 @@ impl FromStr for H256{
     type Err = hex::FromHexError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let bytes = hex::decode(s)?;
+        if bytes.len() != 32 {
+            return Err(hex::FromHexError::InvalidStringLength);
+        }
         let mut buffer: [u8; 32] = [0; 32];
         buffer[..].copy_from_slice(&bytes);
         Ok(H256(buffer))
@@ -1,140 +1,191 @@
 use super::hash::{Hashable, H256};
-
+use std::collections::BTreeMap;
 
 /// A Merkle tree.
-#[derive(Debug, Default)]
+/// A node without sibling is promoted to the upper level as it is, instead of being paired with
+/// a copy of itself, so a tree can not be mutated by duplicating its last leaves (CVE-2012-2459).
+#[derive(Debug, Default, Clone)]
 pub struct MerkleTree {
     /// Root of the Merkle Tree 
     root: H256,
     /// The number of leaves in the Merkle Tree
     leaf_size: usize, 
-    /// Merkle tree
+    /// Merkle tree, level 0 is the leaves, the last level only contains the root
     merkle_tree: Vec<Vec<H256>>,
 }
 
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
-        //unimplemented!()
         // hash all elements and put them into leafs 
-        let mut leafs = data.iter().map(|x| x.hash()).collect::<Vec<H256>>();
-        // *get the total number of valid leafs, if the number of leafs is not a power of 2, padding the merkle tree with the last leaf 
+        let leafs = data.iter().map(|x| x.hash()).collect::<Vec<H256>>();
         let leaf_size = leafs.len();
-        // pad the leafs to be a even number 
-        if leaf_size % 2 == 1 {
-            leafs.push(leafs[leaf_size - 1]);
-        }
-        let mut tree_hashes = Vec::new();
-        let current_level : usize = 0; 
-        if leaf_size ==0{
-            //panic!("no leaf in the merkle tree");
-            //just push an empty vector into the tree_hashes
-            // the root is zero 
-
-            return MerkleTree {
-                root: H256::default(),
-                leaf_size,
-                merkle_tree: tree_hashes,
-            };
+        if leaf_size == 0 {
+            // the root of an empty tree is zero 
+            return MerkleTree::default();
         }
-        tree_hashes.push(leafs);
-        loop {
-            let mut current_hashes = Vec::new();
-            let current_level = current_level + 1;
-            let mut i = 0;
-            while i < tree_hashes[current_level - 1].len() {
-                let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
-                hasher.update(tree_hashes[current_level - 1][i].as_ref());
-                hasher.update(tree_hashes[current_level - 1][i + 1].as_ref());
-                let hash = hasher.finish();
-                current_hashes.push(hash.into());
-                i = i + 2;
-            }
-            let current_hashes_len = current_hashes.len();
-            if current_hashes_len != 1 && current_hashes_len % 2 == 1 {
-                current_hashes.push(current_hashes[current_hashes_len - 1]);
-            }
+        let mut tree_hashes = vec![leafs];
+        while tree_hashes[tree_hashes.len() - 1].len() > 1 {
+            let current_hashes = tree_hashes[tree_hashes.len() - 1]
+                .chunks(2)
+                .map(|pair| if pair.len() == 2 { hash_pair(&pair[0], &pair[1]) } else { pair[0] })
+                .collect();
             tree_hashes.push(current_hashes);
-            if current_hashes_len == 1 {
-                break;
-            }
         }
-        let root = tree_hashes[tree_hashes.len() - 1][0].clone();
-        // return the merkle tree
+        let root = tree_hashes[tree_hashes.len() - 1][0];
         MerkleTree {
             root,
             leaf_size,
             merkle_tree: tree_hashes,
         }
+    }
 
+    /// Append a new leaf, only the nodes on the right-most path are recomputed
+    pub fn push<T>(&mut self, datum: &T) where T: Hashable, {
+        if self.merkle_tree.is_empty() {
+            self.merkle_tree.push(Vec::new());
+        }
+        self.merkle_tree[0].push(datum.hash());
+        self.leaf_size += 1;
+        let mut level = 0;
+        while self.merkle_tree[level].len() > 1 {
+            let current_len = self.merkle_tree[level].len();
+            // the parent of the last node
+            let parent_index = (current_len - 1) / 2;
+            let left = &self.merkle_tree[level][parent_index * 2];
+            let parent = match self.merkle_tree[level].get(parent_index * 2 + 1) {
+                Some(right) => hash_pair(left, right),
+                None => *left,
+            };
+            if level + 1 == self.merkle_tree.len() {
+                self.merkle_tree.push(Vec::new());
+            }
+            let upper = &mut self.merkle_tree[level + 1];
+            if parent_index < upper.len() {
+                upper[parent_index] = parent;
+            } else {
+                upper.push(parent);
+            }
+            level += 1;
+        }
+        self.root = self.merkle_tree[level][0];
     }
 
     pub fn root(&self) -> H256 {
-        //unimplemented!()
         self.root
     }
 
+    /// The number of leaves in the Merkle Tree
+    pub fn leaf_size(&self) -> usize {
+        self.leaf_size
+    }
+
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
-        //unimplemented!()
-        if index > self.leaf_size - 1 {
+        if self.leaf_size == 0 {
+            panic!(" Can not proof for an empty merkle tree");
+        }
+        if index >= self.leaf_size {
             panic!("index out of range");
         }
-        if self.leaf_size == 0{
-            panic!(" Can not proof for an empty merkle tree");
+        let mut proof = Vec::new();
+        let mut current_index = index;
+        for level in &self.merkle_tree[..self.merkle_tree.len() - 1] {
+            // a promoted node has no sibling, so nothing goes into the proof
+            if let Some(sibling) = level.get(current_index ^ 1) {
+                proof.push(*sibling);
+            }
+            current_index /= 2;
         }
+        proof
+    }
 
+    /// Returns a compact proof for several leaves at once, the hashes which can be computed
+    /// from the proved leaves themselves are not included
+    pub fn multi_proof(&self, indices: &[usize]) -> Vec<H256> {
+        if self.leaf_size == 0 {
+            panic!(" Can not proof for an empty merkle tree");
+        }
+        let mut known: Vec<usize> = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        if known.is_empty() || known[known.len() - 1] >= self.leaf_size {
+            panic!("index out of range");
+        }
         let mut proof = Vec::new();
-        let mut current_level: usize = 0; 
-        let mut current_index = index;
-        let tree_depth = self.merkle_tree.len();
-        loop {
-            let sbling_index = get_sbling(current_index);
-            let sbling_hash = self.merkle_tree[current_level][sbling_index].clone();
-            proof.push(sbling_hash);
-            current_index = current_index / 2;
-            current_level = current_level + 1;
-            if current_level == tree_depth - 1 {
-                break;
+        for level in &self.merkle_tree[..self.merkle_tree.len() - 1] {
+            for index in &known {
+                let sibling = index ^ 1;
+                if sibling < level.len() && known.binary_search(&sibling).is_err() {
+                    proof.push(level[sibling]);
+                }
             }
+            known = known.iter().map(|i| i / 2).collect();
+            known.dedup();
         }
         proof
     }
 }
-fn get_sbling(index: usize) -> usize {
-    let mut sbling_index = 0;
-    if index % 2 == 0 {
-        sbling_index = index + 1;
-    } else {
-        sbling_index = index - 1;
-    }
-    sbling_index
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+    hasher.update(left.as_ref());
+    hasher.update(right.as_ref());
+    hasher.finish().into()
 }
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
-    //unimplemented!()
-    let mut current_hash = datum.clone();
-    if index > leaf_size - 1 || leaf_size == 0{
+    verify_multi(root, &[(index, *datum)], proof, leaf_size)
+}
+
+/// Verify a multi proof generated by `MerkleTree::multi_proof`, `leaves` are pairs of leaf index
+/// and datum hash.
+pub fn verify_multi(root: &H256, leaves: &[(usize, H256)], proof: &[H256], leaf_size: usize) -> bool {
+    if leaves.is_empty() || leaf_size == 0 {
         return false;
     }
-
-    let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
-    // watch out, the hash order matters
-    let mut current_index = index;
-    for i in 0..proof.len() {
-        if current_index % 2 == 0 {
-            hasher.update(current_hash.as_ref());
-            hasher.update(proof[i].as_ref());
-        } else {
-            hasher.update(proof[i].as_ref());
-            hasher.update(current_hash.as_ref());
-        }
-        let hash = hasher.finish();
-        current_hash = hash.into();
-        hasher = ring::digest::Context::new(&ring::digest::SHA256);
-        current_index = current_index / 2;
-    }
-    current_hash == *root
+    let mut known: BTreeMap<usize, H256> = BTreeMap::new();
+    for (index, datum) in leaves {
+        if *index >= leaf_size {
+            return false;
+        }
+        // the same index with different data can never be valid
+        if let Some(existing) = known.insert(*index, *datum) {
+            if existing != *datum {
+                return false;
+            }
+        }
+    }
+    let mut proof = proof.iter();
+    let mut level_size = leaf_size;
+    while level_size > 1 {
+        let mut upper = BTreeMap::new();
+        for (&index, hash) in &known {
+            let sibling = index ^ 1;
+            // watch out, the hash order matters
+            let sibling_hash = if sibling >= level_size {
+                None
+            } else if let Some(h) = known.get(&sibling) {
+                Some(*h)
+            } else {
+                match proof.next() {
+                    Some(h) => Some(*h),
+                    None => return false,
+                }
+            };
+            let parent = match sibling_hash {
+                None => *hash,
+                Some(s) if index % 2 == 0 => hash_pair(hash, &s),
+                Some(s) => hash_pair(&s, hash),
+            };
+            upper.insert(index / 2, parent);
+        }
+        known = upper;
+        level_size = (level_size + 1) / 2;
+    }
+    // every proof hash must be used
+    proof.next().is_none() && known.get(&0) == Some(root)
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
@@ -189,6 +240,68 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    fn gen_random_data(n: usize) -> Vec<H256> {
+        (0..n).map(|_| H256::rand()).collect()
+    }
+
+    #[test]
+    fn merkle_proof_odd_sizes() {
+        for n in 1..12 {
+            let input_data = gen_random_data(n);
+            let merkle_tree = MerkleTree::new(&input_data);
+            for i in 0..n {
+                let proof = merkle_tree.proof(i);
+                assert!(verify(&merkle_tree.root(), &input_data[i].hash(), &proof, i, n));
+                assert!(!verify(&merkle_tree.root(), &H256::rand(), &proof, i, n));
+            }
+        }
+    }
+
+    #[test]
+    fn merkle_duplicated_leaf_mutation() {
+        // [a, b, c] and [a, b, c, c] used to share the same root
+        let input_data = gen_random_data(3);
+        let mut mutated = input_data.clone();
+        mutated.push(input_data[2]);
+        assert_ne!(MerkleTree::new(&input_data).root(), MerkleTree::new(&mutated).root());
+        let mut mutated = gen_random_data(5);
+        mutated.push(mutated[4]);
+        mutated.push(mutated[5]);
+        assert_ne!(MerkleTree::new(&mutated[..5]).root(), MerkleTree::new(&mutated).root());
+    }
+
+    #[test]
+    fn merkle_multi_proof() {
+        let input_data = gen_random_data(11);
+        let merkle_tree = MerkleTree::new(&input_data);
+        let indices = vec![0, 1, 4, 10];
+        let proof = merkle_tree.multi_proof(&indices);
+        // compact proof is shorter than the separated proofs
+        let separated: usize = indices.iter().map(|i| merkle_tree.proof(*i).len()).sum();
+        assert!(proof.len() < separated);
+        let leaves: Vec<(usize, H256)> = indices.iter().map(|i| (*i, input_data[*i].hash())).collect();
+        assert!(verify_multi(&merkle_tree.root(), &leaves, &proof, input_data.len()));
+        // a wrong datum fails
+        let mut wrong = leaves.clone();
+        wrong[2].1 = H256::rand();
+        assert!(!verify_multi(&merkle_tree.root(), &wrong, &proof, input_data.len()));
+        // a missing leaf fails
+        assert!(!verify_multi(&merkle_tree.root(), &leaves[1..], &proof, input_data.len()));
+    }
+
+    #[test]
+    fn merkle_incremental_push() {
+        let input_data = gen_random_data(13);
+        let mut merkle_tree = MerkleTree::default();
+        for (i, datum) in input_data.iter().enumerate() {
+            merkle_tree.push(datum);
+            let rebuilt = MerkleTree::new(&input_data[..i + 1]);
+            assert_eq!(merkle_tree.root(), rebuilt.root());
+            assert_eq!(merkle_tree.proof(i), rebuilt.proof(i));
+        }
+        assert_eq!(merkle_tree.leaf_size(), input_data.len());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
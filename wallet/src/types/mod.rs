@@ -3,4 +3,5 @@ pub mod hash;
 pub mod merkle;
 pub mod key_pair;
 pub mod transaction;
-pub mod ico; 
\ No newline at end of file
+pub mod ico; 
+pub mod pow;
\ No newline at end of file
@@ -0,0 +1,93 @@
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+use super::block::Header;
+use super::hash::H256;
+
+/// Proof-of-work hash of a header, the same as `pow::Pow` of the node.
+/// A header is solved when it is not larger than the target, the block id stays `Header::hash`
+pub trait Pow {
+    fn pow_hash(&self, header: &Header) -> H256;
+}
+
+/// SHA-256 of the bincode header, the PoW hash is the block id
+pub struct Sha256;
+
+impl Pow for Sha256 {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        digest(&SHA256, &bincode::serialize(header).unwrap()).into()
+    }
+}
+
+/// SHA-256 of the SHA-256 of the bincode header
+pub struct DoubleSha256;
+
+impl Pow for DoubleSha256 {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        let first = digest(&SHA256, &bincode::serialize(header).unwrap());
+        digest(&SHA256, first.as_ref()).into()
+    }
+}
+
+/// The memory-hard hash of the node: a table of `memory_kib` KiB of chained SHA-256 of the header,
+/// read at positions depending on the previous reads
+pub struct MemoryHard {
+    pub memory_kib: u32,
+}
+
+impl Pow for MemoryHard {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        let sha256 = |bytes: &[u8]| -> [u8; 32] { digest(&SHA256, bytes).as_ref().try_into().unwrap() };
+        let n = (self.memory_kib as usize * 1024 / 32).max(1);
+        let mut x = sha256(&bincode::serialize(header).unwrap());
+        let mut table = Vec::with_capacity(n);
+        for _ in 0..n {
+            table.push(x);
+            x = sha256(&x);
+        }
+        for _ in 0..n {
+            let j = (u64::from_le_bytes(x[..8].try_into().unwrap()) % n as u64) as usize;
+            for (a, b) in x.iter_mut().zip(table[j].iter()) {
+                *a ^= b;
+            }
+            x = sha256(&x);
+        }
+        x.into()
+    }
+}
+
+/// PoW algorithm of a chain, the `pow` field of the chain spec, SHA-256 if not set
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum PowAlgorithm {
+    #[default]
+    Sha256,
+    DoubleSha256,
+    MemoryHard { memory_kib: u32 },
+}
+
+impl Pow for PowAlgorithm {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        match *self {
+            PowAlgorithm::Sha256 => Sha256.pow_hash(header),
+            PowAlgorithm::DoubleSha256 => DoubleSha256.pow_hash(header),
+            PowAlgorithm::MemoryHard { memory_kib } => MemoryHard { memory_kib }.pow_hash(header),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_header;
+    use crate::types::hash::Hashable;
+
+    #[test]
+    fn sha256_is_the_block_id() {
+        let header = generate_random_header(&H256::default());
+        assert_eq!(PowAlgorithm::Sha256.pow_hash(&header), header.hash());
+        assert_eq!(PowAlgorithm::DoubleSha256.pow_hash(&header), header.hash().hash());
+        assert_ne!(PowAlgorithm::MemoryHard { memory_kib: 16 }.pow_hash(&header), header.hash());
+    }
+}